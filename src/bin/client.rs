@@ -1,8 +1,15 @@
 extern crate moba;
 extern crate clap;
 
-use std::net;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
 use clap::{Arg, App};
+use moba::common::LinkConditions;
+
+/// Read for the token if it isn't given on the command line, where anyone on
+/// the machine could see it.
+const TOKEN_VAR: &str = "MOBA_TOKEN";
 
 fn main() {
     let matches = App::new("moba")
@@ -22,9 +29,26 @@ fn main() {
                 .long("user")
                 .value_name("USERNAME")
                 .help("Sets the username to use")
-                .required(true)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .value_name("TOKEN")
+                .help(
+                    "Sets the token to sign in to a registered name with. Other users can see \
+                     it, so prefer --token-file or MOBA_TOKEN",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("token-file")
+                .long("token-file")
+                .value_name("FILE")
+                .help("Reads the token to sign in with from a file")
+                .takes_value(true)
+                .conflicts_with("token"),
+        )
         .arg(
             Arg::with_name("team")
                 .short("t")
@@ -33,22 +57,112 @@ fn main() {
                 .help("Sets the team to join")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("match")
+                .short("m")
+                .long("match")
+                .value_name("MATCH_ID")
+                .help("Sets the match to join")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("queue")
+                .short("q")
+                .long("queue")
+                .help("Joins the matchmaking queue instead of a specific match")
+                .conflicts_with("match"),
+        )
+        .arg(
+            Arg::with_name("udp")
+                .long("udp")
+                .help("Connects over UDP instead of TCP"),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .value_name("CERT")
+                .help(
+                    "Connects over TLS, trusting only a server with the certificate in this \
+                     PEM file",
+                )
+                .takes_value(true)
+                .conflicts_with("udp"),
+        )
+        .arg(
+            Arg::with_name("simulate-network")
+                .long("simulate-network")
+                .value_name("CONDITIONS")
+                .help(
+                    "Simulates a bad network to the server, e.g. \
+                     latency=100,jitter=20,loss=5%,duplicate=1%,bandwidth=256",
+                )
+                .validator(|s| s.parse::<LinkConditions>().map(|_| ()))
+                .takes_value(true),
+        )
         .get_matches();
 
     println!("Alpha Client");
 
-    let name = matches.value_of("user").unwrap();
-    let server = matches.value_of("server").unwrap_or("127.0.0.1");
-    let team = matches
-        .value_of("team")
-        .map(|t| moba::common::Team(t.parse().unwrap()));
+    let mut settings = moba::client::ClientConfig::load_or_default(
+        moba::client::DEFAULT_CONFIG_PATH,
+    ).menu;
+    if let Some(name) = matches.value_of("user") {
+        settings.name = name.into();
+    }
+    if let Some(server) = matches.value_of("server") {
+        settings.server = server.into();
+    }
+    if let Some(token) = matches.value_of("token") {
+        settings.token = token.into();
+    } else if let Some(path) = matches.value_of("token-file") {
+        settings.token = read_token(path).unwrap_or_else(|err| panic!("{}: {}", path, err));
+    } else if let Ok(token) = env::var(TOKEN_VAR) {
+        settings.token = token;
+    }
+    if let Some(team) = matches.value_of("team") {
+        settings.team = team.into();
+    }
+    if matches.is_present("queue") {
+        settings.match_id = String::new();
+    } else if let Some(match_id) = matches.value_of("match") {
+        settings.match_id = match_id.into();
+    }
+
+    if matches.is_present("udp") {
+        settings.udp = true;
+    }
+    if let Some(cert) = matches.value_of("tls") {
+        settings.tls_cert = cert.into();
+    }
 
-    let addr = net::SocketAddrV4::new(server.parse().unwrap(), moba::common::DEFAULT_PORT);
+    // skip the menu if we've been told where to go
+    let connect_to = if matches.is_present("user") && matches.is_present("server") {
+        match moba::client::ConnectRequest::from_settings(&settings) {
+            Ok(request) => Some(request),
+            Err(err) => {
+                println!("{}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    let mut client = moba::client::Client::new(name.to_owned(), team);
+    let conditions = match matches.value_of("simulate-network") {
+        Some(conditions) => {
+            // checked by the validator
+            let conditions: LinkConditions = conditions.parse().unwrap();
+            println!("Simulating network conditions: {}", conditions);
+            conditions
+        }
+        None => LinkConditions::default(),
+    };
 
-    match client.connect(addr) {
-        Ok(()) => {}
-        Err(e) => println!("Error connecting: {}", e),
-    }
+    moba::client::run(settings, connect_to, conditions);
+}
+
+fn read_token(path: &str) -> io::Result<String> {
+    let mut token = String::new();
+    File::open(path)?.read_to_string(&mut token)?;
+    Ok(token.trim().into())
 }
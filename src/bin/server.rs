@@ -1,8 +1,184 @@
 extern crate moba;
+#[macro_use]
+extern crate clap;
+
+use std::process;
+use std::net::IpAddr;
+use std::path::Path;
+use clap::{Arg, App};
+use moba::common::{logic, LinkConditions};
+use moba::server::{Accounts, Config, Overrides};
+
+const DEFAULT_CONFIG_PATH: &str = "server.toml";
+const DEFAULT_ACCOUNTS_PATH: &str = "accounts.txt";
 
 fn main() {
+    let matches = App::new("moba server")
+        .version("alpha")
+        .author("<definitelynotliam@gmail.com>")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Sets the config file to use (default server.toml, if present)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .help("Sets the name shown in LAN server lists")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .value_name("ADDRESS")
+                .help("Sets the address to listen on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("Sets the port to listen on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tick-rate")
+                .long("tick-rate")
+                .value_name("TICKS")
+                .help("Sets the number of ticks per second")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-players")
+                .long("max-players")
+                .value_name("COUNT")
+                .help("Sets the maximum number of players per match")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("team-size")
+                .long("team-size")
+                .value_name("COUNT")
+                .help("Sets the maximum number of players per team")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("map")
+                .long("map")
+                .value_name("MAP")
+                .possible_values(&["Arena"])
+                .help("Sets the map")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .value_name("MODE")
+                .possible_values(&["FreeForAll", "Teams"])
+                .help("Sets the game mode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin-password")
+                .long("admin-password")
+                .value_name("PASSWORD")
+                .help("Sets the admin password")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("simulate-network")
+                .long("simulate-network")
+                .value_name("CONDITIONS")
+                .help(
+                    "Simulates a bad network to every client, e.g. \
+                     latency=100,jitter=20,loss=5%,duplicate=1%,bandwidth=256",
+                )
+                .validator(|s| s.parse::<LinkConditions>().map(|_| ()))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("add-account")
+                .long("add-account")
+                .value_name("NAME")
+                .help(
+                    "Registers a player in the accounts file, prints their token and exits",
+                )
+                .takes_value(true),
+        )
+        .get_matches();
+
     println!("Alpha Server");
 
-    let mut game = moba::server::Server::new();
-    game.serve(moba::common::DEFAULT_PORT);
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(path).unwrap_or_else(|err| panic!("{}: {}", path, err)),
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            Config::load(DEFAULT_CONFIG_PATH)
+                .unwrap_or_else(|err| panic!("{}: {}", DEFAULT_CONFIG_PATH, err))
+        }
+        None => Config::default(),
+    };
+
+    let mut overrides = Overrides::default();
+    overrides.name = matches.value_of("name").map(|name| name.into());
+    if matches.is_present("bind") {
+        overrides.bind_address = Some(value_t_or_exit!(matches, "bind", IpAddr));
+    }
+    if matches.is_present("port") {
+        overrides.port = Some(value_t_or_exit!(matches, "port", u16));
+    }
+    if matches.is_present("tick-rate") {
+        overrides.tick_rate = Some(value_t_or_exit!(matches, "tick-rate", u32));
+    }
+    if matches.is_present("max-players") {
+        overrides.max_players = Some(value_t_or_exit!(matches, "max-players", usize));
+    }
+    if matches.is_present("team-size") {
+        overrides.team_size = Some(value_t_or_exit!(matches, "team-size", usize));
+    }
+    overrides.map = matches.value_of("map").map(|map| match map {
+        "Arena" => logic::MapKind::Arena,
+        _ => unreachable!(),
+    });
+    overrides.game_mode = matches.value_of("mode").map(|mode| match mode {
+        "FreeForAll" => logic::GameMode::FreeForAll,
+        "Teams" => logic::GameMode::Teams,
+        _ => unreachable!(),
+    });
+    overrides.admin_password = matches.value_of("admin-password").map(|p| p.into());
+    config.set_overrides(overrides);
+    if let Err(err) = config.validate() {
+        println!("{}", err);
+        process::exit(1);
+    }
+
+    if let Some(name) = matches.value_of("add-account") {
+        let path = config.accounts.clone().unwrap_or(DEFAULT_ACCOUNTS_PATH.into());
+        match Accounts::add(&path, name) {
+            Ok((id, token)) => {
+                println!("Registered {} as player {} in {}.", name, id.0, path);
+                println!("Their token is {}", token);
+                if config.accounts.is_none() {
+                    println!("Set accounts = \"{}\" in the config to use it.", path);
+                }
+            }
+            Err(err) => println!("Couldn't register {}: {}", name, err),
+        }
+        return;
+    }
+
+    let mut server = moba::server::Server::new(config);
+    if let Some(conditions) = matches.value_of("simulate-network") {
+        // checked by the validator
+        let conditions: LinkConditions = conditions.parse().unwrap();
+        println!("Simulating network conditions: {}", conditions);
+        server.set_link_conditions(conditions);
+    }
+    println!("Effective config:\n{}", server.config().to_display_string());
+    server.serve();
 }
@@ -0,0 +1,186 @@
+use common::*;
+
+use super::{Announcement, Sound};
+
+/// Something to play in response to an event.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cue {
+    Sound(Sound, Point),
+    Announcement(Announcement),
+}
+
+/// What to play for `event`, seen by the player controlling `own_id`. Called
+/// before the event is run, so that units it removes can still be found.
+pub fn cues_for(game: &mut Game, own_id: Option<EntityID>, event: &Event) -> Vec<Cue> {
+    let mut cues = Vec::new();
+
+    match *event {
+        Event::AddProjectile {
+            position,
+            damage,
+            ..
+        } => {
+            // abilities play their cast sound instead
+            if damage.kind == DamageKind::Physical {
+                cues.push(Cue::Sound(Sound::Attack, position));
+            }
+        }
+        Event::AbilityUsed { id, .. } => cues.extend(sound_at(game, Sound::AbilityCast, id)),
+        Event::RemoveEntity(id) => {
+            if game.has_component::<Projectile>(id) {
+                cues.extend(sound_at(game, Sound::ProjectileHit, id));
+            }
+        }
+        Event::DamageEntity { id, damage } => {
+            if !game.is_killing_blow(id, damage) {
+                return cues;
+            }
+            cues.extend(sound_at(game, Sound::Death, id));
+
+            if game.has_component::<Player>(id) {
+                let team = game.clone_component::<Team>(id);
+                // without teams, every other hero is an enemy
+                let ally = match own_id {
+                    Some(own) if own == id => true,
+                    Some(own) => team.is_some() && team == game.clone_component::<Team>(own),
+                    None => false,
+                };
+                cues.push(Cue::Announcement(if ally {
+                    Announcement::AllyHeroKilled
+                } else {
+                    Announcement::EnemyHeroKilled
+                }));
+            }
+        }
+        _ => {}
+    }
+
+    cues
+}
+
+fn sound_at(game: &mut Game, sound: Sound, id: EntityID) -> Option<Cue> {
+    game.clone_component::<Position>(id)
+        .map(|position| Cue::Sound(sound, position.point))
+}
+
+#[cfg(test)]
+mod tests {
+    use common::*;
+    use common::logic::HeroKind;
+    use super::super::{Announcement, Audio, AudioSettings, NullOutput, Sound};
+    use super::*;
+
+    const ME: EntityID = EntityID(0);
+    const OTHER: EntityID = EntityID(1);
+
+    fn game(my_team: Option<Team>, other_team: Option<Team>) -> Game {
+        let mut game = Game::new();
+        game.add_player(ME, HeroKind::John, "me".into(), Point::new(0.0, 0.0), my_team);
+        game.add_player(OTHER, HeroKind::John, "other".into(), Point::new(100.0, 0.0), other_team);
+        game
+    }
+
+    fn kill(id: EntityID) -> Event {
+        Event::DamageEntity {
+            id,
+            damage: Damage::new(1000, DamageKind::Physical),
+        }
+    }
+
+    fn announcements(cues: &[Cue]) -> Vec<Announcement> {
+        cues.iter()
+            .filter_map(|cue| match *cue {
+                Cue::Announcement(announcement) => Some(announcement),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn other_heroes_are_enemies_without_teams() {
+        let mut game = game(None, None);
+        let cues = cues_for(&mut game, Some(ME), &kill(OTHER));
+        assert_eq!(announcements(&cues), vec![Announcement::EnemyHeroKilled]);
+    }
+
+    #[test]
+    fn own_death_is_an_ally_death_without_teams() {
+        let mut game = game(None, None);
+        let cues = cues_for(&mut game, Some(ME), &kill(ME));
+        assert_eq!(announcements(&cues), vec![Announcement::AllyHeroKilled]);
+    }
+
+    #[test]
+    fn teammates_are_allies() {
+        let mut game = game(Some(Team(0)), Some(Team(0)));
+        let cues = cues_for(&mut game, Some(ME), &kill(OTHER));
+        assert_eq!(announcements(&cues), vec![Announcement::AllyHeroKilled]);
+
+        let mut game = self::game(Some(Team(0)), Some(Team(1)));
+        let cues = cues_for(&mut game, Some(ME), &kill(OTHER));
+        assert_eq!(announcements(&cues), vec![Announcement::EnemyHeroKilled]);
+    }
+
+    #[test]
+    fn killing_blows_play_a_death_where_the_unit_was() {
+        let mut game = game(None, None);
+        let cues = cues_for(&mut game, Some(ME), &kill(OTHER));
+        assert_eq!(cues[0], Cue::Sound(Sound::Death, Point::new(100.0, 0.0)));
+    }
+
+    #[test]
+    fn damage_that_doesnt_kill_is_silent() {
+        let mut game = game(None, None);
+        let event = Event::DamageEntity {
+            id: OTHER,
+            damage: Damage::new(1, DamageKind::Physical),
+        };
+        assert_eq!(cues_for(&mut game, Some(ME), &event), vec![]);
+    }
+
+    #[test]
+    fn only_basic_attacks_play_an_attack_sound() {
+        let mut game = game(None, None);
+        let projectile = |kind| {
+            Event::AddProjectile {
+                id: EntityID(2),
+                position: Point::new(0.0, 0.0),
+                target: Target::Entity(OTHER),
+                damage: Damage::new(5, kind),
+                team: None,
+                owner: ME,
+            }
+        };
+
+        let cues = cues_for(&mut game, Some(ME), &projectile(DamageKind::Physical));
+        assert_eq!(cues, vec![Cue::Sound(Sound::Attack, Point::new(0.0, 0.0))]);
+        let cues = cues_for(&mut game, Some(ME), &projectile(DamageKind::Magical));
+        assert_eq!(cues, vec![]);
+    }
+
+    #[test]
+    fn abilities_play_a_cast_sound_at_the_caster() {
+        let mut game = game(None, None);
+        let event = Event::AbilityUsed {
+            id: OTHER,
+            ability_id: 0,
+        };
+        assert_eq!(
+            cues_for(&mut game, Some(ME), &event),
+            vec![Cue::Sound(Sound::AbilityCast, Point::new(100.0, 0.0))]
+        );
+    }
+
+    #[test]
+    fn cues_play_on_the_null_output() {
+        let mut game = game(None, None);
+        let mut audio = Audio::with_output(
+            AudioSettings::default(),
+            Box::new(NullOutput),
+            "no such sounds.json",
+        );
+        for cue in cues_for(&mut game, Some(ME), &kill(OTHER)) {
+            audio.cue(cue);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use serde_json;
+
+use common::Point;
+
+mod null;
+pub use self::null::*;
+
+mod cue;
+pub use self::cue::*;
+
+#[cfg(feature = "audio")]
+mod rodio_output;
+#[cfg(feature = "audio")]
+pub use self::rodio_output::*;
+
+pub const SOUNDS_PATH: &str = "./assets/sounds.json";
+
+/// Sound effects, played where they happen.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Sound {
+    Attack,
+    ProjectileHit,
+    AbilityCast,
+    Death,
+}
+
+/// Announcer lines, played at the same volume wherever the camera is.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Announcement {
+    MatchStart,
+    AllyHeroKilled,
+    EnemyHeroKilled,
+    Paused,
+    Resumed,
+}
+
+/// Somewhere to send sounds.
+pub trait AudioOutput {
+    /// Plays the file once. `volume` is from 0 to 1.
+    fn play(&mut self, path: &Path, volume: f32);
+
+    /// Loops the file, replacing any music that's already playing.
+    fn play_music(&mut self, path: &Path, volume: f32);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Plays nothing, for running without a sound device.
+    Null,
+    /// The default sound device. Needs the client to be built with the
+    /// `audio` feature, otherwise nothing is played.
+    System,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub output: OutputKind,
+    /// Volumes are from 0 to 1. Every sound is scaled by the master volume.
+    pub master_volume: f32,
+    pub effects_volume: f32,
+    pub music_volume: f32,
+    pub announcer_volume: f32,
+    /// Effects closer than this to the centre of the screen, in game units,
+    /// play at full volume.
+    pub full_volume_distance: f64,
+    /// Effects further away than this can't be heard.
+    pub max_distance: f64,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            output: OutputKind::System,
+            master_volume: 1.0,
+            effects_volume: 0.8,
+            music_volume: 0.4,
+            announcer_volume: 1.0,
+            full_volume_distance: 400.0,
+            max_distance: 1500.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct SoundsFile {
+    #[serde(default)]
+    sounds: HashMap<Sound, String>,
+    #[serde(default)]
+    announcer: HashMap<Announcement, String>,
+    #[serde(default)]
+    music: Option<String>,
+}
+
+/// Plays the sounds described by `sounds.json`, quieter the further they
+/// are from the listener.
+pub struct Audio {
+    settings: AudioSettings,
+    output: Box<AudioOutput>,
+    sounds: HashMap<Sound, PathBuf>,
+    announcer: HashMap<Announcement, PathBuf>,
+    music: Option<PathBuf>,
+    listener: Point,
+}
+
+impl Audio {
+    pub fn new(settings: AudioSettings) -> Self {
+        let output = open_output(settings.output);
+        Audio::with_output(settings, output, SOUNDS_PATH)
+    }
+
+    /// With no sounds if the file is missing or invalid. Sound files are
+    /// relative to it.
+    pub fn with_output<P: AsRef<Path>>(
+        settings: AudioSettings,
+        output: Box<AudioOutput>,
+        path: P,
+    ) -> Self {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|f| {
+                serde_json::from_reader::<_, SoundsFile>(f).map_err(|err| err.to_string())
+            });
+
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                println!("Couldn't load sounds from {}: {}", path.display(), err);
+                SoundsFile::default()
+            }
+        };
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        Audio {
+            settings,
+            output,
+            sounds: file.sounds
+                .into_iter()
+                .map(|(sound, name)| (sound, dir.join(name)))
+                .collect(),
+            announcer: file.announcer
+                .into_iter()
+                .map(|(announcement, name)| (announcement, dir.join(name)))
+                .collect(),
+            music: file.music.map(|name| dir.join(name)),
+            listener: Point::new(0.0, 0.0),
+        }
+    }
+
+    /// Where sounds are heard from, usually the centre of the screen.
+    pub fn set_listener(&mut self, point: Point) {
+        self.listener = point;
+    }
+
+    pub fn play(&mut self, sound: Sound, point: Point) {
+        let volume = self.settings.master_volume * self.settings.effects_volume *
+            attenuation(
+                self.listener.distance_to(point),
+                self.settings.full_volume_distance,
+                self.settings.max_distance,
+            );
+        if volume <= 0.0 {
+            return;
+        }
+
+        if let Some(path) = self.sounds.get(&sound) {
+            self.output.play(path, volume);
+        }
+    }
+
+    pub fn cue(&mut self, cue: Cue) {
+        match cue {
+            Cue::Sound(sound, point) => self.play(sound, point),
+            Cue::Announcement(announcement) => self.announce(announcement),
+        }
+    }
+
+    pub fn announce(&mut self, announcement: Announcement) {
+        let volume = self.settings.master_volume * self.settings.announcer_volume;
+        if let Some(path) = self.announcer.get(&announcement) {
+            self.output.play(path, volume);
+        }
+    }
+
+    pub fn start_music(&mut self) {
+        let volume = self.settings.master_volume * self.settings.music_volume;
+        if let Some(ref path) = self.music {
+            self.output.play_music(path, volume);
+        }
+    }
+}
+
+/// From 1 up to `full_volume_distance`, falling linearly to 0 at `max_distance`.
+pub fn attenuation(distance: f64, full_volume_distance: f64, max_distance: f64) -> f32 {
+    if distance <= full_volume_distance {
+        1.0
+    } else if distance >= max_distance {
+        0.0
+    } else {
+        (1.0 - (distance - full_volume_distance) / (max_distance - full_volume_distance)) as f32
+    }
+}
+
+fn open_output(kind: OutputKind) -> Box<AudioOutput> {
+    match kind {
+        OutputKind::Null => Box::new(NullOutput),
+        OutputKind::System => system_output(),
+    }
+}
+
+#[cfg(feature = "audio")]
+fn system_output() -> Box<AudioOutput> {
+    match RodioOutput::new() {
+        Ok(output) => Box::new(output),
+        Err(err) => {
+            println!("Couldn't open the sound device, playing nothing: {}", err);
+            Box::new(NullOutput)
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn system_output() -> Box<AudioOutput> {
+    println!("Built without the audio feature, playing nothing");
+    Box::new(NullOutput)
+}
@@ -0,0 +1,12 @@
+use std::path::Path;
+
+use super::AudioOutput;
+
+/// Plays nothing.
+pub struct NullOutput;
+
+impl AudioOutput for NullOutput {
+    fn play(&mut self, _: &Path, _: f32) {}
+
+    fn play_music(&mut self, _: &Path, _: f32) {}
+}
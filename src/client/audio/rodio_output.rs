@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use rodio::{self, Decoder, Endpoint, Sink, Source};
+
+use super::AudioOutput;
+
+/// A file's bytes, shared between everything playing it.
+#[derive(Clone)]
+struct SoundData(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SoundData {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Plays through the default sound device.
+pub struct RodioOutput {
+    endpoint: Endpoint,
+    music: Option<Sink>,
+    /// Files are read once, and decoded each time they're played. Files that
+    /// couldn't be read are None, so the error is only printed once.
+    files: HashMap<PathBuf, Option<SoundData>>,
+}
+
+impl RodioOutput {
+    pub fn new() -> Result<Self, String> {
+        let endpoint = match rodio::get_default_endpoint() {
+            Some(endpoint) => endpoint,
+            None => return Err("no sound device".into()),
+        };
+
+        Ok(RodioOutput {
+            endpoint,
+            music: None,
+            files: HashMap::new(),
+        })
+    }
+
+    fn decode(&mut self, path: &Path) -> Option<Decoder<Cursor<SoundData>>> {
+        let data = self.files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let mut bytes = Vec::new();
+                match File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)) {
+                    Ok(_) => Some(SoundData(Arc::new(bytes))),
+                    Err(err) => {
+                        println!("Couldn't read {}: {}", path.display(), err);
+                        None
+                    }
+                }
+            })
+            .clone();
+
+        let data = match data {
+            Some(data) => data,
+            None => return None,
+        };
+
+        match Decoder::new(Cursor::new(data)) {
+            Ok(decoder) => Some(decoder),
+            Err(err) => {
+                println!("Couldn't decode {}: {:?}", path.display(), err);
+                None
+            }
+        }
+    }
+}
+
+impl AudioOutput for RodioOutput {
+    fn play(&mut self, path: &Path, volume: f32) {
+        if let Some(source) = self.decode(path) {
+            let sink = Sink::new(&self.endpoint);
+            sink.set_volume(volume);
+            sink.append(source);
+            sink.detach();
+        }
+    }
+
+    fn play_music(&mut self, path: &Path, volume: f32) {
+        if let Some(source) = self.decode(path) {
+            let sink = Sink::new(&self.endpoint);
+            sink.set_volume(volume);
+            sink.append(source.repeat_infinite());
+            // dropping the old sink stops the old music
+            self.music = Some(sink);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use piston_window::MouseButton;
+
+use common::{logic, Point};
+use super::input::Action;
+use super::render::Viewport;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CameraSettings {
+    /// Screen pixels per second.
+    pub pan_speed: f64,
+    pub edge_pan: bool,
+    /// How close to the edge of the window the cursor must be to pan, in pixels.
+    pub edge_margin: f64,
+    pub min_zoom: f64,
+    pub max_zoom: f64,
+    /// Scale multiplier per scroll wheel step.
+    pub zoom_step: f64,
+    pub start_locked: bool,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            pan_speed: 1000.0,
+            edge_pan: true,
+            edge_margin: 10.0,
+            min_zoom: 0.4,
+            max_zoom: 2.0,
+            zoom_step: 1.1,
+            start_locked: false,
+        }
+    }
+}
+
+/// Moves the viewport around in response to player input.
+pub struct Camera {
+    settings: CameraSettings,
+    locked: bool,
+    /// Centre on the hero as soon as it exists.
+    centre_pending: bool,
+    held: [bool; 4], // up, down, left, right
+    drag_from: Option<(f64, f64)>,
+    cursor_in_window: bool,
+}
+
+impl Camera {
+    pub fn new(settings: CameraSettings) -> Self {
+        Camera {
+            locked: settings.start_locked,
+            settings,
+            centre_pending: true,
+            held: [false; 4],
+            drag_from: None,
+            cursor_in_window: false,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn toggle_lock(&mut self) {
+        self.locked = !self.locked;
+    }
+
+    pub fn centre_on_hero(&mut self) {
+        self.centre_pending = true;
+    }
+
+    /// Starts or stops panning for the pan actions. Returns true if `action` is one.
+    pub fn handle_pan(&mut self, action: Action, held: bool) -> bool {
+        let index = match action {
+            Action::PanUp => 0,
+            Action::PanDown => 1,
+            Action::PanLeft => 2,
+            Action::PanRight => 3,
+            _ => return false,
+        };
+
+        self.held[index] = held;
+        true
+    }
+
+    /// Returns true if the button was used by the camera.
+    pub fn handle_mouse_button(
+        &mut self,
+        button: MouseButton,
+        pressed: bool,
+        x: f64,
+        y: f64,
+    ) -> bool {
+        if button != MouseButton::Middle {
+            return false;
+        }
+
+        self.drag_from = if pressed { Some((x, y)) } else { None };
+        true
+    }
+
+    pub fn handle_cursor(&mut self, viewport: &mut Viewport, x: f64, y: f64) {
+        if let Some((from_x, from_y)) = self.drag_from {
+            let dx = viewport.d_screen_to_game(from_x - x);
+            let dy = viewport.d_screen_to_game(from_y - y);
+            viewport.move_by(dx, dy);
+            self.drag_from = Some((x, y));
+        }
+    }
+
+    pub fn handle_cursor_in_window(&mut self, inside: bool) {
+        self.cursor_in_window = inside;
+        if !inside {
+            self.drag_from = None;
+        }
+    }
+
+    /// Zooms so that the point under the cursor stays put.
+    pub fn handle_scroll(&mut self, viewport: &mut Viewport, steps: f64, x: f64, y: f64) {
+        let scale = (viewport.scale() * self.settings.zoom_step.powf(steps))
+            .max(self.settings.min_zoom)
+            .min(self.settings.max_zoom);
+        viewport.zoom_at(scale, x, y);
+    }
+
+    pub fn update(
+        &mut self,
+        viewport: &mut Viewport,
+        dt: f64,
+        screen_size: (f64, f64),
+        cursor: (f64, f64),
+        hero: Option<Point>,
+        map: logic::MapKind,
+    ) {
+        let (width, height) = screen_size;
+
+        if self.locked || self.centre_pending {
+            if let Some(hero) = hero {
+                viewport.centre_on(hero, width, height);
+                self.centre_pending = false;
+            }
+        }
+
+        if !self.locked && self.drag_from.is_none() {
+            let mut dx = 0.0f64;
+            let mut dy = 0.0f64;
+
+            if self.held[0] {
+                dy -= 1.0;
+            }
+            if self.held[1] {
+                dy += 1.0;
+            }
+            if self.held[2] {
+                dx -= 1.0;
+            }
+            if self.held[3] {
+                dx += 1.0;
+            }
+
+            if self.settings.edge_pan && self.cursor_in_window {
+                let margin = self.settings.edge_margin;
+                let (x, y) = cursor;
+                if x <= margin {
+                    dx -= 1.0;
+                } else if x >= width - margin {
+                    dx += 1.0;
+                }
+                if y <= margin {
+                    dy -= 1.0;
+                } else if y >= height - margin {
+                    dy += 1.0;
+                }
+            }
+
+            let distance = viewport.d_screen_to_game(self.settings.pan_speed * dt);
+            viewport.move_by(
+                dx.max(-1.0).min(1.0) * distance,
+                dy.max(-1.0).min(1.0) * distance,
+            );
+        }
+
+        viewport.clamp_to(map.bounds(), width, height);
+    }
+}
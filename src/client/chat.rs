@@ -0,0 +1,160 @@
+use std::time;
+use piston_window::{self, Context, G2d, Transformed, Key};
+use piston_window::character::CharacterCache;
+
+use common::ChatChannel;
+use super::render::Fonts;
+
+const VISIBLE_LINES: usize = 8;
+const FADE_AFTER_SECS: u64 = 10;
+const LINE_HEIGHT: f64 = 18.0;
+const FONT_SIZE: u32 = 14;
+
+pub struct ChatLine {
+    pub user: String,
+    pub message: String,
+    pub channel: ChatChannel,
+}
+
+/// What the chat box wants done after a key press.
+pub enum ChatAction {
+    Nothing,
+    Send { message: String, channel: ChatChannel },
+}
+
+pub struct ChatBox {
+    lines: Vec<(ChatLine, time::Instant)>,
+    /// `Some` while the chat box has input focus.
+    input: Option<String>,
+    channel: ChatChannel,
+}
+
+impl ChatBox {
+    pub fn new() -> Self {
+        ChatBox {
+            lines: Vec::new(),
+            input: None,
+            channel: ChatChannel::Team,
+        }
+    }
+
+    pub fn push(&mut self, line: ChatLine) {
+        self.lines.push((line, time::Instant::now()));
+    }
+
+    pub fn has_focus(&self) -> bool {
+        self.input.is_some()
+    }
+
+    pub fn focus(&mut self, channel: ChatChannel) {
+        self.channel = channel;
+        self.input = Some(String::new());
+    }
+
+    pub fn handle_text(&mut self, text: &str) {
+        if let Some(ref mut input) = self.input {
+            input.push_str(text);
+        }
+    }
+
+    pub fn handle_key(&mut self, key: Key) -> ChatAction {
+        match key {
+            Key::Return | Key::NumPadEnter => {
+                let message = self.input.take().unwrap_or_default();
+                if message.trim().is_empty() {
+                    ChatAction::Nothing
+                } else {
+                    ChatAction::Send {
+                        message,
+                        channel: self.channel,
+                    }
+                }
+            }
+            Key::Escape => {
+                self.input = None;
+                ChatAction::Nothing
+            }
+            Key::Backspace => {
+                if let Some(ref mut input) = self.input {
+                    input.pop();
+                }
+                ChatAction::Nothing
+            }
+            Key::Tab => {
+                self.channel = match self.channel {
+                    ChatChannel::Team => ChatChannel::All,
+                    _ => ChatChannel::Team,
+                };
+                ChatAction::Nothing
+            }
+            _ => ChatAction::Nothing,
+        }
+    }
+
+    pub fn render(&mut self, c: Context, g: &mut G2d, fonts: &mut Fonts, height: u32) {
+        let focused = self.has_focus();
+        let fade_after = time::Duration::from_secs(FADE_AFTER_SECS);
+        let bottom = height as f64 - 200.0;
+
+        let visible = self.lines
+            .iter()
+            .rev()
+            .filter(|&&(_, t)| focused || t.elapsed() < fade_after)
+            .take(VISIBLE_LINES)
+            .collect::<Vec<_>>();
+
+        for (i, &&(ref line, _)) in visible.iter().enumerate() {
+            let text = if line.user == "" {
+                line.message.clone()
+            } else {
+                format!("{}[{}] {}", channel_prefix(line.channel), line.user, line.message)
+            };
+
+            piston_window::text(
+                channel_colour(line.channel),
+                FONT_SIZE,
+                &text,
+                &mut fonts.regular,
+                c.transform.trans(10.0, bottom - LINE_HEIGHT * (i as f64 + 1.0)),
+                g,
+            );
+        }
+
+        if let Some(ref input) = self.input {
+            let prompt = format!("{}> {}_", channel_prefix(self.channel), input);
+            let width = fonts.regular.width(FONT_SIZE, &prompt);
+
+            piston_window::rectangle(
+                [0.0, 0.0, 0.0, 0.6],
+                [5.0, bottom + 4.0, width.max(300.0) + 10.0, LINE_HEIGHT + 2.0],
+                c.transform,
+                g,
+            );
+            piston_window::text(
+                [1.0; 4],
+                FONT_SIZE,
+                &prompt,
+                &mut fonts.regular,
+                c.transform.trans(10.0, bottom + LINE_HEIGHT),
+                g,
+            );
+        }
+    }
+}
+
+fn channel_prefix(channel: ChatChannel) -> &'static str {
+    match channel {
+        ChatChannel::All => "(All) ",
+        ChatChannel::Team => "(Team) ",
+        ChatChannel::Whisper | ChatChannel::System => "",
+    }
+}
+
+fn channel_colour(channel: ChatChannel) -> [f32; 4] {
+    match channel {
+        ChatChannel::All => [0.0, 0.0, 0.0, 1.0],
+        ChatChannel::Team => [0.0, 0.3, 0.8, 1.0],
+        ChatChannel::Whisper => [0.6, 0.0, 0.6, 1.0],
+        ChatChannel::System => [0.5, 0.4, 0.0, 1.0],
+    }
+}
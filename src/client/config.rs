@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use toml;
+
+use super::audio::AudioSettings;
+use super::camera::CameraSettings;
+use super::input::InputSettings;
+use super::menu::MenuSettings;
+use super::render::GraphicsSettings;
+
+pub const DEFAULT_CONFIG_PATH: &str = "client.toml";
+
+/// Client settings, read from a TOML file. Missing fields take their default values.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub camera: CameraSettings,
+    pub input: InputSettings,
+    pub graphics: GraphicsSettings,
+    pub audio: AudioSettings,
+    pub menu: MenuSettings,
+}
+
+impl ClientConfig {
+    /// Falls back to the defaults if the file is missing or invalid.
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        if !path.exists() {
+            return ClientConfig::default();
+        }
+
+        let mut s = String::new();
+        if let Err(err) = File::open(path).and_then(|mut f| f.read_to_string(&mut s)) {
+            println!("Couldn't read {}: {}", path.display(), err);
+            return ClientConfig::default();
+        }
+
+        match toml::from_str(&s) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Couldn't parse {}: {}", path.display(), err);
+                ClientConfig::default()
+            }
+        }
+    }
+
+    /// Overwrites the file, e.g. after rebinding keys in game.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let s = toml::to_string(self)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        File::create(path)?.write_all(s.as_bytes())
+    }
+}
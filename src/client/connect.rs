@@ -0,0 +1,175 @@
+use std::mem;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use common::{self, ClientMessage, ClientStream, DEFAULT_PORT, EntityID, LinkConditions,
+             ServerMessage};
+use super::menu::ConnectRequest;
+
+/// A connection that's been accepted into a match.
+pub struct Joined {
+    pub stream: ClientStream,
+    pub id: EntityID,
+    /// The capabilities both we and the server support.
+    pub capabilities: Vec<String>,
+}
+
+enum Progress {
+    Connecting,
+    /// The server accepted us, and we're waiting for a match.
+    Waiting(String),
+    Done(Result<Joined, String>),
+}
+
+/// A connection being made in the background, so the window stays responsive.
+pub struct Connecting {
+    request: ConnectRequest,
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl Connecting {
+    pub fn start(request: ConnectRequest, conditions: LinkConditions) -> Self {
+        let progress = Arc::new(Mutex::new(Progress::Connecting));
+
+        {
+            let progress = progress.clone();
+            let request = request.clone();
+            thread::spawn(move || {
+                let result = handshake(&request, conditions, &progress);
+                *progress.lock().unwrap() = Progress::Done(result);
+            });
+        }
+
+        Connecting { request, progress }
+    }
+
+    pub fn request(&self) -> &ConnectRequest {
+        &self.request
+    }
+
+    pub fn status(&self) -> String {
+        match *self.progress.lock().unwrap() {
+            Progress::Connecting => format!("Connecting to {}...", self.request.address),
+            Progress::Waiting(ref message) => format!("{} Waiting to join a match...", message),
+            Progress::Done(_) => String::new(),
+        }
+    }
+
+    /// The match we joined, or why we couldn't join, once the handshake is over.
+    pub fn take_result(&mut self) -> Option<Result<Joined, String>> {
+        let mut progress = self.progress.lock().unwrap();
+        if let Progress::Done(_) = *progress {
+            match mem::replace(&mut *progress, Progress::Connecting) {
+                Progress::Done(result) => Some(result),
+                _ => unreachable!(),
+            }
+        } else {
+            None
+        }
+    }
+}
+
+fn handshake(
+    request: &ConnectRequest,
+    conditions: LinkConditions,
+    progress: &Mutex<Progress>,
+) -> Result<Joined, String> {
+    let addr = resolve(&request.address)?;
+    let tls = match request.tls_cert {
+        Some(_) if request.udp => return Err("Encryption is only supported over TCP.".into()),
+        Some(ref path) => {
+            let config =
+                common::client_config(path).map_err(|err| format!("{}: {}", path, err))?;
+            Some(config)
+        }
+        None => None,
+    };
+
+    let stream = if request.udp {
+        ClientStream::connect_udp(addr, conditions)
+    } else {
+        TcpStream::connect(addr).and_then(|mut stream| match tls {
+            Some(ref config) => {
+                common::negotiate_tls(&mut stream)?;
+                Ok(ClientStream::with_tls(stream, config, conditions))
+            }
+            None => Ok(ClientStream::with_conditions(stream, conditions)),
+        })
+    };
+    let mut stream =
+        stream.map_err(|err| format!("Couldn't connect to {}: {}", request.address, err))?;
+
+    stream
+        .write_message(ClientMessage::Connect {
+            protocol_version: common::PROTOCOL_VERSION,
+            capabilities: common::CAPABILITIES.iter().map(|&c| c.into()).collect(),
+            name: request.name.clone(),
+            team: request.team,
+            match_id: request.match_id,
+        })
+        .map_err(|err| format!("Couldn't talk to the server: {}", err))?;
+
+    let mut reply = receive(&stream)?;
+    if let ServerMessage::AuthChallenge { nonce } = reply {
+        let proof = request.token.as_ref().map(|token| common::auth_proof(token, &nonce));
+        stream
+            .write_message(ClientMessage::AuthResponse { proof })
+            .map_err(|err| format!("Couldn't talk to the server: {}", err))?;
+        reply = receive(&stream)?;
+    }
+
+    let capabilities = match reply {
+        ServerMessage::AcceptConnection {
+            message,
+            protocol_version,
+            capabilities,
+            ..
+        } => {
+            // servers from before versioning accept anyone, so check here too
+            if protocol_version != common::PROTOCOL_VERSION {
+                return Err(format!(
+                    "The server speaks protocol {}, but this client needs {}.",
+                    protocol_version,
+                    common::PROTOCOL_VERSION
+                ));
+            }
+            *progress.lock().unwrap() = Progress::Waiting(message);
+            capabilities
+        }
+        ServerMessage::Kick { reason } => return Err(reason),
+        _ => return Err("The server sent something unexpected.".into()),
+    };
+
+    match receive(&stream)? {
+        ServerMessage::SetPlayerEntityID(id) => {
+            Ok(Joined {
+                stream,
+                id,
+                capabilities,
+            })
+        }
+        ServerMessage::Kick { reason } => Err(reason),
+        _ => Err("The server sent something unexpected.".into()),
+    }
+}
+
+fn receive(stream: &ClientStream) -> Result<ServerMessage, String> {
+    stream
+        .get_message()
+        .map_err(|err| format!("Lost connection to the server: {}", err))
+}
+
+/// `host` or `host:port`, on `DEFAULT_PORT` if no port is given.
+pub fn resolve(address: &str) -> Result<SocketAddr, String> {
+    let with_port = if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:{}", address, DEFAULT_PORT)
+    };
+
+    match with_port.to_socket_addrs() {
+        Ok(mut addrs) => addrs.next().ok_or_else(|| format!("Couldn't find {}.", address)),
+        Err(err) => Err(format!("Couldn't find {}: {}", address, err)),
+    }
+}
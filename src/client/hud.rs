@@ -0,0 +1,502 @@
+use std::f64::consts::PI;
+use std::collections::HashMap;
+use conrod::{self, color, widget, Colorable, Positionable, Sizeable, Widget};
+use conrod::backend::piston::draw;
+use conrod::color::Color;
+use gfx_device_gl::Factory;
+use piston_window::{self, Context, G2d, G2dTexture, TextureSettings};
+use piston_window::texture::UpdateTexture;
+
+use common::*;
+
+const ABILITY_SLOTS: u32 = 4;
+const SLOT_SIZE: f64 = 56.0;
+const SLOT_GAP: f64 = 8.0;
+const BAR_MARGIN: f64 = 15.0;
+const PANEL_WIDTH: f64 = 220.0;
+const PANEL_HEIGHT: f64 = 110.0;
+const HEALTH_BAR_HEIGHT: f64 = 8.0;
+const ROW_HEIGHT: f64 = 22.0;
+const SCOREBOARD_WIDTH: f64 = 880.0;
+const SCOREBOARD_COLUMNS: &[(&str, f64)] = &[
+    ("Name", 10.0),
+    ("Team", 180.0),
+    ("Hero", 230.0),
+    ("HP", 320.0),
+    ("Ping", 400.0),
+    ("Jitter", 470.0),
+    ("Packets in/out", 540.0),
+    ("KB in/out", 670.0),
+    ("Queued", 790.0),
+];
+
+const PANEL_COLOUR: Color = Color::Rgba(0.1, 0.1, 0.1, 0.8);
+const TEXT_COLOUR: Color = Color::Rgba(1.0, 1.0, 1.0, 1.0);
+const OWN_ROW_COLOUR: Color = Color::Rgba(1.0, 1.0, 0.5, 1.0);
+
+const REGULAR_FONT: &str = "./assets/fonts/NotoSans-unhinted/NotoSans-Regular.ttf";
+const BOLD_FONT: &str = "./assets/fonts/NotoSans-unhinted/NotoSans-Bold.ttf";
+/// Width and height of the texture glyphs are cached in.
+const GLYPH_CACHE_SIZE: u32 = 1024;
+
+widget_ids! {
+    struct Ids {
+        ability_bar,
+        slots[],
+        slot_sweeps[],
+        slot_timers[],
+        slot_keys[],
+        slot_names[],
+        panels[],
+        portraits[],
+        titles[],
+        teams[],
+        health_backs[],
+        health_fills[],
+        health_labels[],
+        stats[],
+        scoreboard,
+        scoreboard_titles[],
+        scoreboard_cells[],
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Fonts {
+    regular: conrod::text::font::Id,
+    bold: conrod::text::font::Id,
+}
+
+/// The in-game overlay: ability bar, hero and selected unit panels, and the scoreboard.
+pub struct Hud {
+    ui: conrod::Ui,
+    ids: Ids,
+    fonts: Fonts,
+    glyph_cache: conrod::text::GlyphCache,
+    /// Created once there's a window, see `load`.
+    glyph_texture: Option<G2dTexture>,
+    /// Reused for uploading glyphs to `glyph_texture`.
+    glyph_pixels: Vec<u8>,
+    image_map: conrod::image::Map<G2dTexture>,
+    /// The window size the UI was laid out for.
+    size: [f64; 2],
+    /// The key bound to each ability slot.
+    ability_labels: Vec<String>,
+    show_scoreboard: bool,
+}
+
+impl Hud {
+    pub fn new() -> Self {
+        let size = [800.0, 600.0];
+        let mut ui = conrod::UiBuilder::new(size).build();
+        let ids = Ids::new(ui.widget_id_generator());
+        let fonts = Fonts {
+            regular: ui.fonts.insert_from_file(REGULAR_FONT).unwrap(),
+            bold: ui.fonts.insert_from_file(BOLD_FONT).unwrap(),
+        };
+
+        Hud {
+            ui,
+            ids,
+            fonts,
+            glyph_cache: conrod::text::GlyphCache::new(GLYPH_CACHE_SIZE, GLYPH_CACHE_SIZE, 0.1, 0.1),
+            glyph_texture: None,
+            glyph_pixels: Vec::new(),
+            image_map: conrod::image::Map::new(),
+            size,
+            ability_labels: vec![String::new(); ABILITY_SLOTS as usize],
+            show_scoreboard: false,
+        }
+    }
+
+    /// Creates the texture text is drawn from. Nothing is drawn until then.
+    pub fn load(&mut self, factory: &mut Factory) {
+        let blank = vec![0; (GLYPH_CACHE_SIZE * GLYPH_CACHE_SIZE) as usize];
+        let texture = G2dTexture::from_memory_alpha(
+            factory,
+            &blank,
+            GLYPH_CACHE_SIZE,
+            GLYPH_CACHE_SIZE,
+            &TextureSettings::new(),
+        );
+        match texture {
+            Ok(texture) => self.glyph_texture = Some(texture),
+            Err(err) => println!("Couldn't create the HUD's glyph texture: {:?}", err),
+        }
+    }
+
+    pub fn set_ability_labels(&mut self, labels: Vec<String>) {
+        self.ability_labels = labels;
+    }
+
+    pub fn set_scoreboard_visible(&mut self, visible: bool) {
+        self.show_scoreboard = visible;
+    }
+
+    pub fn render(
+        &mut self,
+        c: Context,
+        g: &mut G2d,
+        game: &mut Game,
+        net_stats: &HashMap<EntityID, NetStats>,
+        own_id: Option<EntityID>,
+        selected_id: Option<EntityID>,
+        width: f64,
+        height: f64,
+    ) {
+        if self.size != [width, height] {
+            self.size = [width, height];
+            self.ui.handle_event(conrod::event::Input::Resize(width as u32, height as u32));
+        }
+
+        self.set_widgets(game, net_stats, own_id, selected_id);
+
+        let texture = match self.glyph_texture {
+            Some(ref mut texture) => texture,
+            None => return,
+        };
+        let glyph_pixels = &mut self.glyph_pixels;
+        let cache_queued_glyphs = |g: &mut G2d,
+                                   cache: &mut G2dTexture,
+                                   rect: conrod::text::rt::Rect<u32>,
+                                   data: &[u8]| {
+            // the cache only has coverage, which becomes the alpha of white
+            glyph_pixels.clear();
+            glyph_pixels.extend(data.iter().flat_map(|&a| vec![255, 255, 255, a]));
+
+            let offset = [rect.min.x, rect.min.y];
+            let size = [rect.width(), rect.height()];
+            let format = piston_window::texture::Format::Rgba8;
+            let encoder = &mut g.encoder;
+            UpdateTexture::update(cache, encoder, format, &glyph_pixels[..], offset, size)
+                .expect("couldn't update the HUD's glyph texture")
+        };
+        fn texture_from_image<T>(image: &T) -> &T {
+            image
+        }
+
+        draw::primitives(
+            self.ui.draw(),
+            c,
+            g,
+            texture,
+            &mut self.glyph_cache,
+            &self.image_map,
+            cache_queued_glyphs,
+            texture_from_image,
+        );
+    }
+
+    fn set_widgets(
+        &mut self,
+        game: &mut Game,
+        net_stats: &HashMap<EntityID, NetStats>,
+        own_id: Option<EntityID>,
+        selected_id: Option<EntityID>,
+    ) {
+        let (width, height) = (self.size[0], self.size[1]);
+        let fonts = self.fonts;
+        let ids = &mut self.ids;
+        let ui = &mut self.ui.set_widgets();
+
+        let bar_width = ABILITY_SLOTS as f64 * (SLOT_SIZE + SLOT_GAP) - SLOT_GAP;
+        ids.panels.resize(2, &mut ui.widget_id_generator());
+
+        if let Some(id) = own_id {
+            let player = game.clone_component::<Player>(id);
+            let cooldowns = game.clone_component::<AbilityCooldowns>(id);
+            if let (Some(player), Some(cooldowns)) = (player, cooldowns) {
+                set_ability_bar(
+                    ui,
+                    ids,
+                    fonts,
+                    &self.ability_labels,
+                    player.hero,
+                    &cooldowns,
+                    bar_width,
+                    height,
+                );
+            }
+
+            let left = (width - bar_width) / 2.0 - PANEL_WIDTH - 20.0;
+            let panel = widget::Rectangle::fill([PANEL_WIDTH, PANEL_HEIGHT])
+                .bottom_left_with_margins_on(ui.window, 10.0, left);
+            set_unit_panel(ui, ids, fonts, 0, panel, game, id);
+        }
+
+        if let Some(id) = selected_id {
+            if Some(id) != own_id && game.get_entity(id).is_some() {
+                let panel = widget::Rectangle::fill([PANEL_WIDTH, PANEL_HEIGHT])
+                    .mid_top_with_margin_on(ui.window, 10.0);
+                set_unit_panel(ui, ids, fonts, 1, panel, game, id);
+            }
+        }
+
+        if self.show_scoreboard {
+            set_scoreboard(ui, ids, fonts, game, net_stats, own_id);
+        }
+    }
+}
+
+fn set_ability_bar(
+    ui: &mut conrod::UiCell,
+    ids: &mut Ids,
+    fonts: Fonts,
+    labels: &[String],
+    hero: logic::HeroKind,
+    cooldowns: &AbilityCooldowns,
+    bar_width: f64,
+    window_height: f64,
+) {
+    let slots = ABILITY_SLOTS as usize;
+    ids.slots.resize(slots, &mut ui.widget_id_generator());
+    ids.slot_sweeps.resize(slots, &mut ui.widget_id_generator());
+    ids.slot_timers.resize(slots, &mut ui.widget_id_generator());
+    ids.slot_keys.resize(slots, &mut ui.widget_id_generator());
+    ids.slot_names.resize(slots, &mut ui.widget_id_generator());
+
+    widget::Rectangle::fill_with([bar_width, SLOT_SIZE], color::TRANSPARENT)
+        .mid_bottom_with_margin_on(ui.window, BAR_MARGIN)
+        .set(ids.ability_bar, ui);
+
+    for slot in 0..ABILITY_SLOTS {
+        let i = slot as usize;
+        let offset = slot as f64 * (SLOT_SIZE + SLOT_GAP);
+        let name = hero.ability_name(slot);
+
+        widget::Rectangle::fill([SLOT_SIZE, SLOT_SIZE])
+            .color(if name.is_some() {
+                color::rgba(0.25, 0.35, 0.6, 1.0)
+            } else {
+                color::rgba(0.3, 0.3, 0.3, 0.6)
+            })
+            .top_left_with_margins_on(ids.ability_bar, 0.0, offset)
+            .set(ids.slots[i], ui);
+
+        let remaining = cooldowns.remaining.get(i).cloned().unwrap_or(0.0);
+        if let (Some(_), Some(cooldown)) = (name, hero.ability_cooldown(slot)) {
+            if remaining > 0.0 {
+                // conrod's origin is the middle of the window, with y up
+                let centre = [
+                    -bar_width / 2.0 + offset + SLOT_SIZE / 2.0,
+                    -window_height / 2.0 + BAR_MARGIN + SLOT_SIZE / 2.0,
+                ];
+                let sweep = cooldown_sweep(centre, SLOT_SIZE, remaining / cooldown);
+                widget::Polygon::abs_fill(sweep)
+                    .color(color::rgba(0.0, 0.0, 0.0, 0.6))
+                    .set(ids.slot_sweeps[i], ui);
+
+                widget::Text::new(&format!("{:.1}", remaining))
+                    .font_id(fonts.bold)
+                    .font_size(16)
+                    .color(TEXT_COLOUR)
+                    .middle_of(ids.slots[i])
+                    .set(ids.slot_timers[i], ui);
+            }
+        }
+
+        if let Some(label) = labels.get(i) {
+            widget::Text::new(label)
+                .font_id(fonts.bold)
+                .font_size(12)
+                .color(TEXT_COLOUR)
+                .top_left_with_margins_on(ids.slots[i], 2.0, 3.0)
+                .set(ids.slot_keys[i], ui);
+        }
+
+        if let Some(name) = name {
+            widget::Text::new(name)
+                .font_id(fonts.regular)
+                .font_size(11)
+                .color(TEXT_COLOUR)
+                .mid_bottom_with_margin_on(ids.slots[i], 3.0)
+                .set(ids.slot_names[i], ui);
+        }
+    }
+}
+
+/// The unavailable `fraction` of a square around `centre`, sweeping clockwise
+/// from the top.
+fn cooldown_sweep(centre: [f64; 2], size: f64, fraction: f64) -> Vec<[f64; 2]> {
+    let half = size / 2.0;
+    let start = PI / 2.0;
+    let end = start - fraction.max(0.0).min(1.0) * 2.0 * PI;
+
+    // a fan around the centre, with points projected out onto the square's edges
+    let steps = 48;
+    let mut points = vec![centre];
+    for i in 0..steps + 1 {
+        let angle = start + (end - start) * i as f64 / steps as f64;
+        let (dx, dy) = (angle.cos(), angle.sin());
+        let t = half / dx.abs().max(dy.abs());
+        points.push([centre[0] + dx * t, centre[1] + dy * t]);
+    }
+    points
+}
+
+/// `panel` is where to put it, and `index` which set of widgets to use.
+fn set_unit_panel(
+    ui: &mut conrod::UiCell,
+    ids: &mut Ids,
+    fonts: Fonts,
+    index: usize,
+    panel: widget::Rectangle,
+    game: &mut Game,
+    id: EntityID,
+) {
+    let count = ids.panels.len();
+    ids.portraits.resize(count, &mut ui.widget_id_generator());
+    ids.titles.resize(count, &mut ui.widget_id_generator());
+    ids.teams.resize(count, &mut ui.widget_id_generator());
+    ids.health_backs.resize(count, &mut ui.widget_id_generator());
+    ids.health_fills.resize(count, &mut ui.widget_id_generator());
+    ids.health_labels.resize(count, &mut ui.widget_id_generator());
+    ids.stats.resize(count, &mut ui.widget_id_generator());
+
+    let panel_id = ids.panels[index];
+    panel.color(PANEL_COLOUR).set(panel_id, ui);
+
+    let colour = game.clone_component::<Renderable>(id)
+        .map(|r| r.colour)
+        .unwrap_or([0.5; 4]);
+    widget::Oval::fill([50.0, 50.0])
+        .color(color::rgba(colour[0], colour[1], colour[2], colour[3]))
+        .top_left_with_margins_on(panel_id, 10.0, 10.0)
+        .set(ids.portraits[index], ui);
+
+    let player = game.clone_component::<Player>(id);
+    let title = match player {
+        Some(ref p) => format!("{} ({:?})", p.name, p.hero),
+        None => format!("Entity {}", id.0),
+    };
+    widget::Text::new(&title)
+        .font_id(fonts.bold)
+        .font_size(14)
+        .color(TEXT_COLOUR)
+        .top_left_with_margins_on(panel_id, 10.0, 70.0)
+        .set(ids.titles[index], ui);
+
+    let team = game.clone_component::<Team>(id)
+        .map(|t| format!("Team {}", t.0))
+        .unwrap_or("No team".into());
+    widget::Text::new(&team)
+        .font_id(fonts.regular)
+        .font_size(12)
+        .color(TEXT_COLOUR)
+        .top_left_with_margins_on(panel_id, 30.0, 70.0)
+        .set(ids.teams[index], ui);
+
+    if let Some(hp) = game.clone_component::<Hitpoints>(id) {
+        let fraction = if hp.max() == 0 {
+            0.0
+        } else {
+            hp.current() as f64 / hp.max() as f64
+        };
+        let bar_width = PANEL_WIDTH - 20.0;
+
+        widget::Rectangle::fill([bar_width, HEALTH_BAR_HEIGHT])
+            .color(color::rgba(0.2, 0.2, 0.2, 1.0))
+            .top_left_with_margins_on(panel_id, 66.0, 10.0)
+            .set(ids.health_backs[index], ui);
+        widget::Rectangle::fill([bar_width * fraction, HEALTH_BAR_HEIGHT])
+            .color(color::rgba((1.0 - fraction) as f32, fraction as f32 * 0.8, 0.0, 1.0))
+            .top_left_of(ids.health_backs[index])
+            .set(ids.health_fills[index], ui);
+
+        widget::Text::new(&format!("{}/{}", hp.current(), hp.max()))
+            .font_id(fonts.regular)
+            .font_size(11)
+            .color(TEXT_COLOUR)
+            .top_left_with_margins_on(panel_id, 78.0, 12.0)
+            .set(ids.health_labels[index], ui);
+    }
+
+    if let Some(p) = player {
+        let stats = format!(
+            "SPD {:.0}   RNG {:.0}   AS {:.2}",
+            p.hero.speed(),
+            p.hero.range(),
+            p.hero.attack_speed()
+        );
+        widget::Text::new(&stats)
+            .font_id(fonts.regular)
+            .font_size(11)
+            .color(TEXT_COLOUR)
+            .top_left_with_margins_on(panel_id, 92.0, 12.0)
+            .set(ids.stats[index], ui);
+    }
+}
+
+fn set_scoreboard(
+    ui: &mut conrod::UiCell,
+    ids: &mut Ids,
+    fonts: Fonts,
+    game: &mut Game,
+    net_stats: &HashMap<EntityID, NetStats>,
+    own_id: Option<EntityID>,
+) {
+    let mut rows = Vec::new();
+    for id in game.entity_ids_cloned() {
+        if let Some(player) = game.clone_component::<Player>(id) {
+            let team = game.clone_component::<Team>(id);
+            let hp = game.clone_component::<Hitpoints>(id);
+            rows.push((id, player, team, hp));
+        }
+    }
+    rows.sort_by_key(|&(id, _, team, _)| (team.map(|t| t.0), id.0));
+
+    let columns = SCOREBOARD_COLUMNS.len();
+    ids.scoreboard_titles.resize(columns, &mut ui.widget_id_generator());
+    ids.scoreboard_cells.resize(rows.len() * columns, &mut ui.widget_id_generator());
+
+    widget::Rectangle::fill([SCOREBOARD_WIDTH, ROW_HEIGHT * (rows.len() as f64 + 1.0) + 10.0])
+        .color(PANEL_COLOUR)
+        .mid_top_with_margin_on(ui.window, 60.0)
+        .set(ids.scoreboard, ui);
+
+    for (i, &(title, offset)) in SCOREBOARD_COLUMNS.iter().enumerate() {
+        widget::Text::new(title)
+            .font_id(fonts.bold)
+            .font_size(14)
+            .color(TEXT_COLOUR)
+            .top_left_with_margins_on(ids.scoreboard, 6.0, offset)
+            .set(ids.scoreboard_titles[i], ui);
+    }
+
+    for (row, &(id, ref player, team, ref hp)) in rows.iter().enumerate() {
+        let colour = if Some(id) == own_id {
+            OWN_ROW_COLOUR
+        } else {
+            TEXT_COLOUR
+        };
+
+        let mut cells = vec![
+            player.name.clone(),
+            team.map(|t| t.0.to_string()).unwrap_or("-".into()),
+            format!("{:?}", player.hero),
+            hp.as_ref()
+                .map(|hp| format!("{}/{}", hp.current(), hp.max()))
+                .unwrap_or("-".into()),
+        ];
+        if let Some(stats) = net_stats.get(&id) {
+            cells.extend(vec![
+                stats.rtt_ms.map(|rtt| format!("{}ms", rtt)).unwrap_or("-".into()),
+                stats.jitter_ms.map(|jitter| format!("{}ms", jitter)).unwrap_or("-".into()),
+                format!("{}/{}", stats.packets_in, stats.packets_out),
+                format!("{}/{}", stats.bytes_in / 1024, stats.bytes_out / 1024),
+                format!("{} KB", stats.queued_bytes / 1024),
+            ]);
+        }
+        // the server doesn't measure connections, or hasn't yet
+        cells.resize(SCOREBOARD_COLUMNS.len(), "-".into());
+        let top = 6.0 + ROW_HEIGHT * (row as f64 + 1.0);
+        for (column, (cell, &(_, offset))) in cells.iter().zip(SCOREBOARD_COLUMNS).enumerate() {
+            widget::Text::new(cell)
+                .font_id(fonts.regular)
+                .font_size(13)
+                .color(colour)
+                .top_left_with_margins_on(ids.scoreboard, top, offset)
+                .set(ids.scoreboard_cells[row * columns + column], ui);
+        }
+    }
+}
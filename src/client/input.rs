@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+use piston_window::{Button, Key, MouseButton};
+
+use super::keys::{key_from_name, key_name};
+
+/// Something the player can do, independent of which key or button does it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Select,
+    MoveTo,
+    AttackMove,
+    Stop,
+    HoldPosition,
+    Ability1,
+    Ability2,
+    Ability3,
+    Ability4,
+    CameraLock,
+    CameraCentre,
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    Scoreboard,
+    Pause,
+    ChatTeam,
+    ChatAll,
+    Bindings,
+}
+
+/// Every action, in the order they're listed on the bindings screen.
+pub const ACTIONS: &[Action] = &[
+    Action::Select,
+    Action::MoveTo,
+    Action::AttackMove,
+    Action::Stop,
+    Action::HoldPosition,
+    Action::Ability1,
+    Action::Ability2,
+    Action::Ability3,
+    Action::Ability4,
+    Action::CameraLock,
+    Action::CameraCentre,
+    Action::PanUp,
+    Action::PanDown,
+    Action::PanLeft,
+    Action::PanRight,
+    Action::Scoreboard,
+    Action::Pause,
+    Action::ChatTeam,
+    Action::ChatAll,
+    Action::Bindings,
+];
+
+impl Action {
+    /// The name used in the client config.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Action::Select => "select",
+            Action::MoveTo => "move_to",
+            Action::AttackMove => "attack_move",
+            Action::Stop => "stop",
+            Action::HoldPosition => "hold_position",
+            Action::Ability1 => "ability_1",
+            Action::Ability2 => "ability_2",
+            Action::Ability3 => "ability_3",
+            Action::Ability4 => "ability_4",
+            Action::CameraLock => "camera_lock",
+            Action::CameraCentre => "camera_centre",
+            Action::PanUp => "pan_up",
+            Action::PanDown => "pan_down",
+            Action::PanLeft => "pan_left",
+            Action::PanRight => "pan_right",
+            Action::Scoreboard => "scoreboard",
+            Action::Pause => "pause",
+            Action::ChatTeam => "chat_team",
+            Action::ChatAll => "chat_all",
+            Action::Bindings => "bindings",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTIONS.iter().cloned().find(|a| a.name() == name)
+    }
+
+    /// A human readable description for the bindings screen.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Action::Select => "Select unit",
+            Action::MoveTo => "Move / attack",
+            Action::AttackMove => "Attack-move",
+            Action::Stop => "Stop",
+            Action::HoldPosition => "Hold position",
+            Action::Ability1 => "Ability 1",
+            Action::Ability2 => "Ability 2",
+            Action::Ability3 => "Ability 3",
+            Action::Ability4 => "Ability 4",
+            Action::CameraLock => "Lock camera to hero",
+            Action::CameraCentre => "Centre camera on hero",
+            Action::PanUp => "Pan camera up",
+            Action::PanDown => "Pan camera down",
+            Action::PanLeft => "Pan camera left",
+            Action::PanRight => "Pan camera right",
+            Action::Scoreboard => "Show scoreboard (hold)",
+            Action::Pause => "Pause / unpause",
+            Action::ChatTeam => "Chat to team",
+            Action::ChatAll => "Chat to everyone",
+            Action::Bindings => "Key bindings",
+        }
+    }
+
+    pub fn ability_id(&self) -> Option<u32> {
+        match *self {
+            Action::Ability1 => Some(0),
+            Action::Ability2 => Some(1),
+            Action::Ability3 => Some(2),
+            Action::Ability4 => Some(3),
+            _ => None,
+        }
+    }
+
+    fn default_binding(&self) -> &'static str {
+        match *self {
+            Action::Select => "MouseLeft",
+            Action::MoveTo => "MouseRight",
+            Action::AttackMove => "A",
+            Action::Stop => "S",
+            Action::HoldPosition => "H",
+            Action::Ability1 => "Q",
+            Action::Ability2 => "W",
+            Action::Ability3 => "E",
+            Action::Ability4 => "R",
+            Action::CameraLock => "Y",
+            Action::CameraCentre => "Space",
+            Action::PanUp => "Up",
+            Action::PanDown => "Down",
+            Action::PanLeft => "Left",
+            Action::PanRight => "Right",
+            Action::Scoreboard => "Tab",
+            Action::Pause => "F9",
+            Action::ChatTeam => "Return",
+            Action::ChatAll => "Shift+Return",
+            Action::Bindings => "F1",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    pub fn is_empty(&self) -> bool {
+        !self.shift && !self.ctrl && !self.alt
+    }
+
+    /// Updates the held modifiers. Returns true if `key` is a modifier key.
+    pub fn handle_key(&mut self, key: Key, pressed: bool) -> bool {
+        match key {
+            Key::LShift | Key::RShift => self.shift = pressed,
+            Key::LCtrl | Key::RCtrl => self.ctrl = pressed,
+            Key::LAlt | Key::RAlt => self.alt = pressed,
+            _ => return false,
+        }
+        true
+    }
+}
+
+const MOUSE_BUTTONS: &[(&str, MouseButton)] = &[
+    ("MouseLeft", MouseButton::Left),
+    ("MouseRight", MouseButton::Right),
+    ("MouseMiddle", MouseButton::Middle),
+    ("MouseX1", MouseButton::X1),
+    ("MouseX2", MouseButton::X2),
+];
+
+/// A key or mouse button, plus the modifiers that must be held with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Binding {
+    pub button: Button,
+    pub modifiers: Modifiers,
+}
+
+impl Binding {
+    /// Parses bindings such as `"Q"`, `"Shift+Return"` or `"Ctrl+MouseRight"`.
+    pub fn parse(s: &str) -> Option<Binding> {
+        let mut modifiers = Modifiers::default();
+        let mut parts: Vec<&str> = s.split('+').map(|p| p.trim()).collect();
+        let button_name = match parts.pop() {
+            Some(name) => name,
+            None => return None,
+        };
+
+        for part in parts {
+            match &*part.to_lowercase() {
+                "shift" => modifiers.shift = true,
+                "ctrl" => modifiers.ctrl = true,
+                "alt" => modifiers.alt = true,
+                _ => return None,
+            }
+        }
+
+        MOUSE_BUTTONS
+            .iter()
+            .find(|&&(n, _)| n.eq_ignore_ascii_case(button_name))
+            .map(|&(_, b)| Button::Mouse(b))
+            .or_else(|| key_from_name(button_name).map(Button::Keyboard))
+            .map(|button| Binding { button, modifiers })
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut s = String::new();
+        if self.modifiers.ctrl {
+            s.push_str("Ctrl+");
+        }
+        if self.modifiers.alt {
+            s.push_str("Alt+");
+        }
+        if self.modifiers.shift {
+            s.push_str("Shift+");
+        }
+
+        match self.button {
+            Button::Keyboard(key) => s.push_str(&key_name(key)),
+            Button::Mouse(mouse_button) => {
+                match MOUSE_BUTTONS.iter().find(|&&(_, b)| b == mouse_button) {
+                    Some(&(name, _)) => s.push_str(name),
+                    None => s.push_str(&format!("{:?}", mouse_button)),
+                }
+            }
+            button => s.push_str(&format!("{:?}", button)),
+        }
+
+        s
+    }
+}
+
+/// When an ability or attack-move fires.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CastMode {
+    /// As soon as the key is pressed, at the cursor.
+    Quick,
+    /// Holding the key shows the targeting indicator, and the ability is cast
+    /// at the cursor when the key is released or the select button is clicked.
+    /// The move button cancels.
+    Smart,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InputSettings {
+    pub cast_mode: CastMode,
+    /// Action name to binding, e.g. `ability_1 = "Q"`. Unlisted actions keep
+    /// their default bindings, and an empty string unbinds an action.
+    pub bindings: BTreeMap<String, String>,
+}
+
+impl Default for InputSettings {
+    fn default() -> Self {
+        InputSettings {
+            cast_mode: CastMode::Quick,
+            bindings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Maps keys and buttons to actions.
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    bindings: Vec<(Action, Option<Binding>)>,
+    pub cast_mode: CastMode,
+}
+
+impl Bindings {
+    pub fn new(settings: &InputSettings) -> Self {
+        let mut bindings: Vec<(Action, Option<Binding>)> = ACTIONS
+            .iter()
+            .map(|&a| (a, Binding::parse(a.default_binding())))
+            .collect();
+
+        for (name, binding) in &settings.bindings {
+            let action = match Action::from_name(name) {
+                Some(action) => action,
+                None => {
+                    println!("Unknown action {:?} in input bindings", name);
+                    continue;
+                }
+            };
+
+            let binding = if binding.trim().is_empty() {
+                None
+            } else {
+                match Binding::parse(binding) {
+                    Some(binding) => Some(binding),
+                    None => {
+                        println!(
+                            "Unknown binding {:?} for {}, using {}",
+                            binding,
+                            name,
+                            action.default_binding()
+                        );
+                        continue;
+                    }
+                }
+            };
+
+            for entry in &mut bindings {
+                if entry.0 == action {
+                    entry.1 = binding;
+                }
+            }
+        }
+
+        Bindings {
+            bindings,
+            cast_mode: settings.cast_mode,
+        }
+    }
+
+    /// Only bindings that differ from the defaults are written out.
+    pub fn to_settings(&self) -> InputSettings {
+        let mut settings = InputSettings {
+            cast_mode: self.cast_mode,
+            bindings: BTreeMap::new(),
+        };
+
+        for &(action, binding) in &self.bindings {
+            if binding != Binding::parse(action.default_binding()) {
+                settings.bindings.insert(
+                    action.name().into(),
+                    binding.map(|b| b.to_string()).unwrap_or_default(),
+                );
+            }
+        }
+
+        settings
+    }
+
+    pub fn binding(&self, action: Action) -> Option<Binding> {
+        self.bindings
+            .iter()
+            .find(|&&(a, _)| a == action)
+            .and_then(|&(_, b)| b)
+    }
+
+    /// Binds `action`, unbinding any other action that used the same binding.
+    pub fn set(&mut self, action: Action, binding: Option<Binding>) {
+        for entry in &mut self.bindings {
+            if entry.0 == action {
+                entry.1 = binding;
+            } else if binding.is_some() && entry.1 == binding {
+                entry.1 = None;
+            }
+        }
+    }
+
+    /// The action for a button press. A binding with exactly the held modifiers
+    /// wins; otherwise an unmodified binding matches, so that e.g. Shift+right
+    /// click still moves.
+    pub fn action_for_press(&self, button: Button, modifiers: Modifiers) -> Option<Action> {
+        let exact = self.bindings.iter().find(|&&(_, b)| {
+            b == Some(Binding { button, modifiers })
+        });
+        let plain = || {
+            self.bindings.iter().find(|&&(_, b)| {
+                b.map_or(false, |b| b.button == button && b.modifiers.is_empty())
+            })
+        };
+
+        exact.or_else(plain).map(|&(a, _)| a)
+    }
+
+    /// Every action bound to the button, whatever the modifiers. Modifiers may
+    /// have been let go before the button itself.
+    pub fn actions_for_release(&self, button: Button) -> Vec<Action> {
+        self.bindings
+            .iter()
+            .filter(|&&(_, b)| b.map_or(false, |b| b.button == button))
+            .map(|&(a, _)| a)
+            .collect()
+    }
+}
@@ -0,0 +1,84 @@
+use piston_window::Key;
+
+const NAMED_KEYS: &[(&str, Key)] = &[
+    ("A", Key::A),
+    ("B", Key::B),
+    ("C", Key::C),
+    ("D", Key::D),
+    ("E", Key::E),
+    ("F", Key::F),
+    ("G", Key::G),
+    ("H", Key::H),
+    ("I", Key::I),
+    ("J", Key::J),
+    ("K", Key::K),
+    ("L", Key::L),
+    ("M", Key::M),
+    ("N", Key::N),
+    ("O", Key::O),
+    ("P", Key::P),
+    ("Q", Key::Q),
+    ("R", Key::R),
+    ("S", Key::S),
+    ("T", Key::T),
+    ("U", Key::U),
+    ("V", Key::V),
+    ("W", Key::W),
+    ("X", Key::X),
+    ("Y", Key::Y),
+    ("Z", Key::Z),
+    ("0", Key::D0),
+    ("1", Key::D1),
+    ("2", Key::D2),
+    ("3", Key::D3),
+    ("4", Key::D4),
+    ("5", Key::D5),
+    ("6", Key::D6),
+    ("7", Key::D7),
+    ("8", Key::D8),
+    ("9", Key::D9),
+    ("F1", Key::F1),
+    ("F2", Key::F2),
+    ("F3", Key::F3),
+    ("F4", Key::F4),
+    ("F5", Key::F5),
+    ("F6", Key::F6),
+    ("F7", Key::F7),
+    ("F8", Key::F8),
+    ("F9", Key::F9),
+    ("F10", Key::F10),
+    ("F11", Key::F11),
+    ("F12", Key::F12),
+    ("Up", Key::Up),
+    ("Down", Key::Down),
+    ("Left", Key::Left),
+    ("Right", Key::Right),
+    ("Space", Key::Space),
+    ("Tab", Key::Tab),
+    ("Return", Key::Return),
+    ("Escape", Key::Escape),
+    ("Backspace", Key::Backspace),
+    ("Backquote", Key::Backquote),
+    ("LShift", Key::LShift),
+    ("RShift", Key::RShift),
+    ("LCtrl", Key::LCtrl),
+    ("RCtrl", Key::RCtrl),
+    ("LAlt", Key::LAlt),
+    ("RAlt", Key::RAlt),
+];
+
+/// Parses a key name as used in the client config, e.g. `"Q"`, `"F9"` or `"Space"`.
+pub fn key_from_name(name: &str) -> Option<Key> {
+    NAMED_KEYS
+        .iter()
+        .find(|&&(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|&(_, key)| key)
+}
+
+pub fn key_name(key: Key) -> String {
+    NAMED_KEYS
+        .iter()
+        .find(|&&(_, k)| k == key)
+        .map(|&(n, _)| n.to_string())
+        .unwrap_or_else(|| format!("{:?}", key))
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time;
+
+use common;
+use super::menu::ListedServer;
+
+/// How long to wait for replies to each query.
+const REPLY_WAIT_MILLIS: u64 = 1000;
+const REFRESH_SECS: u64 = 3;
+
+/// Looks for servers on the local network in the background until dropped.
+pub struct LanBrowser {
+    servers: Arc<Mutex<Vec<ListedServer>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LanBrowser {
+    pub fn start() -> Self {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        {
+            let servers = servers.clone();
+            let stop = stop.clone();
+            thread::spawn(move || while !stop.load(Ordering::SeqCst) {
+                let wait = time::Duration::from_millis(REPLY_WAIT_MILLIS);
+                match common::discover(wait) {
+                    Ok(found) => {
+                        *servers.lock().unwrap() = found
+                            .into_iter()
+                            .map(|(addr, info)| {
+                                let mut description = format!(
+                                    "{} - {:?} {:?}, {} players",
+                                    info.name,
+                                    info.map,
+                                    info.game_mode,
+                                    info.players
+                                );
+                                if info.version != common::VERSION {
+                                    description.push_str(&format!(" (version {})", info.version));
+                                }
+                                ListedServer {
+                                    address: addr.to_string(),
+                                    description,
+                                }
+                            })
+                            .collect();
+                    }
+                    Err(err) => {
+                        println!("LAN discovery failed: {}", err);
+                        return;
+                    }
+                }
+                thread::sleep(time::Duration::from_secs(REFRESH_SECS));
+            });
+        }
+
+        LanBrowser { servers, stop }
+    }
+
+    pub fn servers(&self) -> Vec<ListedServer> {
+        self.servers.lock().unwrap().clone()
+    }
+}
+
+impl Drop for LanBrowser {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
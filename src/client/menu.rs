@@ -0,0 +1,533 @@
+use piston_window::{self, Context, G2d, Key, Transformed};
+use piston_window::character::CharacterCache;
+
+use common::{MatchID, Team};
+use super::render::Fonts;
+
+const MAX_RECENT_SERVERS: usize = 8;
+
+const WIDTH: f64 = 480.0;
+const TOP: f64 = 100.0;
+const ROW_HEIGHT: f64 = 26.0;
+const HEADER_HEIGHT: f64 = 34.0;
+const FIELD_X: f64 = 140.0;
+const TEXT_COLOUR: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
+const FADED_COLOUR: [f32; 4] = [0.4, 0.4, 0.4, 1.0];
+const SELECTED_COLOUR: [f32; 4] = [0.2, 0.4, 0.9, 1.0];
+const ERROR_COLOUR: [f32; 4] = [0.8, 0.1, 0.1, 1.0];
+
+/// What was last typed into the main menu, kept in the client config.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MenuSettings {
+    pub name: String,
+    /// `host` or `host:port`.
+    pub server: String,
+    /// Empty for whichever team has room.
+    pub team: String,
+    /// Empty to join the matchmaking queue.
+    pub match_id: String,
+    /// Servers that were connected to, most recent first.
+    pub recent_servers: Vec<String>,
+    /// Connect over UDP instead of TCP.
+    pub udp: bool,
+    /// For signing in to a registered name. Empty to play as a guest.
+    pub token: String,
+    /// The server's certificate, to connect over TLS and trust only a server
+    /// presenting it. Empty to connect unencrypted.
+    pub tls_cert: String,
+}
+
+impl Default for MenuSettings {
+    fn default() -> Self {
+        MenuSettings {
+            name: String::new(),
+            server: "127.0.0.1".into(),
+            team: String::new(),
+            match_id: "0".into(),
+            recent_servers: Vec::new(),
+            udp: false,
+            token: String::new(),
+            tls_cert: String::new(),
+        }
+    }
+}
+
+impl MenuSettings {
+    pub fn remember_server(&mut self, address: &str) {
+        self.recent_servers.retain(|s| s != address);
+        self.recent_servers.insert(0, address.into());
+        self.recent_servers.truncate(MAX_RECENT_SERVERS);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectRequest {
+    pub address: String,
+    pub name: String,
+    pub team: Option<Team>,
+    pub match_id: Option<MatchID>,
+    pub udp: bool,
+    pub token: Option<String>,
+    /// See `MenuSettings::tls_cert`.
+    pub tls_cert: Option<String>,
+}
+
+impl ConnectRequest {
+    pub fn from_settings(settings: &MenuSettings) -> Result<Self, String> {
+        let name = settings.name.trim();
+        if name.is_empty() {
+            return Err("Enter a name.".into());
+        }
+
+        let address = settings.server.trim();
+        if address.is_empty() {
+            return Err("Enter a server address.".into());
+        }
+
+        let team = match settings.team.trim() {
+            "" => None,
+            team => {
+                match team.parse() {
+                    Ok(team) => Some(Team(team)),
+                    Err(_) => return Err(format!("{:?} isn't a team number.", team)),
+                }
+            }
+        };
+
+        let match_id = match settings.match_id.trim() {
+            "" => None,
+            match_id => {
+                match match_id.parse() {
+                    Ok(match_id) => Some(MatchID(match_id)),
+                    Err(_) => return Err(format!("{:?} isn't a match number.", match_id)),
+                }
+            }
+        };
+
+        Ok(ConnectRequest {
+            address: address.into(),
+            name: name.into(),
+            team,
+            match_id,
+            udp: settings.udp,
+            token: match settings.token.trim() {
+                "" => None,
+                token => Some(token.into()),
+            },
+            tls_cert: match settings.tls_cert.trim() {
+                "" => None,
+                path => Some(path.into()),
+            },
+        })
+    }
+}
+
+/// A server shown in the LAN list.
+#[derive(Clone, Debug)]
+pub struct ListedServer {
+    pub address: String,
+    pub description: String,
+}
+
+pub enum MenuAction {
+    Nothing,
+    Connect(ConnectRequest),
+    Quit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Name,
+    Server,
+    Team,
+    Match,
+}
+
+const FIELDS: &[Field] = &[Field::Name, Field::Server, Field::Team, Field::Match];
+
+impl Field {
+    fn label(&self) -> &'static str {
+        match *self {
+            Field::Name => "Name",
+            Field::Server => "Server",
+            Field::Team => "Team",
+            Field::Match => "Match",
+        }
+    }
+
+    fn hint(&self) -> &'static str {
+        match *self {
+            Field::Name | Field::Server => "",
+            Field::Team => "any",
+            Field::Match => "matchmaking queue",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Row {
+    Field(Field),
+    Connect,
+    Recent(usize),
+    Lan(usize),
+    Quit,
+}
+
+/// The screen shown before joining a match.
+pub struct Menu {
+    settings: MenuSettings,
+    lan_servers: Vec<ListedServer>,
+    selected: Row,
+    /// Why the last connect attempt wasn't made.
+    status: Option<String>,
+    mouse: (f64, f64),
+}
+
+impl Menu {
+    pub fn new(settings: MenuSettings) -> Self {
+        let selected = if settings.name.is_empty() {
+            Row::Field(Field::Name)
+        } else {
+            Row::Connect
+        };
+
+        Menu {
+            settings,
+            lan_servers: Vec::new(),
+            selected,
+            status: None,
+            mouse: (0.0, 0.0),
+        }
+    }
+
+    pub fn settings(&self) -> &MenuSettings {
+        &self.settings
+    }
+
+    pub fn set_lan_servers(&mut self, servers: Vec<ListedServer>) {
+        self.lan_servers = servers;
+        if let Row::Lan(i) = self.selected {
+            if i >= self.lan_servers.len() {
+                self.selected = Row::Connect;
+            }
+        }
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        let mut rows: Vec<Row> = FIELDS.iter().map(|&f| Row::Field(f)).collect();
+        rows.push(Row::Connect);
+        rows.extend((0..self.settings.recent_servers.len()).map(Row::Recent));
+        rows.extend((0..self.lan_servers.len()).map(Row::Lan));
+        rows.push(Row::Quit);
+        rows
+    }
+
+    /// Each row and the y of its baseline, with room left for section headers.
+    fn layout(&self) -> Vec<(Row, f64)> {
+        let mut y = TOP + HEADER_HEIGHT;
+        let mut layout = Vec::new();
+        let mut last = None;
+
+        for row in self.rows() {
+            let section = match row {
+                Row::Recent(_) => Some(1),
+                Row::Lan(_) => Some(2),
+                Row::Quit => Some(3),
+                _ => None,
+            };
+            if section.is_some() && section != last {
+                y += HEADER_HEIGHT;
+            }
+            last = section;
+
+            y += ROW_HEIGHT;
+            layout.push((row, y));
+        }
+
+        layout
+    }
+
+    fn field_mut(&mut self, field: Field) -> &mut String {
+        match field {
+            Field::Name => &mut self.settings.name,
+            Field::Server => &mut self.settings.server,
+            Field::Team => &mut self.settings.team,
+            Field::Match => &mut self.settings.match_id,
+        }
+    }
+
+    fn field(&self, field: Field) -> &str {
+        match field {
+            Field::Name => &self.settings.name,
+            Field::Server => &self.settings.server,
+            Field::Team => &self.settings.team,
+            Field::Match => &self.settings.match_id,
+        }
+    }
+
+    pub fn handle_text(&mut self, text: &str) {
+        if let Row::Field(field) = self.selected {
+            self.field_mut(field)
+                .extend(text.chars().filter(|c| !c.is_control()));
+        }
+    }
+
+    pub fn handle_key(&mut self, key: Key) -> MenuAction {
+        match key {
+            Key::Up => self.move_selection(-1),
+            Key::Down | Key::Tab => self.move_selection(1),
+            Key::Backspace => {
+                if let Row::Field(field) = self.selected {
+                    self.field_mut(field).pop();
+                }
+            }
+            Key::Delete => {
+                if let Row::Recent(i) = self.selected {
+                    self.settings.recent_servers.remove(i);
+                    if self.settings.recent_servers.is_empty() {
+                        self.selected = Row::Connect;
+                    } else {
+                        self.selected = Row::Recent(i.min(self.settings.recent_servers.len() - 1));
+                    }
+                }
+            }
+            Key::Return | Key::NumPadEnter => {
+                let row = self.selected;
+                return self.activate(row);
+            }
+            _ => {}
+        }
+        MenuAction::Nothing
+    }
+
+    pub fn handle_mouse_move(&mut self, x: f64, y: f64) {
+        self.mouse = (x, y);
+    }
+
+    /// `width` is the window's. Fields are focused by clicking them, and
+    /// everything else is activated.
+    pub fn handle_click(&mut self, width: f64) -> MenuAction {
+        let (mx, my) = self.mouse;
+        let left = (width - WIDTH) / 2.0;
+        if mx < left || mx > left + WIDTH {
+            return MenuAction::Nothing;
+        }
+
+        let hit = self.layout()
+            .into_iter()
+            .find(|&(_, y)| my > y - ROW_HEIGHT + 6.0 && my <= y + 6.0);
+
+        match hit {
+            Some((row @ Row::Field(_), _)) => {
+                self.selected = row;
+                MenuAction::Nothing
+            }
+            Some((row, _)) => {
+                self.selected = row;
+                self.activate(row)
+            }
+            None => MenuAction::Nothing,
+        }
+    }
+
+    fn move_selection(&mut self, by: isize) {
+        let rows = self.rows();
+        let current = rows.iter().position(|&r| r == self.selected).unwrap_or(0);
+        let next = (current as isize + by + rows.len() as isize) as usize % rows.len();
+        self.selected = rows[next];
+    }
+
+    fn activate(&mut self, row: Row) -> MenuAction {
+        match row {
+            Row::Field(_) | Row::Connect => {}
+            Row::Recent(i) => self.settings.server = self.settings.recent_servers[i].clone(),
+            Row::Lan(i) => self.settings.server = self.lan_servers[i].address.clone(),
+            Row::Quit => return MenuAction::Quit,
+        }
+
+        match ConnectRequest::from_settings(&self.settings) {
+            Ok(request) => {
+                self.status = None;
+                MenuAction::Connect(request)
+            }
+            Err(err) => {
+                self.status = Some(err);
+                MenuAction::Nothing
+            }
+        }
+    }
+
+    pub fn render(&self, c: Context, g: &mut G2d, fonts: &mut Fonts, width: f64) {
+        let left = (width - WIDTH) / 2.0;
+
+        piston_window::text(
+            TEXT_COLOUR,
+            32,
+            "moba",
+            &mut fonts.bold,
+            c.transform.trans(left, TOP),
+            g,
+        );
+
+        let mut last_header = None;
+        for (row, y) in self.layout() {
+            let header = match row {
+                Row::Recent(_) => Some("Recent servers"),
+                Row::Lan(_) => Some("On your network"),
+                _ => None,
+            };
+            if header.is_some() && header != last_header {
+                piston_window::text(
+                    FADED_COLOUR,
+                    14,
+                    header.unwrap(),
+                    &mut fonts.bold,
+                    c.transform.trans(left, y - ROW_HEIGHT - 8.0),
+                    g,
+                );
+            }
+            last_header = header;
+
+            let selected = row == self.selected;
+            let colour = if selected {
+                SELECTED_COLOUR
+            } else {
+                TEXT_COLOUR
+            };
+
+            match row {
+                Row::Field(field) => {
+                    piston_window::text(
+                        colour,
+                        16,
+                        field.label(),
+                        &mut fonts.regular,
+                        c.transform.trans(left, y),
+                        g,
+                    );
+
+                    let value = self.field(field);
+                    let (shown, shown_colour) = match (value.is_empty(), selected) {
+                        (true, false) => (field.hint().to_string(), FADED_COLOUR),
+                        (_, true) => (format!("{}_", value), TEXT_COLOUR),
+                        (false, false) => (value.to_string(), TEXT_COLOUR),
+                    };
+                    piston_window::rectangle(
+                        colour,
+                        [left + FIELD_X - 4.0, y + 5.0, WIDTH - FIELD_X, 1.0],
+                        c.transform,
+                        g,
+                    );
+                    piston_window::text(
+                        shown_colour,
+                        16,
+                        &shown,
+                        &mut fonts.regular,
+                        c.transform.trans(left + FIELD_X, y),
+                        g,
+                    );
+                }
+                Row::Connect | Row::Quit => {
+                    let label = if row == Row::Connect {
+                        "Connect"
+                    } else {
+                        "Quit"
+                    };
+                    let label = if selected {
+                        format!("> {}", label)
+                    } else {
+                        label.to_string()
+                    };
+                    piston_window::text(
+                        colour,
+                        18,
+                        &label,
+                        &mut fonts.bold,
+                        c.transform.trans(left, y),
+                        g,
+                    );
+                }
+                Row::Recent(i) => {
+                    piston_window::text(
+                        colour,
+                        16,
+                        &self.settings.recent_servers[i],
+                        &mut fonts.regular,
+                        c.transform.trans(left + 10.0, y),
+                        g,
+                    );
+                }
+                Row::Lan(i) => {
+                    let server = &self.lan_servers[i];
+                    piston_window::text(
+                        colour,
+                        16,
+                        &format!("{}  {}", server.address, server.description),
+                        &mut fonts.regular,
+                        c.transform.trans(left + 10.0, y),
+                        g,
+                    );
+                }
+            }
+
+            if row == Row::Connect {
+                if let Some(ref status) = self.status {
+                    piston_window::text(
+                        ERROR_COLOUR,
+                        14,
+                        status,
+                        &mut fonts.regular,
+                        c.transform.trans(left + FIELD_X, y),
+                        g,
+                    );
+                }
+            }
+        }
+
+        let footer_y = self.layout().last().map_or(TOP, |&(_, y)| y) + HEADER_HEIGHT;
+        piston_window::text(
+            FADED_COLOUR,
+            12,
+            "Up/Down: select   Enter: connect   Delete: forget a recent server",
+            &mut fonts.regular,
+            c.transform.trans(left, footer_y),
+            g,
+        );
+    }
+}
+
+/// A full screen message, e.g. while connecting or after being kicked.
+pub fn render_message(
+    c: Context,
+    g: &mut G2d,
+    fonts: &mut Fonts,
+    title: &str,
+    message: &str,
+    hint: &str,
+    width: f64,
+    height: f64,
+) {
+    let lines = [(title, 28, TEXT_COLOUR), (message, 16, TEXT_COLOUR), (hint, 12, FADED_COLOUR)];
+
+    for (i, &(text, size, colour)) in lines.iter().enumerate() {
+        let (font, text_width) = if i == 0 {
+            let w = fonts.bold.width(size, text);
+            (&mut fonts.bold, w)
+        } else {
+            let w = fonts.regular.width(size, text);
+            (&mut fonts.regular, w)
+        };
+        piston_window::text(
+            colour,
+            size,
+            text,
+            font,
+            c.transform.trans(
+                (width - text_width) / 2.0,
+                height / 3.0 + 40.0 * i as f64,
+            ),
+            g,
+        );
+    }
+}
@@ -1,11 +1,10 @@
 use std;
-use std::net::{self, TcpStream};
-use std::io;
 use std::thread;
 use std::time;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use piston_window::{self, Transformed, Window, Input, Button, MouseButton, Motion, Key, EventLoop};
+use piston_window::{self, Transformed, Window, Input, Button, Motion, Key, EventLoop};
+use piston_window::character::CharacterCache;
 #[cfg(feature = "sdl2")]
 use sdl2_window::Sdl2Window;
 
@@ -14,10 +13,43 @@ use common::*;
 mod render;
 use self::render::particle;
 
+mod chat;
+use self::chat::{ChatBox, ChatLine, ChatAction};
+
+mod keys;
+
+mod input;
+use self::input::{Action, Bindings, CastMode, Modifiers};
+
+mod rebind;
+use self::rebind::{RebindScreen, RebindResult};
+
+mod config;
+pub use self::config::*;
+
+mod camera;
+use self::camera::Camera;
+
+mod hud;
+use self::hud::Hud;
+
+mod menu;
+use self::menu::{Menu, MenuAction};
+pub use self::menu::{ConnectRequest, MenuSettings};
+
+mod connect;
+use self::connect::{Connecting, Joined};
+
+mod lan;
+use self::lan::LanBrowser;
+
+mod audio;
+use self::audio::{Announcement, Audio};
+
 #[cfg(not(feature = "sdl2"))]
 fn new_window() -> piston_window::PistonWindow {
     piston_window::WindowSettings::new("moba", [1280, 720])
-        .exit_on_esc(true)
+        .exit_on_esc(false)
         .samples(1)
         .vsync(true)
         .build()
@@ -27,21 +59,60 @@ fn new_window() -> piston_window::PistonWindow {
 #[cfg(feature = "sdl2")]
 fn new_window() -> piston_window::PistonWindow<Sdl2Window> {
     piston_window::WindowSettings::new("moba", [1280, 720])
-        .exit_on_esc(true)
+        .exit_on_esc(false)
         .samples(1)
         .vsync(true)
         .build()
         .unwrap()
 }
 
+/// Something that's cast at the cursor.
+#[derive(Clone, Copy, PartialEq)]
+enum Aim {
+    Ability(u32),
+    AttackMove,
+}
+
+impl Aim {
+    fn for_action(action: Action) -> Option<Aim> {
+        match action {
+            Action::AttackMove => Some(Aim::AttackMove),
+            _ => action.ability_id().map(Aim::Ability),
+        }
+    }
+}
+
 pub struct Client {
-    name: String,
-    team: Option<Team>,
     game: Game,
     viewport: render::Viewport,
+    camera: Camera,
+    minimap: render::Minimap,
+    graphics: render::GraphicsSettings,
+    sprites: Option<render::SpriteAtlas>,
+    animator: render::Animator,
+    effects: render::EffectLibrary,
+    audio: Audio,
+    hud: Hud,
+    screen_width: f64,
+    screen_height: f64,
     particles: Vec<Box<particle::Particle>>,
+    chat_box: ChatBox,
+    /// Shown across the screen while the game is paused.
+    pause_banner: Arc<Mutex<Option<String>>>,
+    /// Every player's connection, as the server sees it.
+    net_stats: Arc<Mutex<HashMap<EntityID, NetStats>>>,
+    config: ClientConfig,
+    bindings: Bindings,
+    modifiers: Modifiers,
+    rebind_screen: RebindScreen,
+    /// What's being aimed with smart cast.
+    targeting: Option<Aim>,
     id: Option<EntityID>,
-    stream: Option<Stream>,
+    stream: Option<ClientStream>,
+    /// Set when a message couldn't be sent, so the match ends on the error screen.
+    send_failure: Option<String>,
+    /// The protocol capabilities both we and the server support.
+    capabilities: Vec<String>,
     game_mouse_x: f64,
     game_mouse_y: f64,
     screen_mouse_x: f64,
@@ -51,16 +122,35 @@ pub struct Client {
 }
 
 impl Client {
-    pub fn new(name: String, team: Option<Team>) -> Self {
-        Client {
-            name,
-            team,
+    fn new(config: ClientConfig) -> Self {
+        let bindings = Bindings::new(&config.input);
 
+        let mut client = Client {
             game: Game::new(),
             viewport: render::Viewport::new(-500.0, -500.0, 1.0),
+            camera: Camera::new(config.camera.clone()),
+            minimap: render::Minimap::new(),
+            graphics: config.graphics.clone(),
+            sprites: None,
+            animator: render::Animator::new(),
+            effects: render::EffectLibrary::load_or_empty(render::EFFECTS_PATH),
+            audio: Audio::new(config.audio.clone()),
+            hud: Hud::new(),
+            screen_width: 0.0,
+            screen_height: 0.0,
             particles: Vec::new(),
+            chat_box: ChatBox::new(),
+            pause_banner: Arc::new(Mutex::new(None)),
+            net_stats: Arc::new(Mutex::new(HashMap::new())),
+            config,
+            bindings,
+            modifiers: Modifiers::default(),
+            rebind_screen: RebindScreen::new(),
+            targeting: None,
             id: None,
             stream: None,
+            send_failure: None,
+            capabilities: Vec::new(),
             game_mouse_x: 0.0,
             game_mouse_y: 0.0,
 
@@ -69,77 +159,176 @@ impl Client {
 
             selected_entity_id: None,
             hovered_entity_id: None,
-        }
+        };
+        client.update_ability_labels();
+        client
+    }
+
+    fn update_ability_labels(&mut self) {
+        let labels = [
+            Action::Ability1,
+            Action::Ability2,
+            Action::Ability3,
+            Action::Ability4,
+        ].iter()
+            .map(|&a| {
+                self.bindings
+                    .binding(a)
+                    .map(|b| b.to_string())
+                    .unwrap_or_default()
+            })
+            .collect();
+        self.hud.set_ability_labels(labels);
     }
 
     fn run_command(&mut self, command: Command) {
         self.game.run_command(command.clone(), self.id.unwrap());
-        self.stream
-            .as_mut()
-            .unwrap()
-            .write_message(Message::Command(command))
-            .unwrap();
+        self.send(ClientMessage::Command(command));
+    }
+
+    /// Once the server has gone, the first failure is kept to show on the error screen.
+    fn send(&mut self, message: ClientMessage) {
+        let result = self.stream.as_mut().unwrap().write_message(message);
+        if let Err(err) = result {
+            if self.send_failure.is_none() {
+                self.send_failure = Some(format!("Lost connection to the server: {}", err));
+            }
+        }
     }
 
-    fn run(
+    fn run<W: piston_window::OpenGLWindow>(
         &mut self,
+        window: &mut piston_window::PistonWindow<W>,
+        fonts: &mut render::Fonts,
         current_ping: Arc<Mutex<u64>>,
         events: Arc<Mutex<Vec<Event>>>,
-        player_entity_id: Arc<Mutex<Option<EntityID>>>,
-    ) -> io::Result<()> {
-
-        let mut window = new_window();
-        window.set_ups(60);
+        chat_lines: Arc<Mutex<Vec<ChatLine>>>,
+        disconnected: Arc<Mutex<Option<String>>>,
+    ) -> Result<(), String> {
+        self.sprites = match render::SpriteAtlas::load(&mut window.factory, render::ATLAS_PATH) {
+            Ok(sprites) => Some(sprites),
+            Err(err) => {
+                println!("Couldn't load sprites, drawing circles instead: {}", err);
+                None
+            }
+        };
+        self.hud.load(&mut window.factory);
 
-        let mut fonts = render::Fonts::new(window.factory.clone());
+        self.audio.start_music();
+        self.audio.announce(Announcement::MatchStart);
 
         let mut last_render_time = time::Instant::now();
+        let mut was_paused = false;
 
         while let Some(e) = window.next() {
             let piston_window::Size { width, height } = window.draw_size();
+            self.screen_width = width as f64;
+            self.screen_height = height as f64;
 
-            if self.id.is_none() {
-                if let Some(id) = *player_entity_id.lock().unwrap() {
-                    self.id = Some(id);
-                }
+            // the reader knows why, if it's noticed yet
+            if let Some(reason) = disconnected.lock().unwrap().take() {
+                return Err(reason);
+            }
+            if let Some(reason) = self.send_failure.take() {
+                return Err(reason);
+            }
+
+            let paused = self.pause_banner.lock().unwrap().is_some();
+            if paused != was_paused {
+                self.audio.announce(if paused {
+                    Announcement::Paused
+                } else {
+                    Announcement::Resumed
+                });
+                was_paused = paused;
             }
 
             {
                 let mut events_handle = events.lock().unwrap();
                 for ev in events_handle.drain(..) {
+                    self.animate_event(&ev);
+                    self.spawn_effects(&ev);
+                    self.play_sounds(&ev);
                     self.game.run_event(ev);
                 }
             }
 
+            for line in chat_lines.lock().unwrap().drain(..) {
+                self.chat_box.push(line);
+            }
+
             match e {
                 Input::Render(_) => {
                     self.render(
-                        &mut window,
+                        window,
                         &current_ping,
                         width,
                         &mut last_render_time,
                         e,
-                        &mut fonts,
+                        fonts,
+                        height,
                     )
                 }
+                Input::Update(args) => {
+                    // the server's cooldowns don't count down while paused either
+                    if !paused {
+                        self.game.count_down_cooldowns(args.dt);
+                    }
+                    self.animator.update(args.dt);
+                    self.update_camera(args.dt, width, height);
+                }
+                Input::Text(text) => self.chat_box.handle_text(&text),
+                Input::Cursor(inside) => self.camera.handle_cursor_in_window(inside),
                 Input::Move(motion) => {
                     match motion {
                         Motion::MouseCursor(x, y) => self.handle_mouse_motion(x, y),
+                        Motion::MouseScroll(_, dy) => {
+                            let (x, y) = (self.screen_mouse_x, self.screen_mouse_y);
+                            self.camera.handle_scroll(&mut self.viewport, dy, x, y);
+                            self.handle_mouse_motion(x, y);
+                        }
                         _ => {}
                     }
                 }
                 Input::Press(button) => {
+                    if let Button::Keyboard(key) = button {
+                        self.modifiers.handle_key(key, true);
+                    }
+
                     match button {
-                        Button::Mouse(mouse_button) => self.handle_mouse_press(mouse_button),
-                        Button::Keyboard(key) => self.handle_keyboard_press(key),
-                        _ => {}
+                        Button::Keyboard(key) if self.chat_box.has_focus() => {
+                            self.handle_chat_key(key)
+                        }
+                        _ if self.rebind_screen.is_open() => self.handle_rebind_press(button),
+                        Button::Keyboard(Key::Escape) => {
+                            // Escape isn't rebindable, so there's always a way out
+                            if self.targeting.take().is_none() {
+                                break;
+                            }
+                        }
+                        _ => self.handle_press(button),
+                    }
+                }
+                Input::Release(button) => {
+                    if let Button::Keyboard(key) = button {
+                        self.modifiers.handle_key(key, false);
+                    }
+                    if let Button::Mouse(mouse_button) = button {
+                        let (x, y) = (self.screen_mouse_x, self.screen_mouse_y);
+                        self.camera.handle_mouse_button(mouse_button, false, x, y);
+                    }
+
+                    for action in self.bindings.actions_for_release(button) {
+                        self.handle_action(action, false);
                     }
                 }
                 _ => {}
             };
         }
 
-        self.stream.as_mut().unwrap().write_message(Message::Quit)
+        // we're leaving, so it doesn't matter if the server's already gone
+        let _ = self.stream.as_mut().unwrap().write_message(ClientMessage::Quit);
+        Ok(())
     }
 
     fn render<W: piston_window::OpenGLWindow>(
@@ -150,6 +339,7 @@ impl Client {
         last_render_time: &mut time::Instant,
         e: Input,
         fonts: &mut render::Fonts,
+        height: u32,
     ) {
         // HACK
         if let Some(id) = self.id {
@@ -157,8 +347,53 @@ impl Client {
                 .with_component_mut::<Renderable, _, _>(id, |r| r.colour = [0.0, 0.0, 1.0, 1.0]);
         }
 
+        let targeting_from = match (self.targeting, self.id) {
+            (Some(_), Some(id)) => self.game.clone_component::<Position>(id).map(|p| p.point),
+            _ => None,
+        };
+
+        let path = match self.id {
+            Some(id) => {
+                let start = self.game.clone_component::<Position>(id).map(|p| p.point);
+                let unit = self.game.clone_component::<Unit>(id);
+                match (start, unit) {
+                    (Some(start), Some(unit)) => {
+                        let mut path = vec![start];
+                        path.extend(unit.orders.iter().filter_map(|o| o.waypoint()));
+                        path
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            None => Vec::new(),
+        };
+
         let dur_since_last_render = last_render_time.elapsed();
         *last_render_time = time::Instant::now();
+        let dt = dur_since_last_render.as_secs() as f64 +
+            dur_since_last_render.subsec_nanos() as f64 / 1000000000.0;
+
+        for p in &mut self.particles {
+            if let Some(id) = p.attached_to() {
+                let position = self.game.clone_component::<Position>(id).map(|p| p.point);
+                p.follow(position);
+            }
+        }
+
+        // back to front
+        let mut bodies = Vec::new();
+        for id in self.game.entity_ids_cloned() {
+            if let Some(position) = self.game.clone_component::<Position>(id) {
+                let alive = self.game
+                    .clone_component::<Hitpoints>(id)
+                    .map_or(true, |hp| hp.current() > 0);
+                self.animator.observe(id, position.point, alive, dt);
+            }
+            if let Some(kind) = self.game.clone_component::<EntityKind>(id) {
+                bodies.push((render::layer_of(kind), id));
+            }
+        }
+        bodies.sort_by_key(|&(layer, _)| layer);
 
         // self.game.clone();
         // self.game.clone();
@@ -171,16 +406,68 @@ impl Client {
             //                          c.transform,
             //
 
-            {
-                let viewport = self.viewport;
-                for e in self.game.entity_ids_cloned() {
-                    let e = self.game.get_entity(e).unwrap();
-                    render::render(viewport, c, g, fonts, e, self.game.mut_world());
+            let viewport = self.viewport;
+            let sprites = if self.graphics.debug_circles {
+                None
+            } else {
+                self.sprites.as_ref()
+            };
+
+            // Layer::Ground
+            render::render_path(viewport, c, g, &path);
+
+            for p in &mut self.particles {
+                if p.layer() == render::Layer::Ground {
+                    (&mut **p).render(viewport, c, g, fonts)
                 }
             }
 
+            render::render_corpses(viewport, c, g, sprites, &self.animator);
+
+            // Layer::Units and Layer::Projectiles
+            for &(_, id) in &bodies {
+                let e = self.game.get_entity(id).unwrap();
+                render::render_body(viewport, c, g, e, self.game.mut_world(), sprites, &self.animator);
+            }
+
+            // Layer::Ui
             for p in &mut self.particles {
-                (&mut **p).render(self.viewport, c, g)
+                if p.layer() != render::Layer::Ground {
+                    (&mut **p).render(viewport, c, g, fonts)
+                }
+            }
+
+            for &(_, id) in &bodies {
+                let e = self.game.get_entity(id).unwrap();
+                render::render_overlay(viewport, c, g, fonts, e, self.game.mut_world());
+            }
+
+            if let Some(from) = targeting_from {
+                let viewport = self.viewport;
+                let (x1, y1) = (viewport.x_game_to_screen(from.x), viewport.y_game_to_screen(from.y));
+                let (x2, y2) = (self.screen_mouse_x, self.screen_mouse_y);
+                let colour = [1.0, 0.5, 0.0, 0.8];
+                piston_window::line(colour, 2.0, [x1, y1, x2, y2], c.transform, g);
+                piston_window::Ellipse::new_border(colour, 2.0).draw(
+                    [x2 - 15.0, y2 - 15.0, 30.0, 30.0],
+                    &c.draw_state,
+                    c.transform,
+                    g,
+                );
+            }
+
+            {
+                let map = self.game.map();
+                self.minimap.render(
+                    self.viewport,
+                    c,
+                    g,
+                    self.game.mut_world(),
+                    self.id,
+                    width as f64,
+                    height as f64,
+                    map,
+                );
             }
 
             piston_window::text(
@@ -195,67 +482,424 @@ impl Client {
                 g,
             );
 
-            piston_window::text(
-                [0.0, 0.0, 0.0, 1.0],
-                14,
-                &format!(
-                    "Selected: {:?}    Hovered: {:?}",
-                    self.selected_entity_id,
-                    self.hovered_entity_id
-                ),
-                &mut fonts.regular,
-                c.transform.trans(5.0, 15.0),
+            self.hud.render(
+                c,
                 g,
+                &mut self.game,
+                &self.net_stats.lock().unwrap(),
+                self.id,
+                self.selected_entity_id,
+                width as f64,
+                height as f64,
             );
 
+            self.chat_box.render(c, g, fonts, height);
 
+            if self.rebind_screen.is_open() {
+                self.rebind_screen.render(
+                    c,
+                    g,
+                    fonts,
+                    &self.bindings,
+                    width as f64,
+                    height as f64,
+                );
+            }
 
-            for p in &mut self.particles {
-                p.update(
-                    dur_since_last_render.as_secs() as f64 +
-                        dur_since_last_render.subsec_nanos() as f64 / 1000000000.0,
+            if let Some(ref banner) = *self.pause_banner.lock().unwrap() {
+                let size = 32;
+                let banner_width = fonts.bold.width(size, banner);
+                piston_window::text(
+                    [0.0, 0.0, 0.0, 1.0],
+                    size,
+                    banner,
+                    &mut fonts.bold,
+                    c.transform.trans(
+                        (width as f64 - banner_width) / 2.0,
+                        height as f64 / 3.0,
+                    ),
+                    g,
                 );
             }
 
+
+            for p in &mut self.particles {
+                p.update(dt);
+            }
+
             self.particles.retain(|p| !p.should_remove());
         });
     }
 
-    fn handle_mouse_press(&mut self, mouse_button: MouseButton) {
-        match mouse_button {
-            MouseButton::Left => {
-                self.selected_entity_id = self.entity_under_cursor();
+    fn handle_minimap_press(&mut self, action: Action) -> bool {
+        let (x, y) = (self.screen_mouse_x, self.screen_mouse_y);
+        let (width, height) = (self.screen_width, self.screen_height);
+        let map = self.game.map();
+
+        if !self.minimap.contains(x, y, width, height, map) {
+            return false;
+        }
+
+        let point = self.minimap.screen_to_game(x, y, width, height, map);
+        match action {
+            Action::Select => {
+                if self.camera.is_locked() {
+                    self.camera.toggle_lock();
+                }
+                self.viewport.centre_on(point, width, height);
+                self.viewport.clamp_to(map.bounds(), width, height);
             }
-            MouseButton::Right => {
-                let x = self.game_mouse_x;
-                let y = self.game_mouse_y;
-                if let Some(e) = self.targetable_entity_under_cursor() {
-                    self.run_command(Command::SetTarget(Target::Entity(e)));
-                } else {
-                    self.run_command(Command::SetTarget(Target::Position(Point::new(x, y))));
+            Action::MoveTo => {
+                self.issue(Command::SetTarget(Target::Position(point)));
+                self.particles
+                    .push(Box::new(particle::RightClick::new(point.x, point.y)))
+            }
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn handle_press(&mut self, button: Button) {
+        let action = match self.bindings.action_for_press(button, self.modifiers) {
+            Some(action) => action,
+            None => {
+                if let Button::Mouse(mouse_button) = button {
+                    let (x, y) = (self.screen_mouse_x, self.screen_mouse_y);
+                    self.camera.handle_mouse_button(mouse_button, true, x, y);
+                }
+                return;
+            }
+        };
+
+        if let Button::Mouse(_) = button {
+            if self.targeting.is_none() && self.handle_minimap_press(action) {
+                return;
+            }
+        }
+
+        self.handle_action(action, true);
+    }
+
+    fn handle_rebind_press(&mut self, button: Button) {
+        match self.rebind_screen
+            .handle_press(button, self.modifiers, &mut self.bindings) {
+            RebindResult::Nothing => {}
+            RebindResult::Close => self.rebind_screen.close(),
+            RebindResult::Changed => {
+                self.update_ability_labels();
+                self.config.input = self.bindings.to_settings();
+                if let Err(err) = self.config.save(DEFAULT_CONFIG_PATH) {
+                    println!("Couldn't save {}: {}", DEFAULT_CONFIG_PATH, err);
+                }
+            }
+        }
+    }
+
+    fn handle_action(&mut self, action: Action, pressed: bool) {
+        if self.camera.handle_pan(action, pressed) {
+            return;
+        }
+
+        if !pressed {
+            match action {
+                Action::Scoreboard => self.hud.set_scoreboard_visible(false),
+                _ => {
+                    let aim = Aim::for_action(action);
+                    if aim.is_some() && self.targeting == aim {
+                        self.targeting = None;
+                        self.fire(aim.unwrap());
+                    }
+                }
+            }
+            return;
+        }
+
+        match action {
+            Action::Select => {
+                match self.targeting.take() {
+                    Some(aim) => self.fire(aim),
+                    None => self.selected_entity_id = self.entity_under_cursor(),
+                }
+            }
+            Action::MoveTo => {
+                // cancels aiming instead of moving
+                if self.targeting.take().is_none() {
+                    self.move_to_cursor();
+                }
+            }
+            Action::Stop => self.run_command(Command::Stop),
+            Action::HoldPosition => self.issue(Command::HoldPosition),
+            Action::AttackMove |
+            Action::Ability1 |
+            Action::Ability2 |
+            Action::Ability3 |
+            Action::Ability4 => {
+                let aim = Aim::for_action(action).unwrap();
+                match self.bindings.cast_mode {
+                    CastMode::Quick => self.fire(aim),
+                    CastMode::Smart => {
+                        let ready = match aim {
+                            Aim::Ability(ability_id) => self.ability_ready(ability_id),
+                            Aim::AttackMove => true,
+                        };
+                        if ready {
+                            self.targeting = Some(aim);
+                        }
+                    }
+                }
+            }
+            Action::CameraLock => self.camera.toggle_lock(),
+            Action::CameraCentre => self.camera.centre_on_hero(),
+            Action::Scoreboard => self.hud.set_scoreboard_visible(true),
+            Action::Pause => {
+                if !self.has_capability(CAPABILITY_PAUSE) {
+                    self.chat_box.push(ChatLine {
+                        user: "".into(),
+                        message: "This server doesn't support pausing.".into(),
+                        channel: ChatChannel::System,
+                    });
+                    return;
                 }
+                let message = if self.pause_banner.lock().unwrap().is_some() {
+                    ClientMessage::Unpause
+                } else {
+                    ClientMessage::Pause
+                };
+                self.send(message);
+            }
+            Action::ChatTeam => self.chat_box.focus(ChatChannel::Team),
+            Action::ChatAll => self.chat_box.focus(ChatChannel::All),
+            Action::Bindings => {
+                self.targeting = None;
+                self.rebind_screen.open();
+            }
+            Action::PanUp | Action::PanDown | Action::PanLeft | Action::PanRight => {}
+        }
+    }
+
+    fn fire(&mut self, aim: Aim) {
+        match aim {
+            Aim::Ability(ability_id) => self.use_ability(ability_id),
+            Aim::AttackMove => {
+                let point = Point::new(self.game_mouse_x, self.game_mouse_y);
+                self.issue(Command::AttackMove(point));
                 self.particles
-                    .push(Box::new(particle::RightClick::new(x, y)))
+                    .push(Box::new(particle::RightClick::new(point.x, point.y)))
             }
-            _ => {}
         }
     }
 
-    fn handle_keyboard_press(&mut self, key: Key) {
-        match key {
-            Key::Q => {
-                let command = Command::UseAbility {
-                    ability_id: 0,
-                    mouse_position: Some(Point::new(self.game_mouse_x, self.game_mouse_y)),
+    fn move_to_cursor(&mut self) {
+        let x = self.game_mouse_x;
+        let y = self.game_mouse_y;
+        if let Some(e) = self.targetable_entity_under_cursor() {
+            self.issue(Command::SetTarget(Target::Entity(e)));
+        } else {
+            self.issue(Command::SetTarget(Target::Position(Point::new(x, y))));
+        }
+        self.particles
+            .push(Box::new(particle::RightClick::new(x, y)))
+    }
+
+    fn handle_chat_key(&mut self, key: Key) {
+        if let ChatAction::Send { message, channel } = self.chat_box.handle_key(key) {
+            self.send(ClientMessage::SendChat { message, channel });
+        }
+    }
+
+    fn update_camera(&mut self, dt: f64, width: u32, height: u32) {
+        let hero = self.id.and_then(|id| self.game.clone_component::<Position>(id));
+        let map = self.game.map();
+
+        self.camera.update(
+            &mut self.viewport,
+            dt,
+            (width as f64, height as f64),
+            (self.screen_mouse_x, self.screen_mouse_y),
+            hero.map(|p| p.point),
+            map,
+        );
+
+        let centre = Point::new(
+            self.viewport.x_screen_to_game(width as f64 / 2.0),
+            self.viewport.y_screen_to_game(height as f64 / 2.0),
+        );
+        self.audio.set_listener(centre);
+
+        // the game position under the cursor may have changed
+        let (x, y) = (self.screen_mouse_x, self.screen_mouse_y);
+        self.handle_mouse_motion(x, y);
+    }
+
+    /// Whether our hero has the ability and it's off cooldown.
+    fn ability_ready(&mut self, ability_id: u32) -> bool {
+        let id = match self.id {
+            Some(id) => id,
+            None => return false,
+        };
+        self.game
+            .clone_component::<AbilityCooldowns>(id)
+            .map_or(false, |cooldowns| cooldowns.is_ready(ability_id))
+    }
+
+    fn use_ability(&mut self, ability_id: u32) {
+        let command = Command::UseAbility {
+            ability_id,
+            mouse_position: Some(Point::new(self.game_mouse_x, self.game_mouse_y)),
+        };
+
+        // queued abilities wait for their cooldown, which the server sends once it's started
+        if self.queueing() {
+            self.run_command(Command::Queue(Box::new(command)));
+            return;
+        }
+
+        // running it here starts the cooldown until the server confirms it
+        if self.ability_ready(ability_id) {
+            self.run_command(command);
+        }
+    }
+
+    /// Starts attack and death animations. Called before the event is run.
+    fn animate_event(&mut self, event: &Event) {
+        match *event {
+            Event::AddProjectile {
+                owner,
+                ref target,
+                ..
+            } => {
+                let towards = match *target {
+                    Target::Position(p) => Some(p),
+                    Target::Entity(id) => self.game.clone_component::<Position>(id).map(|p| p.point),
+                    Target::Nothing => None,
                 };
-                self.run_command(command);
+                let duration = self.sprite_name(owner).and_then(|sprite| {
+                    self.sprites
+                        .as_ref()
+                        .and_then(|s| s.duration(&sprite, render::Animation::Attack))
+                });
+                self.animator.attack(owner, towards, duration.unwrap_or(0.3));
+            }
+            Event::RemoveEntity(id) => {
+                let dead = self.game
+                    .clone_component::<Hitpoints>(id)
+                    .map_or(false, |hp| hp.current() == 0);
+                let sprite = self.sprite_name(id);
+                let renderable = self.game.clone_component::<Renderable>(id);
+
+                match (dead, sprite, renderable) {
+                    (true, Some(sprite), Some(r)) => {
+                        let duration = self.sprites
+                            .as_ref()
+                            .and_then(|s| s.duration(&sprite, render::Animation::Death))
+                            .unwrap_or(0.0);
+                        self.animator.died(id, sprite, r.radius, r.colour, duration);
+                    }
+                    _ => self.animator.remove(id),
+                }
             }
+            _ => {}
+        }
+    }
 
+    /// Spawns the effects that `effects.json` gives the event. Called before the event is run.
+    fn spawn_effects(&mut self, event: &Event) {
+        match *event {
+            Event::DamageEntity { id, damage } => {
+                if self.game.is_killing_blow(id, damage) {
+                    self.spawn_effect(render::Trigger::Death, id, true);
+                }
+                self.spawn_effect(render::Trigger::Damage, id, true);
+                self.spawn_combat_text(id, |point| render::CombatText::damage(point, damage));
+            }
+            Event::SetHitpoints { id, current } => {
+                // there's no heal event, so anything that raises hitpoints counts
+                let old = self.game.clone_component::<Hitpoints>(id).map(|hp| hp.current());
+                if let Some(old) = old {
+                    if current > old {
+                        self.spawn_combat_text(id, |point| {
+                            render::CombatText::heal(point, current - old)
+                        });
+                    }
+                }
+            }
+            Event::RemoveEntity(id) => {
+                // projectiles are only removed when they hit something
+                if self.game.has_component::<Projectile>(id) {
+                    self.spawn_effect(render::Trigger::ProjectileHit, id, false);
+                }
+            }
+            Event::AbilityUsed { id, .. } => {
+                self.spawn_effect(render::Trigger::AbilityCast, id, true);
+            }
             _ => {}
         }
     }
 
+    fn spawn_effect(&mut self, trigger: render::Trigger, id: EntityID, attach: bool) {
+        let point = match self.game.clone_component::<Position>(id) {
+            Some(position) => position.point,
+            None => return,
+        };
+        let attached_to = if attach { Some(id) } else { None };
+
+        if let Some(emitter) = self.effects.trigger(trigger, point, attached_to) {
+            self.particles.push(Box::new(emitter));
+        }
+    }
+
+    /// Plays sound effects and announcer lines. Called before the event is run.
+    fn play_sounds(&mut self, event: &Event) {
+        for cue in audio::cues_for(&mut self.game, self.id, event) {
+            self.audio.cue(cue);
+        }
+    }
+
+    /// Shows the text just above the unit.
+    fn spawn_combat_text<F>(&mut self, id: EntityID, make: F)
+    where
+        F: FnOnce(Point) -> render::CombatText,
+    {
+        let position = self.game.clone_component::<Position>(id);
+        let radius = self.game
+            .clone_component::<Renderable>(id)
+            .map_or(0.0, |r| r.radius);
+
+        if let Some(position) = position {
+            let point = Point::new(position.point.x, position.point.y - radius);
+            self.particles.push(Box::new(make(point)));
+        }
+    }
+
+    fn sprite_name(&mut self, id: EntityID) -> Option<String> {
+        let e = match self.game.get_entity(id) {
+            Some(e) => e,
+            None => return None,
+        };
+        render::sprite_name(e, self.game.mut_world())
+    }
+
+    /// Holding shift adds the command to the end of the hero's queue.
+    fn queueing(&self) -> bool {
+        self.modifiers.shift && self.has_capability(CAPABILITY_ORDER_QUEUE)
+    }
+
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    fn issue(&mut self, command: Command) {
+        let command = if self.queueing() {
+            Command::Queue(Box::new(command))
+        } else {
+            command
+        };
+        self.run_command(command);
+    }
+
     fn handle_mouse_motion(&mut self, x: f64, y: f64) {
+        self.camera.handle_cursor(&mut self.viewport, x, y);
         self.screen_mouse_x = x;
         self.screen_mouse_y = y;
         self.game_mouse_x = self.viewport.x_screen_to_game(x);
@@ -287,79 +931,110 @@ impl Client {
         None
     }
 
-    pub fn connect(&mut self, addr: net::SocketAddrV4) -> io::Result<()> {
-        let mut stream = Stream::new(TcpStream::connect(addr)?);
+    /// Plays until the player leaves the match, or returns why we were
+    /// disconnected.
+    fn play<W: piston_window::OpenGLWindow>(
+        &mut self,
+        window: &mut piston_window::PistonWindow<W>,
+        fonts: &mut render::Fonts,
+        joined: Joined,
+    ) -> Result<(), String> {
+        let Joined {
+            stream,
+            id,
+            capabilities,
+        } = joined;
         self.stream = Some(stream.clone());
-
-        let name = self.name.clone();
-        let team = self.team;
+        self.send_failure = None;
+        self.id = Some(id);
+        self.capabilities = capabilities;
 
         let current_ping = Arc::new(Mutex::new(0));
         let events = Arc::new(Mutex::new(Vec::new()));
-        let player_entity_id = Arc::new(Mutex::new(None));
+        let chat_lines = Arc::new(Mutex::new(Vec::new()));
+        let disconnected = Arc::new(Mutex::new(None));
 
         {
             let current_ping = current_ping.clone();
             let events = events.clone();
-            let player_entity_id = player_entity_id.clone();
+            let chat_lines = chat_lines.clone();
+            let pause_banner = self.pause_banner.clone();
+            let net_stats = self.net_stats.clone();
+            let disconnected = disconnected.clone();
 
             thread::spawn(move || {
-                stream
-                    .write_message(Message::Connect { name, team })
-                    .expect("1");
-
-                let message = stream.get_message().unwrap();
-                match message {
-                    Message::AcceptConnection { message } => {
-                        println!("Connection successful: {}", message);
-                    }
-                    _ => {
-                        panic!("Connection unsuccessful.");
-                    }
-                }
-
-                let message = stream.get_message().unwrap();
-                match message {
-                    Message::SetPlayerEntityID(id) => *player_entity_id.lock().unwrap() = Some(id),
-                    _ => panic!("Connection unsuccessful. (2)"),
-                }
-
                 let ping_store = Arc::new(Mutex::new(PingStore::new()));
 
                 {
                     let mut stream = stream.clone();
                     let ping_store = ping_store.clone();
                     thread::spawn(move || loop {
-                        stream
-                            .write_message(Message::Ping {
-                                id: ping_store.lock().unwrap().start_ping(),
-                            })
-                            .unwrap();
+                        let id = ping_store.lock().unwrap().start_ping();
+                        if stream.write_message(ClientMessage::Ping { id }).is_err() {
+                            break;
+                        }
                         thread::sleep(time::Duration::from_secs(1));
                     });
                 }
 
+                // answers the server's pings
+                let mut replies = stream.clone();
+
                 loop {
-                    let message = stream.get_message().unwrap();
-                    match message {
-                        Message::Kick { reason } => {
-                            println!("Kicked: {}", reason);
+                    let message = match stream.get_message() {
+                        Ok(message) => message,
+                        Err(err) => {
+                            *disconnected.lock().unwrap() =
+                                Some(format!("Lost connection to the server: {}", err));
                             break;
                         }
-                        Message::ReturnPing { id } => {
-                            let dur = ping_store.lock().unwrap().end_ping(id).unwrap();
-                            let ping_ms = dur.as_secs() * 1000 +
-                                (dur.subsec_nanos() / 1000000) as u64;
-                            *current_ping.lock().unwrap() = ping_ms;
-                            // println!("Ping: {}ms", ping_ms);
+                    };
+                    match message {
+                        ServerMessage::Kick { reason } => {
+                            *disconnected.lock().unwrap() = Some(format!("Kicked: {}", reason));
+                            break;
                         }
-                        Message::ReceiveChat { user, message } => {
-                            if user != "" {
-                                print!("[{}] ", user);
+                        ServerMessage::ReturnPing { id } => {
+                            // given up on, see `PingStore::start_ping`
+                            if let Some(dur) = ping_store.lock().unwrap().end_ping(id) {
+                                let ping_ms = dur.as_secs() * 1000 +
+                                    (dur.subsec_nanos() / 1000000) as u64;
+                                *current_ping.lock().unwrap() = ping_ms;
                             }
-                            println!("{}", message);
                         }
-                        Message::Events(mut e) => {
+                        ServerMessage::Ping { id } => {
+                            let _ = replies.write_message(ClientMessage::ReturnPing { id });
+                        }
+                        ServerMessage::ReceiveChat {
+                            user,
+                            message,
+                            channel,
+                        } => {
+                            chat_lines.lock().unwrap().push(ChatLine {
+                                user,
+                                message,
+                                channel,
+                            });
+                        }
+                        ServerMessage::Paused {
+                            by,
+                            remaining_budget,
+                        } => {
+                            let banner = match remaining_budget {
+                                Some(secs) => format!("Paused by {} ({}s left)", by, secs),
+                                None => format!("Paused by {}", by),
+                            };
+                            *pause_banner.lock().unwrap() = Some(banner);
+                        }
+                        ServerMessage::UnpauseCountdown { seconds } => {
+                            *pause_banner.lock().unwrap() =
+                                Some(format!("Resuming in {}...", seconds));
+                        }
+                        ServerMessage::Resumed => *pause_banner.lock().unwrap() = None,
+                        ServerMessage::NetStats(stats) => {
+                            *net_stats.lock().unwrap() = stats.into_iter().collect();
+                        }
+                        ServerMessage::Events(mut e) => {
                             events.lock().unwrap().append(&mut e);
                             // let () = e;
                         }
@@ -369,10 +1044,175 @@ impl Client {
             });
         }
 
-        self.run(current_ping, events, player_entity_id)
+        self.run(window, fonts, current_ping, events, chat_lines, disconnected)
+    }
+}
+
+enum Screen {
+    Menu,
+    Connecting(Connecting),
+    Error(String),
+}
+
+/// Shows the main menu, filled in from `menu_settings`, and plays matches
+/// joined from it until the window is closed. Connects straight away if
+/// `connect_to` is given. Every connection simulates `conditions`.
+pub fn run(
+    menu_settings: MenuSettings,
+    connect_to: Option<ConnectRequest>,
+    conditions: LinkConditions,
+) {
+    let mut config = ClientConfig::load_or_default(DEFAULT_CONFIG_PATH);
+    config.menu = menu_settings;
+
+    let mut window = new_window();
+    window.set_ups(60);
+
+    let mut fonts = render::Fonts::new(window.factory.clone());
+
+    let mut menu = Menu::new(config.menu.clone());
+    let mut screen = match connect_to {
+        Some(request) => Screen::Connecting(Connecting::start(request, conditions.clone())),
+        None => Screen::Menu,
+    };
+
+    // only looks for servers while the menu is showing
+    let mut lan_browser = None;
+
+    while let Some(e) = window.next() {
+        let piston_window::Size { width, height } = window.draw_size();
+
+        if let Screen::Menu = screen {
+            if lan_browser.is_none() {
+                lan_browser = Some(LanBrowser::start());
+            }
+        } else {
+            lan_browser = None;
+        }
+        if let Some(ref lan_browser) = lan_browser {
+            menu.set_lan_servers(lan_browser.servers());
+        }
+
+        let joined = match screen {
+            Screen::Connecting(ref mut connecting) => {
+                connecting
+                    .take_result()
+                    .map(|result| (connecting.request().clone(), result))
+            }
+            _ => None,
+        };
+
+        match joined {
+            Some((request, Ok(joined))) => {
+                config.menu = menu.settings().clone();
+                config.menu.remember_server(&request.address);
+                if let Err(err) = config.save(DEFAULT_CONFIG_PATH) {
+                    println!("Couldn't save {}: {}", DEFAULT_CONFIG_PATH, err);
+                }
+
+                let mut client = Client::new(config.clone());
+                let result = client.play(&mut window, &mut fonts, joined);
+                // bindings may have been changed in game
+                config = client.config.clone();
+                menu = Menu::new(config.menu.clone());
+
+                screen = match result {
+                    Ok(()) => Screen::Menu,
+                    Err(reason) => Screen::Error(reason),
+                };
+                continue;
+            }
+            Some((_, Err(reason))) => screen = Screen::Error(reason),
+            None => {}
+        }
+
+        match e {
+            Input::Render(_) => {
+                window.draw_2d(&e, |c, g| {
+                    piston_window::clear([1.0; 4], g);
+
+                    match screen {
+                        Screen::Menu => menu.render(c, g, &mut fonts, width as f64),
+                        Screen::Connecting(ref connecting) => {
+                            menu::render_message(
+                                c,
+                                g,
+                                &mut fonts,
+                                "Connecting",
+                                &connecting.status(),
+                                "Esc: cancel",
+                                width as f64,
+                                height as f64,
+                            )
+                        }
+                        Screen::Error(ref reason) => {
+                            menu::render_message(
+                                c,
+                                g,
+                                &mut fonts,
+                                "Disconnected",
+                                reason,
+                                "Enter or Esc: back to the menu",
+                                width as f64,
+                                height as f64,
+                            )
+                        }
+                    }
+                });
+            }
+            Input::Text(ref text) => {
+                if let Screen::Menu = screen {
+                    menu.handle_text(text);
+                }
+            }
+            Input::Move(Motion::MouseCursor(x, y)) => menu.handle_mouse_move(x, y),
+            Input::Press(button) => {
+                let action = match screen {
+                    Screen::Menu => {
+                        match button {
+                            Button::Keyboard(key) => menu.handle_key(key),
+                            Button::Mouse(piston_window::MouseButton::Left) => {
+                                menu.handle_click(width as f64)
+                            }
+                            _ => MenuAction::Nothing,
+                        }
+                    }
+                    _ => MenuAction::Nothing,
+                };
+
+                let back = match (&screen, button) {
+                    (&Screen::Menu, _) => false,
+                    (&Screen::Error(_), Button::Keyboard(Key::Return)) |
+                    (_, Button::Keyboard(Key::Escape)) => true,
+                    _ => false,
+                };
+                if back {
+                    // a cancelled connection carries on in the background,
+                    // and is dropped when it's done
+                    screen = Screen::Menu;
+                }
+
+                match action {
+                    MenuAction::Nothing => {}
+                    MenuAction::Connect(request) => {
+                        screen = Screen::Connecting(Connecting::start(request, conditions.clone()))
+                    }
+                    MenuAction::Quit => window.set_should_close(true),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    config.menu = menu.settings().clone();
+    if let Err(err) = config.save(DEFAULT_CONFIG_PATH) {
+        println!("Couldn't save {}: {}", DEFAULT_CONFIG_PATH, err);
     }
 }
 
+/// Pings unanswered for this long are given up on, so they don't pile up.
+const PING_TIMEOUT_SECS: u64 = 10;
+
 struct PingStore {
     next_id: u64,
     sent_times: HashMap<u64, time::Instant>,
@@ -395,6 +1235,9 @@ impl PingStore {
     fn start_ping(&mut self) -> u64 {
         let id = self.next_id();
 
+        let timeout = time::Duration::from_secs(PING_TIMEOUT_SECS);
+        self.sent_times.retain(|_, sent_time| sent_time.elapsed() < timeout);
+
         self.sent_times.insert(id, time::Instant::now());
         id
     }
@@ -0,0 +1,176 @@
+use piston_window::{self, Button, Context, G2d, Key, Transformed};
+
+use super::input::{Action, Binding, Bindings, CastMode, Modifiers, ACTIONS};
+use super::render::Fonts;
+
+const ROW_HEIGHT: f64 = 22.0;
+const WIDTH: f64 = 460.0;
+const BACKGROUND: [f32; 4] = [0.1, 0.1, 0.1, 0.9];
+const TEXT_COLOUR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const SELECTED_COLOUR: [f32; 4] = [1.0, 1.0, 0.5, 1.0];
+
+pub enum RebindResult {
+    Nothing,
+    /// The bindings were changed and should be saved.
+    Changed,
+    Close,
+}
+
+/// The in-game screen for changing key bindings.
+pub struct RebindScreen {
+    open: bool,
+    selected: usize,
+    /// Waiting for the new binding of the selected action.
+    capturing: bool,
+}
+
+impl RebindScreen {
+    pub fn new() -> Self {
+        RebindScreen {
+            open: false,
+            selected: 0,
+            capturing: false,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn open(&mut self) {
+        self.open = true;
+        self.capturing = false;
+    }
+
+    pub fn close(&mut self) {
+        self.open = false;
+    }
+
+    pub fn handle_press(
+        &mut self,
+        button: Button,
+        modifiers: Modifiers,
+        bindings: &mut Bindings,
+    ) -> RebindResult {
+        if self.capturing {
+            match button {
+                Button::Keyboard(Key::Escape) => self.capturing = false,
+                // wait for the key the modifiers go with
+                Button::Keyboard(Key::LShift) |
+                Button::Keyboard(Key::RShift) |
+                Button::Keyboard(Key::LCtrl) |
+                Button::Keyboard(Key::RCtrl) |
+                Button::Keyboard(Key::LAlt) |
+                Button::Keyboard(Key::RAlt) => {}
+                _ => {
+                    let binding = Binding { button, modifiers };
+                    bindings.set(ACTIONS[self.selected], Some(binding));
+                    self.capturing = false;
+                    return RebindResult::Changed;
+                }
+            }
+            return RebindResult::Nothing;
+        }
+
+        match button {
+            Button::Keyboard(Key::Up) => {
+                self.selected = (self.selected + ACTIONS.len() - 1) % ACTIONS.len();
+            }
+            Button::Keyboard(Key::Down) => self.selected = (self.selected + 1) % ACTIONS.len(),
+            Button::Keyboard(Key::Return) => self.capturing = true,
+            Button::Keyboard(Key::Delete) |
+            Button::Keyboard(Key::Backspace) => {
+                bindings.set(ACTIONS[self.selected], None);
+                return RebindResult::Changed;
+            }
+            Button::Keyboard(Key::C) => {
+                bindings.cast_mode = match bindings.cast_mode {
+                    CastMode::Quick => CastMode::Smart,
+                    CastMode::Smart => CastMode::Quick,
+                };
+                return RebindResult::Changed;
+            }
+            Button::Keyboard(Key::Escape) => return RebindResult::Close,
+            _ => {
+                if bindings.action_for_press(button, modifiers) == Some(Action::Bindings) {
+                    return RebindResult::Close;
+                }
+            }
+        }
+
+        RebindResult::Nothing
+    }
+
+    pub fn render(
+        &self,
+        c: Context,
+        g: &mut G2d,
+        fonts: &mut Fonts,
+        bindings: &Bindings,
+        width: f64,
+        height: f64,
+    ) {
+        let board_height = ROW_HEIGHT * (ACTIONS.len() as f64 + 4.0);
+        let x = (width - WIDTH) / 2.0;
+        let y = ((height - board_height) / 2.0).max(0.0);
+
+        piston_window::rectangle(BACKGROUND, [x, y, WIDTH, board_height], c.transform, g);
+
+        piston_window::text(
+            TEXT_COLOUR,
+            16,
+            "Key bindings",
+            &mut fonts.bold,
+            c.transform.trans(x + 10.0, y + ROW_HEIGHT),
+            g,
+        );
+
+        for (i, &action) in ACTIONS.iter().enumerate() {
+            let ry = y + ROW_HEIGHT * (i as f64 + 2.0);
+            let colour = if i == self.selected {
+                SELECTED_COLOUR
+            } else {
+                TEXT_COLOUR
+            };
+
+            let binding = if i == self.selected && self.capturing {
+                "Press a key...".into()
+            } else {
+                bindings
+                    .binding(action)
+                    .map(|b| b.to_string())
+                    .unwrap_or("-".into())
+            };
+
+            piston_window::text(
+                colour,
+                13,
+                action.description(),
+                &mut fonts.regular,
+                c.transform.trans(x + 10.0, ry),
+                g,
+            );
+            piston_window::text(
+                colour,
+                13,
+                &binding,
+                &mut fonts.regular,
+                c.transform.trans(x + 280.0, ry),
+                g,
+            );
+        }
+
+        let footer = format!(
+            "Cast mode: {:?} (C to change)   Enter: rebind   Delete: unbind   Esc: close",
+            bindings.cast_mode
+        );
+        piston_window::text(
+            TEXT_COLOUR,
+            11,
+            &footer,
+            &mut fonts.regular,
+            c.transform.trans(x + 10.0, y + board_height - 10.0),
+            g,
+        );
+    }
+}
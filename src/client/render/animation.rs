@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use common::{EntityID, Point};
+use super::sprite::Animation;
+
+/// Movement slower than this, in game units per second, counts as standing still.
+const WALK_THRESHOLD: f64 = 5.0;
+/// How long a unit must stop for before it goes back to idle.
+const STOP_DELAY: f64 = 0.1;
+
+struct AnimationState {
+    animation: Animation,
+    /// When the animation started, on the animator's clock.
+    since: f64,
+    /// An attack runs until this time, then the unit goes back to walking or idle.
+    until: Option<f64>,
+    /// In radians, clockwise from facing right.
+    facing: f64,
+    last_point: Point,
+    last_moved: f64,
+}
+
+/// A unit that has been removed from the game, still playing its death animation.
+pub struct Corpse {
+    pub sprite: String,
+    pub point: Point,
+    pub radius: f64,
+    pub colour: [f32; 4],
+    pub facing: f64,
+    since: f64,
+    until: f64,
+}
+
+/// Tracks which animation each unit is playing. This is client only: facing
+/// and walking are worked out from how units move between frames, since the
+/// client doesn't run the movement systems itself.
+pub struct Animator {
+    clock: f64,
+    states: HashMap<EntityID, AnimationState>,
+    corpses: Vec<Corpse>,
+}
+
+impl Animator {
+    pub fn new() -> Self {
+        Animator {
+            clock: 0.0,
+            states: HashMap::new(),
+            corpses: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, dt: f64) {
+        self.clock += dt;
+
+        let clock = self.clock;
+        self.corpses.retain(|c| c.until > clock);
+    }
+
+    /// Call every frame with the unit's position. Units that are out of
+    /// hitpoints play their death animation.
+    pub fn observe(&mut self, id: EntityID, point: Point, alive: bool, dt: f64) {
+        let clock = self.clock;
+        let state = self.states.entry(id).or_insert_with(|| {
+            AnimationState {
+                animation: Animation::Idle,
+                since: clock,
+                until: None,
+                facing: 0.0,
+                last_point: point,
+                last_moved: clock,
+            }
+        });
+
+        let (dx, dy) = (point.x - state.last_point.x, point.y - state.last_point.y);
+        let moving = dt > 0.0 && (dx * dx + dy * dy).sqrt() / dt > WALK_THRESHOLD;
+        state.last_point = point;
+
+        if moving {
+            state.facing = dy.atan2(dx);
+            state.last_moved = clock;
+        }
+
+        if !alive {
+            if state.animation != Animation::Death {
+                state.animation = Animation::Death;
+                state.since = clock;
+                state.until = None;
+            }
+            return;
+        }
+
+        if let Some(until) = state.until {
+            if clock < until {
+                return;
+            }
+            state.until = None;
+        }
+
+        let animation = if clock - state.last_moved < STOP_DELAY {
+            Animation::Walk
+        } else {
+            Animation::Idle
+        };
+        if animation != state.animation {
+            state.animation = animation;
+            state.since = clock;
+        }
+    }
+
+    /// Plays the attack animation, facing `towards` if given.
+    pub fn attack(&mut self, id: EntityID, towards: Option<Point>, duration: f64) {
+        if let Some(state) = self.states.get_mut(&id) {
+            if let Some(p) = towards {
+                let (dx, dy) = (p.x - state.last_point.x, p.y - state.last_point.y);
+                if dx != 0.0 || dy != 0.0 {
+                    state.facing = dy.atan2(dx);
+                }
+            }
+            state.animation = Animation::Attack;
+            state.since = self.clock;
+            state.until = Some(self.clock + duration);
+        }
+    }
+
+    /// Stops tracking the unit, leaving a corpse behind for `duration` seconds.
+    pub fn died(
+        &mut self,
+        id: EntityID,
+        sprite: String,
+        radius: f64,
+        colour: [f32; 4],
+        duration: f64,
+    ) {
+        if let Some(state) = self.states.remove(&id) {
+            // carry on from where the death animation got to, if it started already
+            let since = if state.animation == Animation::Death {
+                state.since
+            } else {
+                self.clock
+            };
+            self.corpses.push(Corpse {
+                sprite,
+                point: state.last_point,
+                radius,
+                colour,
+                facing: state.facing,
+                since,
+                until: since + duration,
+            });
+        }
+    }
+
+    pub fn remove(&mut self, id: EntityID) {
+        self.states.remove(&id);
+    }
+
+    /// The animation, seconds into it, and facing.
+    pub fn frame(&self, id: EntityID) -> (Animation, f64, f64) {
+        match self.states.get(&id) {
+            Some(state) => (state.animation, self.clock - state.since, state.facing),
+            None => (Animation::Idle, self.clock, 0.0),
+        }
+    }
+
+    /// Corpses, with how far into the death animation each is.
+    pub fn corpses(&self) -> Vec<(&Corpse, f64)> {
+        self.corpses
+            .iter()
+            .map(|c| (c, self.clock - c.since))
+            .collect()
+    }
+}
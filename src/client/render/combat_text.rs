@@ -0,0 +1,82 @@
+use piston_window::*;
+use piston_window::character::CharacterCache;
+
+use common::{Damage, DamageKind, Point};
+use super::{Fonts, Layer, Viewport};
+use super::particle::Particle;
+
+const LIFETIME: f64 = 1.0;
+/// Screen units per second.
+const RISE_SPEED: f64 = 40.0;
+const FONT_SIZE: u32 = 16;
+const CRIT_FONT_SIZE: u32 = 24;
+
+/// A number that floats up from a unit and fades, for damage and healing.
+pub struct CombatText {
+    text: String,
+    colour: [f32; 4],
+    size: u32,
+    point: Point,
+    age: f64,
+}
+
+impl CombatText {
+    pub fn damage(point: Point, damage: Damage) -> Self {
+        let colour = match damage.kind {
+            DamageKind::Physical => [0.9, 0.1, 0.1, 1.0],
+            DamageKind::Magical => [0.3, 0.4, 1.0, 1.0],
+        };
+
+        if damage.crit {
+            CombatText::new(point, format!("{}!", damage.amount), colour, CRIT_FONT_SIZE)
+        } else {
+            CombatText::new(point, damage.amount.to_string(), colour, FONT_SIZE)
+        }
+    }
+
+    pub fn heal(point: Point, amount: u16) -> Self {
+        CombatText::new(point, format!("+{}", amount), [0.1, 0.8, 0.1, 1.0], FONT_SIZE)
+    }
+
+    fn new(point: Point, text: String, colour: [f32; 4], size: u32) -> Self {
+        CombatText {
+            text,
+            colour,
+            size,
+            point,
+            age: 0.0,
+        }
+    }
+}
+
+impl Particle for CombatText {
+    fn update(&mut self, t: f64) {
+        self.age += t;
+    }
+
+    fn should_remove(&self) -> bool {
+        self.age >= LIFETIME
+    }
+
+    fn layer(&self) -> Layer {
+        Layer::Ui
+    }
+
+    fn render(&mut self, viewport: Viewport, c: Context, g: &mut G2d, fonts: &mut Fonts) {
+        let mut colour = self.colour;
+        colour[3] *= (1.0 - self.age / LIFETIME).max(0.0) as f32;
+
+        let width = fonts.bold.width(self.size, &self.text);
+        let x = viewport.x_game_to_screen(self.point.x) - width / 2.0;
+        let y = viewport.y_game_to_screen(self.point.y) - RISE_SPEED * self.age;
+
+        text(
+            colour,
+            self.size,
+            &self.text,
+            &mut fonts.bold,
+            c.transform.trans(x, y),
+            g,
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::f64::consts::PI;
+use piston_window::*;
+use serde_json;
+
+use common::{EntityID, Point};
+use super::{Fonts, Layer, Viewport};
+use super::particle::Particle;
+
+pub const EFFECTS_PATH: &str = "./assets/effects.json";
+
+/// Gameplay moments that can have an effect attached in `effects.json`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    ProjectileHit,
+    Damage,
+    Death,
+    AbilityCast,
+}
+
+/// An emitter's settings. Curves are keyframes spread evenly over each
+/// particle's lifetime, and ranges are `[min, max]`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct EffectDef {
+    /// How long the emitter keeps spawning particles, in seconds.
+    #[serde(default)]
+    pub duration: f64,
+    /// Particles spawned straight away.
+    #[serde(default)]
+    pub burst: u32,
+    /// Particles spawned per second while the emitter runs.
+    #[serde(default)]
+    pub rate: f64,
+    pub lifetime: [f64; 2],
+    /// In game units per second, in a random direction.
+    pub speed: [f64; 2],
+    /// Added to each particle's vertical velocity per second.
+    #[serde(default)]
+    pub gravity: f64,
+    pub colour: Vec<[f32; 4]>,
+    /// Radius in game units.
+    pub size: Vec<f64>,
+}
+
+#[derive(Deserialize, Default)]
+struct EffectsFile {
+    effects: HashMap<String, EffectDef>,
+    triggers: HashMap<Trigger, String>,
+}
+
+/// The effects described by `effects.json`.
+pub struct EffectLibrary {
+    effects: HashMap<String, EffectDef>,
+    triggers: HashMap<Trigger, String>,
+    seed: u64,
+}
+
+impl EffectLibrary {
+    /// With no effects if the file is missing or invalid.
+    pub fn load_or_empty<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|err| err.to_string())
+            .and_then(|f| {
+                serde_json::from_reader::<_, EffectsFile>(f).map_err(|err| err.to_string())
+            });
+
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                println!("Couldn't load effects from {}: {}", path.display(), err);
+                EffectsFile::default()
+            }
+        };
+
+        for (trigger, name) in &file.triggers {
+            if !file.effects.contains_key(name) {
+                println!("Unknown effect {:?} for {:?}", name, trigger);
+            }
+        }
+
+        EffectLibrary {
+            effects: file.effects,
+            triggers: file.triggers,
+            seed: 0x2545F4914F6CDD1D,
+        }
+    }
+
+    /// An emitter for the effect, at `point` or following `attached_to`.
+    pub fn spawn(
+        &mut self,
+        name: &str,
+        point: Point,
+        attached_to: Option<EntityID>,
+    ) -> Option<Emitter> {
+        let def = match self.effects.get(name) {
+            Some(def) => def.clone(),
+            None => return None,
+        };
+
+        // each emitter gets its own random sequence
+        self.seed = self.seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+
+        Some(Emitter::new(def, point, attached_to, self.seed))
+    }
+
+    pub fn trigger(
+        &mut self,
+        trigger: Trigger,
+        point: Point,
+        attached_to: Option<EntityID>,
+    ) -> Option<Emitter> {
+        let name = match self.triggers.get(&trigger) {
+            Some(name) => name.clone(),
+            None => return None,
+        };
+        self.spawn(&name, point, attached_to)
+    }
+}
+
+/// xorshift, good enough for scattering particles.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn range(&mut self, range: [f64; 2]) -> f64 {
+        range[0] + (range[1] - range[0]) * self.next()
+    }
+}
+
+struct Spark {
+    point: Point,
+    velocity: (f64, f64),
+    age: f64,
+    lifetime: f64,
+}
+
+/// Spawns and draws particles as described by an `EffectDef`.
+pub struct Emitter {
+    def: EffectDef,
+    point: Point,
+    attached_to: Option<EntityID>,
+    age: f64,
+    /// Fractional particles owed by `rate`.
+    pending: f64,
+    sparks: Vec<Spark>,
+    rng: Rng,
+}
+
+impl Emitter {
+    fn new(def: EffectDef, point: Point, attached_to: Option<EntityID>, seed: u64) -> Self {
+        let mut emitter = Emitter {
+            def,
+            point,
+            attached_to,
+            age: 0.0,
+            pending: 0.0,
+            sparks: Vec::new(),
+            rng: Rng(seed | 1),
+        };
+
+        for _ in 0..emitter.def.burst {
+            emitter.emit();
+        }
+
+        emitter
+    }
+
+    fn emit(&mut self) {
+        let angle = self.rng.next() * 2.0 * PI;
+        let speed = self.rng.range(self.def.speed);
+        let lifetime = self.rng.range(self.def.lifetime);
+
+        self.sparks.push(Spark {
+            point: self.point,
+            velocity: (angle.cos() * speed, angle.sin() * speed),
+            age: 0.0,
+            lifetime,
+        });
+    }
+}
+
+/// Linear interpolation between evenly spaced keyframes, `t` from 0 to 1.
+fn sample<T, F>(keys: &[T], t: f64, lerp: F) -> Option<T>
+where
+    T: Copy,
+    F: Fn(T, T, f64) -> T,
+{
+    match keys.len() {
+        0 => None,
+        1 => Some(keys[0]),
+        n => {
+            let x = t.max(0.0).min(1.0) * (n - 1) as f64;
+            let i = (x as usize).min(n - 2);
+            Some(lerp(keys[i], keys[i + 1], x - i as f64))
+        }
+    }
+}
+
+impl Particle for Emitter {
+    fn update(&mut self, t: f64) {
+        self.age += t;
+
+        if self.age <= self.def.duration {
+            self.pending += self.def.rate * t;
+            while self.pending >= 1.0 {
+                self.pending -= 1.0;
+                self.emit();
+            }
+        }
+
+        let gravity = self.def.gravity;
+        for spark in &mut self.sparks {
+            spark.age += t;
+            spark.velocity.1 += gravity * t;
+            spark.point.x += spark.velocity.0 * t;
+            spark.point.y += spark.velocity.1 * t;
+        }
+        self.sparks.retain(|s| s.age < s.lifetime);
+    }
+
+    fn should_remove(&self) -> bool {
+        self.age > self.def.duration && self.sparks.is_empty()
+    }
+
+    fn attached_to(&self) -> Option<EntityID> {
+        self.attached_to
+    }
+
+    fn follow(&mut self, position: Option<Point>) {
+        match position {
+            Some(point) => self.point = point,
+            // the entity is gone, so stop spawning and let the rest fade
+            None => {
+                self.attached_to = None;
+                self.def.duration = 0.0;
+            }
+        }
+    }
+
+    /// Drawn over units and projectiles.
+    fn layer(&self) -> Layer {
+        Layer::Ui
+    }
+
+    fn render(&mut self, viewport: Viewport, c: Context, g: &mut G2d, _: &mut Fonts) {
+        for spark in &self.sparks {
+            let t = spark.age / spark.lifetime;
+            let colour = sample(&self.def.colour, t, |a, b, f| {
+                let f = f as f32;
+                [
+                    a[0] + (b[0] - a[0]) * f,
+                    a[1] + (b[1] - a[1]) * f,
+                    a[2] + (b[2] - a[2]) * f,
+                    a[3] + (b[3] - a[3]) * f,
+                ]
+            }).unwrap_or([0.0, 0.0, 0.0, 1.0]);
+            let size = sample(&self.def.size, t, |a, b, f| a + (b - a) * f).unwrap_or(2.0);
+
+            let radius = viewport.d_game_to_screen(size);
+            ellipse(
+                colour,
+                [-radius, -radius, radius * 2.0, radius * 2.0],
+                c.transform.trans(
+                    viewport.x_game_to_screen(spark.point.x),
+                    viewport.y_game_to_screen(spark.point.y),
+                ),
+                g,
+            );
+        }
+    }
+}
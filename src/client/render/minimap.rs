@@ -0,0 +1,151 @@
+use piston_window::*;
+use specs::{self, Join};
+
+use common::{self, logic, Point};
+use super::Viewport;
+
+const SIZE: f64 = 200.0;
+const MARGIN: f64 = 10.0;
+const DOT_RADIUS: f64 = 3.0;
+
+/// An overview of the whole map in the bottom right corner of the screen.
+pub struct Minimap;
+
+impl Minimap {
+    pub fn new() -> Self {
+        Minimap
+    }
+
+    /// [x, y, width, height] on screen. The map's aspect ratio is kept.
+    fn rect(&self, screen_width: f64, screen_height: f64, map: logic::MapKind) -> [f64; 4] {
+        let ratio = map.width() / map.height();
+        let (w, h) = if ratio >= 1.0 {
+            (SIZE, SIZE / ratio)
+        } else {
+            (SIZE * ratio, SIZE)
+        };
+
+        [screen_width - w - MARGIN, screen_height - h - MARGIN, w, h]
+    }
+
+    pub fn contains(
+        &self,
+        x: f64,
+        y: f64,
+        screen_width: f64,
+        screen_height: f64,
+        map: logic::MapKind,
+    ) -> bool {
+        let r = self.rect(screen_width, screen_height, map);
+        x >= r[0] && x <= r[0] + r[2] && y >= r[1] && y <= r[1] + r[3]
+    }
+
+    pub fn screen_to_game(
+        &self,
+        x: f64,
+        y: f64,
+        screen_width: f64,
+        screen_height: f64,
+        map: logic::MapKind,
+    ) -> Point {
+        let r = self.rect(screen_width, screen_height, map);
+        let (min, max) = map.bounds();
+
+        Point::new(
+            min.x + (x - r[0]) / r[2] * (max.x - min.x),
+            min.y + (y - r[1]) / r[3] * (max.y - min.y),
+        )
+    }
+
+    fn game_to_screen(&self, p: Point, r: [f64; 4], map: logic::MapKind) -> (f64, f64) {
+        let (min, max) = map.bounds();
+
+        (
+            r[0] + (p.x - min.x) / (max.x - min.x) * r[2],
+            r[1] + (p.y - min.y) / (max.y - min.y) * r[3],
+        )
+    }
+
+    pub fn render(
+        &self,
+        viewport: Viewport,
+        c: Context,
+        g: &mut G2d,
+        world: &mut specs::World,
+        own_id: Option<common::EntityID>,
+        screen_width: f64,
+        screen_height: f64,
+        map: logic::MapKind,
+    ) {
+        let r = self.rect(screen_width, screen_height, map);
+
+        rectangle([0.2, 0.3, 0.2, 0.9], r, c.transform, g);
+
+        let (idc, posc, hitpointsc, teamc) = (
+            world.read::<common::EntityID>(),
+            world.read::<common::Position>(),
+            world.read::<common::Hitpoints>(),
+            world.read::<common::Team>(),
+        );
+
+        let own_team = own_id.and_then(|id| {
+            (&idc, &teamc)
+                .join()
+                .find(|&(&i, _)| i == id)
+                .map(|(_, &team)| team)
+        });
+
+        // only units are shown, not projectiles
+        for (&id, pos, _, entity) in (&idc, &posc, &hitpointsc, &*world.entities()).join() {
+            let team = teamc.get(entity).cloned();
+            let colour = if Some(id) == own_id {
+                [0.0, 0.0, 1.0, 1.0]
+            } else if team.is_some() && team == own_team {
+                [0.0, 1.0, 0.0, 1.0]
+            } else if team.is_none() {
+                [0.8, 0.8, 0.8, 1.0]
+            } else {
+                [1.0, 0.0, 0.0, 1.0]
+            };
+
+            let (x, y) = self.game_to_screen(pos.point, r, map);
+            ellipse(
+                colour,
+                [
+                    x - DOT_RADIUS,
+                    y - DOT_RADIUS,
+                    DOT_RADIUS * 2.0,
+                    DOT_RADIUS * 2.0,
+                ],
+                c.transform,
+                g,
+            );
+        }
+
+        // the part of the map currently on screen
+        let (x1, y1) = self.game_to_screen(
+            Point::new(viewport.x_screen_to_game(0.0), viewport.y_screen_to_game(0.0)),
+            r,
+            map,
+        );
+        let (x2, y2) = self.game_to_screen(
+            Point::new(
+                viewport.x_screen_to_game(screen_width),
+                viewport.y_screen_to_game(screen_height),
+            ),
+            r,
+            map,
+        );
+        let x1 = x1.max(r[0]);
+        let y1 = y1.max(r[1]);
+        let x2 = x2.min(r[0] + r[2]);
+        let y2 = y2.min(r[1] + r[3]);
+
+        Rectangle::new_border([1.0; 4], 1.0).draw(
+            [x1, y1, x2 - x1, y2 - y1],
+            &c.draw_state,
+            c.transform,
+            g,
+        );
+    }
+}
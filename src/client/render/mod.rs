@@ -7,6 +7,21 @@ use common;
 
 pub mod particle;
 
+mod minimap;
+pub use self::minimap::*;
+
+mod sprite;
+pub use self::sprite::*;
+
+mod animation;
+pub use self::animation::*;
+
+mod effect;
+pub use self::effect::*;
+
+mod combat_text;
+pub use self::combat_text::*;
+
 #[derive(Clone, Copy)]
 pub struct Viewport {
     // In game units
@@ -48,6 +63,52 @@ impl Viewport {
     pub fn d_screen_to_game(&self, v: f64) -> f64 {
         v / self.scale
     }
+
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    pub fn move_by(&mut self, dx: f64, dy: f64) {
+        self.x += dx;
+        self.y += dy;
+    }
+
+    /// Sets the scale, keeping the game point at screen position (sx, sy) in place.
+    pub fn zoom_at(&mut self, scale: f64, sx: f64, sy: f64) {
+        let gx = self.x_screen_to_game(sx);
+        let gy = self.y_screen_to_game(sy);
+        self.scale = scale;
+        self.x = gx - sx / scale;
+        self.y = gy - sy / scale;
+    }
+
+    pub fn centre_on(&mut self, point: common::Point, screen_width: f64, screen_height: f64) {
+        self.x = point.x - self.d_screen_to_game(screen_width) / 2.0;
+        self.y = point.y - self.d_screen_to_game(screen_height) / 2.0;
+    }
+
+    /// Keeps the view inside `bounds`, or centred on it if the view is larger.
+    pub fn clamp_to(
+        &mut self,
+        bounds: (common::Point, common::Point),
+        screen_width: f64,
+        screen_height: f64,
+    ) {
+        let (min, max) = bounds;
+        let width = self.d_screen_to_game(screen_width);
+        let height = self.d_screen_to_game(screen_height);
+
+        self.x = if width >= max.x - min.x {
+            (min.x + max.x - width) / 2.0
+        } else {
+            self.x.max(min.x).min(max.x - width)
+        };
+        self.y = if height >= max.y - min.y {
+            (min.y + max.y - height) / 2.0
+        } else {
+            self.y.max(min.y).min(max.y - height)
+        };
+    }
 }
 
 pub struct Fonts {
@@ -77,20 +138,55 @@ impl Fonts {
 //     TextureSettings::new().compress(true)
 // }
 
-pub fn render(
+/// Draw order, back to front.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    Ground,
+    Units,
+    Projectiles,
+    Ui,
+}
+
+pub fn layer_of(kind: common::EntityKind) -> Layer {
+    match kind {
+        common::EntityKind::Hero => Layer::Units,
+        common::EntityKind::Projectile => Layer::Projectiles,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    /// Draw entities as plain circles instead of sprites.
+    pub debug_circles: bool,
+}
+
+/// The name of the entity's sprite in the atlas.
+pub fn sprite_name(entity: specs::Entity, world: &specs::World) -> Option<String> {
+    if let Some(p) = world.read::<common::Player>().get(entity) {
+        return Some(format!("{:?}", p.hero).to_lowercase());
+    }
+    if world.read::<common::Projectile>().get(entity).is_some() {
+        return Some("projectile".into());
+    }
+    None
+}
+
+/// Draws the entity itself: its sprite, or a circle if it has none.
+pub fn render_body(
     viewport: Viewport,
     c: Context,
     g: &mut G2d,
-    fonts: &mut Fonts,
     entity: specs::Entity,
     world: &mut specs::World,
+    sprites: Option<&SpriteAtlas>,
+    animator: &Animator,
 ) {
-    let (r_component, pos_component, player_component, teamc, hitpointsc) = (
+    let sprite = sprite_name(entity, world);
+    let (r_component, pos_component, idc) = (
         world.read::<common::Renderable>(),
         world.read::<common::Position>(),
-        world.read::<common::Player>(),
-        world.read::<common::Team>(),
-        world.read::<common::Hitpoints>(),
+        world.read::<common::EntityID>(),
     );
 
     if let Some(r) = r_component.get(entity) {
@@ -101,25 +197,101 @@ pub fn render(
         let sx = viewport.x_game_to_screen(position.point.x);
         let sy = viewport.y_game_to_screen(position.point.y);
 
+        if let (Some(sprites), Some(sprite), Some(&id)) = (sprites, sprite, idc.get(entity)) {
+            let (animation, time, facing) = animator.frame(id);
+            if sprites.draw(
+                &sprite,
+                animation,
+                time,
+                facing,
+                r.colour,
+                sx,
+                sy,
+                radius * 2.0,
+                c,
+                g,
+            )
+            {
+                return;
+            }
+        }
+
         ellipse(
             r.colour,
             [-radius, -radius, radius * 2.0, radius * 2.0],
             c.transform.trans(sx, sy),
             g,
         );
+    }
+}
+
+/// Draws units that have died but are still playing their death animation.
+pub fn render_corpses(
+    viewport: Viewport,
+    c: Context,
+    g: &mut G2d,
+    sprites: Option<&SpriteAtlas>,
+    animator: &Animator,
+) {
+    let sprites = match sprites {
+        Some(sprites) => sprites,
+        None => return,
+    };
+
+    for (corpse, time) in animator.corpses() {
+        sprites.draw(
+            &corpse.sprite,
+            Animation::Death,
+            time,
+            corpse.facing,
+            corpse.colour,
+            viewport.x_game_to_screen(corpse.point.x),
+            viewport.y_game_to_screen(corpse.point.y),
+            viewport.d_game_to_screen(corpse.radius) * 2.0,
+            c,
+            g,
+        );
+    }
+}
+
+/// Draws the health bar and nameplate above the entity.
+pub fn render_overlay(
+    viewport: Viewport,
+    c: Context,
+    g: &mut G2d,
+    fonts: &mut Fonts,
+    entity: specs::Entity,
+    world: &mut specs::World,
+) {
+    let (r_component, pos_component, player_component, teamc, hitpointsc) = (
+        world.read::<common::Renderable>(),
+        world.read::<common::Position>(),
+        world.read::<common::Player>(),
+        world.read::<common::Team>(),
+        world.read::<common::Hitpoints>(),
+    );
+
+    if let Some(r) = r_component.get(entity) {
+        let radius = viewport.d_game_to_screen(r.radius);
+
+        let position = pos_component.get(entity).unwrap();
+
+        let sx = viewport.x_game_to_screen(position.point.x);
+        let sy = viewport.y_game_to_screen(position.point.y);
+
+        if let Some(hp) = hitpointsc.get(entity) {
+            render_health_bar(c, g, hp, sx, sy - radius - 12.0, radius * 2.0);
+        }
 
         if let Some(p) = player_component.get(entity) {
             let size = 16;
-            let hp = hitpointsc.get(entity).unwrap();
             let name = &format!(
-                "{} ({}) ({}/{})",
+                "{} ({})",
                 p.name,
                 teamc
                     .get(entity)
                     .map(|t| t.0.to_string())
-                    .unwrap_or("-".into()),
-                hp.current(),
-                hp.max()
+                    .unwrap_or("-".into())
             );
             let width = fonts.bold.width(size, name);
 
@@ -128,9 +300,70 @@ pub fn render(
                 size,
                 name,
                 &mut fonts.bold,
-                c.transform.trans(sx - width / 2.0, sy - radius * 1.4),
+                c.transform.trans(sx - width / 2.0, sy - radius - 18.0),
                 g,
             );
         }
     }
 }
+
+pub const HEALTH_BAR_HEIGHT: f64 = 6.0;
+
+/// Draws a bar centred on `x`, with its top at `y`.
+pub fn render_health_bar(
+    c: Context,
+    g: &mut G2d,
+    hp: &common::Hitpoints,
+    x: f64,
+    y: f64,
+    width: f64,
+) {
+    let fraction = if hp.max() == 0 {
+        0.0
+    } else {
+        hp.current() as f64 / hp.max() as f64
+    };
+    let left = x - width / 2.0;
+
+    rectangle(
+        [0.2, 0.2, 0.2, 1.0],
+        [left - 1.0, y - 1.0, width + 2.0, HEALTH_BAR_HEIGHT + 2.0],
+        c.transform,
+        g,
+    );
+    rectangle(
+        [
+            (1.0 - fraction) as f32,
+            fraction as f32 * 0.8,
+            0.0,
+            1.0,
+        ],
+        [left, y, width * fraction, HEALTH_BAR_HEIGHT],
+        c.transform,
+        g,
+    );
+}
+
+/// Draws lines joining the points, e.g. a hero and its queued waypoints.
+pub fn render_path(viewport: Viewport, c: Context, g: &mut G2d, points: &[common::Point]) {
+    let colour = [0.0, 0.6, 0.0, 0.7];
+
+    for pair in points.windows(2) {
+        let (from, to) = (pair[0], pair[1]);
+        line(
+            colour,
+            1.5,
+            [
+                viewport.x_game_to_screen(from.x),
+                viewport.y_game_to_screen(from.y),
+                viewport.x_game_to_screen(to.x),
+                viewport.y_game_to_screen(to.y),
+            ],
+            c.transform,
+            g,
+        );
+
+        let (x, y) = (viewport.x_game_to_screen(to.x), viewport.y_game_to_screen(to.y));
+        ellipse(colour, [x - 4.0, y - 4.0, 8.0, 8.0], c.transform, g);
+    }
+}
@@ -1,11 +1,26 @@
-use super::Viewport;
+use super::{Fonts, Layer, Viewport};
 
 use piston_window::*;
 
+use common::{EntityID, Point};
+
 pub trait Particle {
-    fn render(&mut self, viewport: Viewport, Context, &mut G2d);
+    fn render(&mut self, viewport: Viewport, Context, &mut G2d, &mut Fonts);
     fn update(&mut self, world_time: f64);
     fn should_remove(&self) -> bool;
+
+    /// The entity the particle moves with, if any.
+    fn attached_to(&self) -> Option<EntityID> {
+        None
+    }
+
+    /// Called each frame with the position of the entity the particle is
+    /// attached to, or `None` once that entity is gone.
+    fn follow(&mut self, _position: Option<Point>) {}
+
+    fn layer(&self) -> Layer {
+        Layer::Ground
+    }
 }
 
 pub struct RightClick {
@@ -33,7 +48,7 @@ impl Particle for RightClick {
         self.time < 0.0
     }
 
-    fn render(&mut self, viewport: Viewport, c: Context, g: &mut G2d) {
+    fn render(&mut self, viewport: Viewport, c: Context, g: &mut G2d, _: &mut Fonts) {
         let radius = 10.0 * self.time / 0.3;
         ellipse(
             [0.0, 0.0, 1.0, 1.0],
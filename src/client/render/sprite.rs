@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use piston_window::*;
+use gfx_device_gl::{Factory, Resources};
+use serde_json;
+
+pub const ATLAS_PATH: &str = "./assets/sprites/atlas.json";
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Animation {
+    Idle,
+    Walk,
+    Attack,
+    Death,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct AnimationFrames {
+    /// [x, y, width, height] in the atlas image.
+    frames: Vec<[f64; 4]>,
+    fps: f64,
+    looping: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct SpriteSheet {
+    /// True if the frames are drawn facing right, and should be turned to
+    /// face the way the unit is going.
+    rotate: bool,
+    animations: HashMap<Animation, AnimationFrames>,
+}
+
+#[derive(Deserialize)]
+struct AtlasFile {
+    /// Relative to the atlas file.
+    image: String,
+    sprites: HashMap<String, SpriteSheet>,
+}
+
+/// One texture holding every sprite, described by a JSON file of frame rectangles.
+pub struct SpriteAtlas {
+    texture: Texture<Resources>,
+    sheets: HashMap<String, SpriteSheet>,
+}
+
+impl SpriteAtlas {
+    pub fn load<P: AsRef<Path>>(factory: &mut Factory, path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let atlas: AtlasFile = serde_json::from_reader(file)
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        let image_path = path.with_file_name(&atlas.image);
+        let texture = Texture::from_path(
+            factory,
+            &image_path,
+            Flip::None,
+            &TextureSettings::new(),
+        )?;
+
+        Ok(SpriteAtlas {
+            texture,
+            sheets: atlas.sprites,
+        })
+    }
+
+    /// How long a non-looping animation takes to play, in seconds.
+    pub fn duration(&self, sprite: &str, animation: Animation) -> Option<f64> {
+        self.sheets
+            .get(sprite)
+            .and_then(|sheet| sheet.animations.get(&animation))
+            .map(|a| a.frames.len() as f64 / a.fps)
+    }
+
+    /// Draws the frame of `animation` that's `time` seconds in, centred on
+    /// (x, y) and `size` screen units across. Animations the sprite doesn't
+    /// have fall back to idle. Returns false if there's nothing to draw.
+    pub fn draw(
+        &self,
+        sprite: &str,
+        animation: Animation,
+        time: f64,
+        facing: f64,
+        colour: [f32; 4],
+        x: f64,
+        y: f64,
+        size: f64,
+        c: Context,
+        g: &mut G2d,
+    ) -> bool {
+        let sheet = match self.sheets.get(sprite) {
+            Some(sheet) => sheet,
+            None => return false,
+        };
+        let frames = match sheet
+            .animations
+            .get(&animation)
+            .or_else(|| sheet.animations.get(&Animation::Idle)) {
+            Some(frames) if !frames.frames.is_empty() => frames,
+            _ => return false,
+        };
+
+        let count = frames.frames.len();
+        let index = (time.max(0.0) * frames.fps) as usize;
+        let index = if frames.looping {
+            index % count
+        } else {
+            index.min(count - 1)
+        };
+
+        let mut transform = c.transform.trans(x, y);
+        if sheet.rotate {
+            transform = transform.rot_rad(facing);
+        }
+
+        Image::new_color(colour)
+            .src_rect(frames.frames[index])
+            .rect([-size / 2.0, -size / 2.0, size, size])
+            .draw(&self.texture, &c.draw_state, transform, g);
+
+        true
+    }
+}
@@ -0,0 +1,44 @@
+use sha2::{Digest, Sha256};
+
+/// A registered player, the same every time they connect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlayerID(pub u64);
+
+/// What a client sends to prove it has `token`, without sending the token
+/// itself. `nonce` comes from the server and is different every time, so a
+/// proof can't be replayed.
+pub fn auth_proof(token: &str, nonce: &str) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(b"moba auth\0");
+    hasher.input(nonce.as_bytes());
+    hasher.input(b"\0");
+    hasher.input(token.as_bytes());
+
+    hasher
+        .result()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_hash_the_nonce_and_token() {
+        assert_eq!(
+            auth_proof("token", "nonce"),
+            "fdfc4544547062bb75db44c90b82d9ca588e1e4d727300a3beebfa089ea12146"
+        );
+    }
+
+    #[test]
+    fn proofs_differ_by_nonce_and_token() {
+        let proof = auth_proof("token", "nonce");
+        assert_ne!(proof, auth_proof("token", "other nonce"));
+        assert_ne!(proof, auth_proof("other token", "nonce"));
+        // the separator stops one being moved into the other
+        assert_ne!(auth_proof("ab", "c"), auth_proof("b", "ca"));
+    }
+}
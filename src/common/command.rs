@@ -3,8 +3,58 @@ use common::*;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     SetTarget(Target),
+    /// Move to the point, attacking enemies met on the way.
+    AttackMove(Point),
+    Stop,
+    /// Stay put, attacking enemies that come into range.
+    HoldPosition,
+    /// Carry out the command after the unit's other orders, rather than instead of them.
+    Queue(Box<Command>),
     UseAbility {
         ability_id: u32,
         mouse_position: Option<Point>,
     },
 }
+
+/// A command that a unit carries out over time. Units keep a queue of these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Order {
+    Move(Target),
+    AttackMove(Point),
+    HoldPosition,
+    UseAbility {
+        ability_id: u32,
+        mouse_position: Option<Point>,
+    },
+}
+
+impl Order {
+    /// `None` if the command leaves the unit with nothing to do.
+    pub fn from_command(command: Command, origin: EntityID) -> Option<Order> {
+        match command {
+            Command::SetTarget(Target::Nothing) => None,
+            Command::SetTarget(Target::Entity(id)) if id == origin => None, // XXX error?
+            Command::SetTarget(target) => Some(Order::Move(target)),
+            Command::AttackMove(point) => Some(Order::AttackMove(point)),
+            Command::Stop => None,
+            Command::HoldPosition => Some(Order::HoldPosition),
+            Command::Queue(command) => Order::from_command(*command, origin),
+            Command::UseAbility {
+                ability_id,
+                mouse_position,
+            } => Some(Order::UseAbility {
+                ability_id,
+                mouse_position,
+            }),
+        }
+    }
+
+    /// Where the unit will walk to, for drawing the queued path.
+    pub fn waypoint(&self) -> Option<Point> {
+        match *self {
+            Order::Move(Target::Position(point)) |
+            Order::AttackMove(point) => Some(point),
+            _ => None,
+        }
+    }
+}
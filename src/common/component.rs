@@ -1,4 +1,5 @@
 use std::cmp;
+use std::collections::VecDeque;
 
 use specs;
 use common::*;
@@ -118,10 +119,40 @@ impl Hitbox {
     }
 }
 
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DamageKind {
+    /// From basic attacks.
+    Physical,
+    /// From abilities.
+    Magical,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct Damage {
+    pub amount: u16,
+    pub kind: DamageKind,
+    /// Only changes how the damage is shown.
+    #[serde(default)]
+    pub crit: bool,
+}
+
+impl Damage {
+    pub fn new(amount: u16, kind: DamageKind) -> Self {
+        Damage {
+            amount,
+            kind,
+            crit: false,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Projectile {
-    pub damage: u16,
+    pub damage: Damage,
     pub owner: EntityID,
+    /// How far back hits are checked, in seconds, so that a skillshot hits what
+    /// its owner aimed at on their lagged screen. Always 0 on the client.
+    pub rewind: f64,
 }
 
 impl specs::Component for Projectile {
@@ -164,16 +195,89 @@ impl specs::Component for BasicAttacker {
     type Storage = specs::VecStorage<BasicAttacker>;
 }
 
+/// Seconds until each of an entity's abilities can be used again.
+#[derive(Clone, Debug)]
+pub struct AbilityCooldowns {
+    pub remaining: Vec<f64>,
+}
+
+impl AbilityCooldowns {
+    pub fn new(ability_count: usize) -> Self {
+        AbilityCooldowns {
+            remaining: vec![0.0; ability_count],
+        }
+    }
+
+    pub fn is_ready(&self, ability_id: u32) -> bool {
+        self.remaining
+            .get(ability_id as usize)
+            .map_or(false, |&r| r <= 0.0)
+    }
+
+    pub fn count_down(&mut self, time: f64) {
+        for remaining in &mut self.remaining {
+            *remaining = (*remaining - time).max(0.0);
+        }
+    }
+}
+
+impl specs::Component for AbilityCooldowns {
+    type Storage = specs::HashMapStorage<AbilityCooldowns>;
+}
+
 #[derive(Clone, Debug)]
 pub struct Unit {
     pub speed: f64,
-    pub target: Target,
+    /// The front order is the one being carried out. Idle when empty.
+    pub orders: VecDeque<Order>,
+}
+
+impl Unit {
+    pub fn new(speed: f64, order: Option<Order>) -> Self {
+        Unit {
+            speed,
+            orders: order.into_iter().collect(),
+        }
+    }
+
+    pub fn current_order(&self) -> Option<&Order> {
+        self.orders.front()
+    }
+
+    /// What the current order has the unit moving towards or attacking.
+    pub fn target(&self) -> Target {
+        match self.current_order() {
+            Some(&Order::Move(ref target)) => target.clone(),
+            Some(&Order::AttackMove(point)) => Target::Position(point),
+            _ => Target::Nothing,
+        }
+    }
+
+    pub fn mode(&self) -> UnitMode {
+        match self.current_order() {
+            Some(&Order::AttackMove(_)) => UnitMode::AttackMove,
+            Some(&Order::HoldPosition) => UnitMode::HoldPosition,
+            _ => UnitMode::Normal,
+        }
+    }
 }
 
 impl specs::Component for Unit {
     type Storage = specs::VecStorage<Unit>;
 }
 
+/// How a unit picks what to attack when it hasn't been told to attack something.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnitMode {
+    /// Only attacks its target.
+    Normal,
+    /// Moves to its target position, but stops to chase and attack any enemy
+    /// within `logic::ACQUISITION_RANGE` on the way.
+    AttackMove,
+    /// Doesn't move, but attacks any enemy in range.
+    HoldPosition,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Velocity {
     pub vector: Vector,
@@ -0,0 +1,333 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How a simulated network treats what goes over it, for reproducing laggy
+/// players locally.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// One way.
+    pub latency_ms: u64,
+    /// Latency varies randomly by up to this much either way.
+    pub jitter_ms: u64,
+    /// Chance of a packet being lost, from 0 to 1.
+    pub loss: f64,
+    /// Chance of a packet arriving twice, from 0 to 1.
+    pub duplicate: f64,
+    /// Kilobits per second, if limited.
+    pub bandwidth_kbps: Option<u64>,
+}
+
+impl LinkConditions {
+    pub fn is_perfect(&self) -> bool {
+        *self == LinkConditions::default()
+    }
+}
+
+/// Parses a list like `latency=100,jitter=20,loss=5%,duplicate=1%,bandwidth=256`.
+/// Times are in milliseconds and bandwidth in kilobits per second. Anything
+/// left out is perfect.
+impl FromStr for LinkConditions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut conditions = LinkConditions::default();
+
+        for setting in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let mut parts = setting.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(format!("{:?} needs a value, like {}=10", key, key)),
+            };
+
+            match key {
+                "latency" => conditions.latency_ms = parse_number(key, value)?,
+                "jitter" => conditions.jitter_ms = parse_number(key, value)?,
+                "loss" => conditions.loss = parse_chance(key, value)?,
+                "duplicate" => conditions.duplicate = parse_chance(key, value)?,
+                "bandwidth" => conditions.bandwidth_kbps = Some(parse_number(key, value)?),
+                _ => {
+                    return Err(format!(
+                        "Unknown network setting {:?}, expected latency, jitter, loss, \
+                         duplicate or bandwidth",
+                        key
+                    ))
+                }
+            }
+        }
+
+        if conditions.bandwidth_kbps == Some(0) {
+            return Err("bandwidth must be more than 0".into());
+        }
+
+        Ok(conditions)
+    }
+}
+
+impl fmt::Display for LinkConditions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "latency {}ms, jitter {}ms, loss {}%, duplicate {}%",
+            self.latency_ms,
+            self.jitter_ms,
+            self.loss * 100.0,
+            self.duplicate * 100.0
+        )?;
+        match self.bandwidth_kbps {
+            Some(kbps) => write!(f, ", bandwidth {}kbps", kbps),
+            None => Ok(()),
+        }
+    }
+}
+
+fn micros(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000)
+}
+
+fn parse_number(key: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} should be a whole number, not {:?}", key, value))
+}
+
+/// `5%` or `0.05`.
+fn parse_chance(key: &str, value: &str) -> Result<f64, String> {
+    let chance = if value.ends_with('%') {
+        value[..value.len() - 1].parse::<f64>().map(|percent| percent / 100.0)
+    } else {
+        value.parse::<f64>()
+    };
+
+    match chance {
+        Ok(chance) if chance >= 0.0 && chance <= 1.0 => Ok(chance),
+        _ => Err(format!("{} should be between 0% and 100%, not {:?}", key, value)),
+    }
+}
+
+/// Holds things back until a simulated network would have delivered them.
+/// With perfect conditions everything is due as soon as it's pushed.
+pub struct Conditioner<T> {
+    conditions: LinkConditions,
+    /// Like TCP: nothing is lost, duplicated or reordered, and a lost packet
+    /// instead holds up everything behind it until it's resent.
+    reliable: bool,
+    /// Sorted by when each is due.
+    queue: Vec<(Instant, T)>,
+    last_due: Option<Instant>,
+    /// When everything sent so far will have fit through the bandwidth cap.
+    link_free_at: Option<Instant>,
+    rng: u64,
+}
+
+impl<T: Clone> Conditioner<T> {
+    pub fn new(conditions: LinkConditions, reliable: bool) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64 ^ d.as_secs())
+            .unwrap_or(0);
+
+        Conditioner {
+            conditions,
+            reliable,
+            queue: Vec::new(),
+            last_due: None,
+            link_free_at: None,
+            // xorshift gets stuck on 0
+            rng: seed | 1,
+        }
+    }
+
+    /// `size` is in bytes, for the bandwidth cap.
+    pub fn push(&mut self, item: T, size: usize, now: Instant) {
+        let sent = match self.conditions.bandwidth_kbps {
+            Some(kbps) => {
+                let start = self.link_free_at.map_or(now, |free| free.max(now));
+                let sent = start + micros(size as u64 * 8 * 1000 / kbps);
+                self.link_free_at = Some(sent);
+                sent
+            }
+            None => now,
+        };
+
+        let (loss, duplicate) = (self.conditions.loss, self.conditions.duplicate);
+        let lost = self.chance(loss);
+        if self.reliable {
+            let mut due = sent + self.delay();
+            if lost {
+                // about how long a resend would take
+                let resend_ms = (self.conditions.latency_ms * 2).max(200);
+                due += Duration::from_millis(resend_ms);
+            }
+            let due = self.last_due.map_or(due, |last| last.max(due));
+            self.last_due = Some(due);
+            self.insert(due, item);
+        } else {
+            if lost {
+                return;
+            }
+            if self.chance(duplicate) {
+                let due = sent + self.delay();
+                self.insert(due, item.clone());
+            }
+            let due = sent + self.delay();
+            self.insert(due, item);
+        }
+    }
+
+    /// Everything that has arrived by `now`, in the order it arrived.
+    pub fn pop_due(&mut self, now: Instant) -> Vec<T> {
+        let count = self.queue.iter().take_while(|&&(due, _)| due <= now).count();
+        self.queue.drain(..count).map(|(_, item)| item).collect()
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.first().map(|&(due, _)| due)
+    }
+
+    fn insert(&mut self, due: Instant, item: T) {
+        let index = self.queue.iter().take_while(|&&(d, _)| d <= due).count();
+        self.queue.insert(index, (due, item));
+    }
+
+    fn delay(&mut self) -> Duration {
+        let latency = self.conditions.latency_ms as i64;
+        let jitter = self.conditions.jitter_ms as i64;
+        let offset = if jitter > 0 {
+            (self.next_random() % (jitter as u64 * 2 + 1)) as i64 - jitter
+        } else {
+            0
+        };
+        Duration::from_millis((latency + offset).max(0) as u64)
+    }
+
+    fn chance(&mut self, chance: f64) -> bool {
+        chance > 0.0 && (self.next_random() % 1_000_000) as f64 / 1_000_000.0 < chance
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+/// A `Conditioner` on a thread of its own, which hands each item to `deliver`
+/// when it's due. Once every clone has been dropped, what was already pushed
+/// is still delivered.
+pub struct DelayLine<T> {
+    sender: mpsc::Sender<(T, usize)>,
+}
+
+// derived Clone would need T to be Clone
+impl<T> Clone for DelayLine<T> {
+    fn clone(&self) -> Self {
+        DelayLine { sender: self.sender.clone() }
+    }
+}
+
+impl<T: Clone + Send + 'static> DelayLine<T> {
+    pub fn spawn<F>(conditions: LinkConditions, reliable: bool, mut deliver: F) -> Self
+    where
+        F: FnMut(T) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<(T, usize)>();
+
+        thread::spawn(move || {
+            let mut conditioner = Conditioner::new(conditions, reliable);
+            let mut open = true;
+
+            while open || conditioner.next_due().is_some() {
+                let now = Instant::now();
+                let received = match conditioner.next_due() {
+                    Some(due) if due <= now => Err(mpsc::RecvTimeoutError::Timeout),
+                    Some(due) if open => receiver.recv_timeout(due - now),
+                    Some(due) => {
+                        thread::sleep(due - now);
+                        Err(mpsc::RecvTimeoutError::Timeout)
+                    }
+                    None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                };
+
+                match received {
+                    Ok((item, size)) => conditioner.push(item, size, Instant::now()),
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => open = false,
+                }
+
+                for item in conditioner.pop_due(Instant::now()) {
+                    deliver(item);
+                }
+            }
+        });
+
+        DelayLine { sender }
+    }
+
+    pub fn push(&self, item: T, size: usize) {
+        // the thread only stops once every sender has gone
+        let _ = self.sender.send((item, size));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_setting_is_parsed() {
+        let s = "latency=100, jitter=20,loss=5%,duplicate=0.01,bandwidth=256";
+        let conditions: LinkConditions = s.parse().unwrap();
+        assert_eq!(
+            conditions,
+            LinkConditions {
+                latency_ms: 100,
+                jitter_ms: 20,
+                loss: 0.05,
+                duplicate: 0.01,
+                bandwidth_kbps: Some(256),
+            }
+        );
+    }
+
+    #[test]
+    fn anything_left_out_is_perfect() {
+        assert!("".parse::<LinkConditions>().unwrap().is_perfect());
+        let conditions: LinkConditions = "latency=50,".parse().unwrap();
+        assert_eq!(conditions.latency_ms, 50);
+        assert_eq!(conditions.loss, 0.0);
+        assert_eq!(conditions.bandwidth_kbps, None);
+    }
+
+    #[test]
+    fn bad_settings_are_rejected() {
+        for s in &[
+            "latency",
+            "latency=fast",
+            "latency=-1",
+            "lag=100",
+            "loss=150%",
+            "loss=-0.1",
+            "duplicate=lots",
+            "bandwidth=0",
+        ] {
+            assert!(s.parse::<LinkConditions>().is_err(), "{:?} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn conditions_are_displayed_in_full() {
+        let conditions: LinkConditions = "latency=100,jitter=20,loss=5%,bandwidth=256"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            conditions.to_string(),
+            "latency 100ms, jitter 20ms, loss 5%, duplicate 0%, bandwidth 256kbps"
+        );
+    }
+}
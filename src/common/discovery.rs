@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time;
+use serde_json;
+
+use common::DEFAULT_PORT;
+use common::logic::{GameMode, MapKind};
+
+/// Servers answer discovery queries on this UDP port.
+pub const DISCOVERY_PORT: u16 = DEFAULT_PORT + 1;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Big enough for any reply.
+const MAX_PACKET_SIZE: usize = 4096;
+
+/// What a server says about itself when asked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub map: MapKind,
+    pub game_mode: GameMode,
+    /// In every match and the matchmaking queue.
+    pub players: usize,
+    /// Per match.
+    pub max_players: usize,
+    pub version: String,
+    /// The TCP port to connect to.
+    pub port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DiscoveryPacket {
+    Query,
+    Reply(ServerInfo),
+}
+
+pub fn encode_packet(packet: &DiscoveryPacket) -> Vec<u8> {
+    serde_json::to_vec(packet).unwrap()
+}
+
+/// `None` for anything that isn't ours.
+pub fn decode_packet(bytes: &[u8]) -> Option<DiscoveryPacket> {
+    serde_json::from_slice(bytes).ok()
+}
+
+/// Broadcasts a query on the local network, and to this machine, and
+/// collects replies for `wait`. Returns each server's game address.
+pub fn discover(wait: time::Duration) -> io::Result<Vec<(SocketAddr, ServerInfo)>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_broadcast(true)?;
+
+    let query = encode_packet(&DiscoveryPacket::Query);
+    for &ip in &[Ipv4Addr::new(255, 255, 255, 255), Ipv4Addr::new(127, 0, 0, 1)] {
+        // there may be no network to broadcast on
+        let _ = socket.send_to(&query, SocketAddrV4::new(ip, DISCOVERY_PORT));
+    }
+
+    // a server on this machine can answer both queries
+    let mut servers = HashMap::new();
+    let started = time::Instant::now();
+    let mut buf = [0; MAX_PACKET_SIZE];
+
+    loop {
+        let elapsed = started.elapsed();
+        if elapsed >= wait {
+            break;
+        }
+        socket.set_read_timeout(Some(wait - elapsed))?;
+
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                                err.kind() == io::ErrorKind::TimedOut => break,
+            Err(err) => return Err(err),
+        };
+
+        if let Some(DiscoveryPacket::Reply(info)) = decode_packet(&buf[..len]) {
+            servers.insert(SocketAddr::new(from.ip(), info.port), info);
+        }
+    }
+
+    let mut servers: Vec<_> = servers.into_iter().collect();
+    servers.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    Ok(servers)
+}
@@ -3,6 +3,7 @@ use common::*;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Event {
+    SetMap(logic::MapKind),
     EntityMove(EntityID, Point),
     AddHero {
         id: EntityID,
@@ -15,10 +16,31 @@ pub enum Event {
         id: EntityID,
         position: Point,
         target: Target,
-        damage: u16,
+        damage: Damage,
         team: Option<Team>,
         owner: EntityID,
     },
-    DamageEntity { id: EntityID, damage: u16 },
+    DamageEntity { id: EntityID, damage: Damage },
+    /// Purely informational, for effects and sounds.
+    AbilityUsed { id: EntityID, ability_id: u32 },
+    SetHitpoints { id: EntityID, current: u16 },
+    SetTeam { id: EntityID, team: Option<Team> },
     RemoveEntity(EntityID),
+    /// The unit has finished its current order and moves on to the next one.
+    CompleteOrder(EntityID),
+    /// The server's `AbilityCooldowns` for a hero. Only sent to the hero's
+    /// own client, with `CAPABILITY_COOLDOWNS`.
+    SetCooldowns { id: EntityID, remaining: Vec<f64> },
+}
+
+impl Event {
+    /// Whether a later event of the same kind makes this one irrelevant, so
+    /// that it's fine for it to be lost.
+    pub fn is_superseded(&self) -> bool {
+        match *self {
+            Event::EntityMove(..) |
+            Event::SetCooldowns { .. } => true,
+            _ => false,
+        }
+    }
 }
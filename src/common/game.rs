@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use na::{self, Point2};
 use ncollide::query::PointQuery;
-use specs;
+use specs::{self, Join};
 
 use common::*;
 
@@ -14,11 +14,18 @@ pub enum Target {
 }
 
 pub struct Game {
+    map: logic::MapKind,
     entity_ids: Vec<EntityID>,
     players: Vec<EntityID>,
     next_entity_id: Arc<Mutex<u32>>,
     entity_map: Arc<Mutex<HashMap<EntityID, specs::Entity>>>,
     world: specs::World,
+    /// Seconds of game time ticked so far.
+    elapsed: f64,
+    /// Only kept by the server, which is the only one checking hits.
+    history: Option<Arc<Mutex<PositionHistory>>>,
+    /// How far back each player's skillshots are checked. See `Projectile::rewind`.
+    rewinds: HashMap<EntityID, f64>,
 }
 
 impl Game {
@@ -36,16 +43,35 @@ impl Game {
         w.register::<Hitpoints>();
         w.register::<Team>();
         w.register::<BasicAttacker>();
+        w.register::<AbilityCooldowns>();
 
         Game {
+            map: logic::MapKind::Arena,
             entity_ids: Vec::new(),
             players: Vec::new(),
             next_entity_id: Arc::new(Mutex::new(0)),
             entity_map: Arc::new(Mutex::new(HashMap::new())),
             world: w,
+            elapsed: 0.0,
+            history: None,
+            rewinds: HashMap::new(),
         }
     }
 
+    pub fn map(&self) -> logic::MapKind {
+        self.map
+    }
+
+    pub fn set_map(&mut self, map: logic::MapKind) {
+        self.map = map;
+    }
+
+    /// Records where everything is after each tick, so that skillshots can be
+    /// checked against where their targets were on the shooter's screen.
+    pub fn keep_history(&mut self) {
+        self.history = Some(Arc::new(Mutex::new(PositionHistory::new())));
+    }
+
     pub fn players(&self) -> &[EntityID] {
         &self.players
     }
@@ -65,7 +91,21 @@ impl Game {
         position: Point,
         team: Option<Team>,
     ) -> EntityID {
-        let e = self.add_entity(id, EntityKind::Hero, |entity| {
+        let e = self.add_hero(id, hero, name, position, team);
+        self.players.push(e);
+        e
+    }
+
+    /// Adds a hero that isn't controlled by a player.
+    pub fn add_hero(
+        &mut self,
+        id: EntityID,
+        hero: logic::HeroKind,
+        name: String,
+        position: Point,
+        team: Option<Team>,
+    ) -> EntityID {
+        self.add_entity(id, EntityKind::Hero, |entity| {
             let mut e = entity
                 .with(Position { point: position })
                 .with(Player {
@@ -77,15 +117,13 @@ impl Game {
                     colour: [0.0, 1.0, 0.0, 1.0],
                 })
                 .with(Hitbox::new_ball(hero.radius()))
-                .with(Unit {
-                    speed: hero.speed(),
-                    target: Target::Nothing,
-                })
+                .with(Unit::new(hero.speed(), None))
                 .with(BasicAttacker {
                     attack_speed: hero.attack_speed(),
                     time_until_next_attack: 0.0,
                     range: hero.range(),
                 })
+                .with(AbilityCooldowns::new(hero.ability_count()))
                 .with(Hitpoints::new_at_max(50))
                 .with(Velocity::new(0.0, 0.0));
 
@@ -94,10 +132,13 @@ impl Game {
             }
 
             e
-        });
+        })
+    }
 
-        self.players.push(e);
-        e
+    /// Sets how far back `player`'s skillshots are checked from now on, usually
+    /// their round trip time. Capped at `MAX_REWIND`.
+    pub fn set_lag_compensation(&mut self, player: EntityID, rewind: f64) {
+        self.rewinds.insert(player, rewind.max(0.0).min(MAX_REWIND));
     }
 
     pub fn add_projectile(
@@ -105,23 +146,25 @@ impl Game {
         id: EntityID,
         position: Point,
         target: Target,
-        damage: u16,
+        damage: Damage,
         team: Option<Team>,
         owner: EntityID,
     ) -> EntityID {
+        let rewind = self.rewinds.get(&owner).cloned().unwrap_or(0.0);
         self.add_entity(id, EntityKind::Projectile, |entity| {
             let mut e = entity
                 .with(Position { point: position })
-                .with(Projectile { damage, owner })
+                .with(Projectile {
+                    damage,
+                    owner,
+                    rewind,
+                })
                 .with(Renderable {
                     radius: 5.0,
                     colour: [1.0, 0.0, 0.0, 1.0],
                 })
                 .with(Hitbox::new_ball(5.0))
-                .with(Unit {
-                    speed: 800.0,
-                    target: target,
-                })
+                .with(Unit::new(800.0, Some(Order::Move(target))))
                 .with(Velocity::new(0.0, 0.0));
 
             if let Some(team) = team {
@@ -208,6 +251,12 @@ impl Game {
         self.with_component::<T, _, _>(e, |_| {}).is_some()
     }
 
+    /// Whether `damage` would kill a unit that's still alive.
+    pub fn is_killing_blow(&mut self, id: EntityID, damage: Damage) -> bool {
+        self.clone_component::<Hitpoints>(id)
+            .map_or(false, |hp| hp.current() > 0 && damage.amount >= hp.current())
+    }
+
     pub fn entity_contains_point(&mut self, e: EntityID, x: f64, y: f64) -> bool {
         let entity = match self.get_entity(e) {
             Some(entity) => entity,
@@ -233,32 +282,66 @@ impl Game {
         let mut events = Vec::new();
 
         match command {
-            Command::SetTarget(target) => {
-                let mut tc = self.world.write::<Unit>();
-
-                tc.get_mut(entity).unwrap().target = match target {
-                    Target::Entity(id) if id == origin => Target::Nothing, // XXX error?
-                    x => x,
-                };
-            }
-
             Command::UseAbility {
                 ability_id,
                 mouse_position,
             } => {
+                let hero = match self.world.read::<Player>().get(entity) {
+                    Some(player) => player.hero,
+                    None => return events,
+                };
+                let mouse_position = match mouse_position {
+                    Some(mouse_position) => mouse_position,
+                    None => return events,
+                };
+
+                {
+                    let mut cooldownc = self.world.write::<AbilityCooldowns>();
+                    let cooldowns = match cooldownc.get_mut(entity) {
+                        Some(cooldowns) => cooldowns,
+                        None => return events,
+                    };
+                    if !logic::start_cooldown(hero, cooldowns, ability_id) {
+                        return events;
+                    }
+                }
+
                 let eid = self.next_entity_id();
                 let positionc = self.world.read::<Position>();
                 let teamc = self.world.read::<Team>();
                 let p = positionc.get(entity).unwrap().point;
-                let e = Event::AddProjectile {
-                    id: eid,
-                    position: p,
-                    target: Target::Position(mouse_position.unwrap()),
-                    damage: 10,
-                    owner: origin,
-                    team: teamc.get(entity).cloned(),
-                };
-                events.push(e);
+                events.push(Event::AbilityUsed {
+                    id: origin,
+                    ability_id,
+                });
+                events.extend(logic::ability_event(
+                    hero,
+                    ability_id,
+                    origin,
+                    p,
+                    teamc.get(entity).cloned(),
+                    mouse_position,
+                    eid,
+                ));
+            }
+
+            Command::Queue(command) => {
+                let mut tc = self.world.write::<Unit>();
+                let unit = tc.get_mut(entity).unwrap();
+
+                if let Some(order) = Order::from_command(*command, origin) {
+                    unit.orders.push_back(order);
+                }
+            }
+
+            command => {
+                let mut tc = self.world.write::<Unit>();
+                let unit = tc.get_mut(entity).unwrap();
+
+                unit.orders.clear();
+                if let Some(order) = Order::from_command(command, origin) {
+                    unit.orders.push_back(order);
+                }
             }
         }
 
@@ -270,19 +353,33 @@ impl Game {
         self.entity_map.lock().unwrap().remove(&id);
         self.players.retain(|&p| p != id);
         self.entity_ids.retain(|&x| x != id);
+        self.rewinds.remove(&id);
         self.world.delete_entity(e);
     }
 
     pub fn run_event(&mut self, event: Event) {
         println!("{:?}", event);
         match event {
+            Event::SetMap(map) => {
+                self.set_map(map);
+            }
             Event::RemoveEntity(id) => {
                 self.remove_entity(id);
             }
+            Event::AbilityUsed { .. } => {}
+            Event::CompleteOrder(id) => {
+                if let Some(e) = self.get_entity(id) {
+                    let mut unitc = self.world.write::<Unit>();
+                    unitc.get_mut(e).map(|unit| unit.orders.pop_front());
+                }
+            }
             Event::EntityMove(id, point) => {
-                let e = self.get_entity(id).unwrap();
-                let mut posc = self.world.write::<Position>();
-                posc.get_mut(e).unwrap().point = point;
+                // moves can arrive out of order with the events that add and
+                // remove entities, see `Event::is_superseded`
+                if let Some(e) = self.get_entity(id) {
+                    let mut posc = self.world.write::<Position>();
+                    posc.get_mut(e).map(|x| x.point = point);
+                }
             }
             Event::AddHero {
                 id,
@@ -306,12 +403,46 @@ impl Game {
             Event::DamageEntity { id, damage } => {
                 if let Some(e) = self.get_entity(id) {
                     let mut hitpointsc = self.world.write::<Hitpoints>();
-                    hitpointsc.get_mut(e).map(|x| x.damage(damage)); // XXX
+                    hitpointsc.get_mut(e).map(|x| x.damage(damage.amount)); // XXX
+                }
+            }
+            Event::SetHitpoints { id, current } => {
+                if let Some(e) = self.get_entity(id) {
+                    let mut hitpointsc = self.world.write::<Hitpoints>();
+                    hitpointsc.get_mut(e).map(|x| x.set_current(current));
+                }
+            }
+            Event::SetCooldowns { id, remaining } => {
+                if let Some(e) = self.get_entity(id) {
+                    let mut cooldownc = self.world.write::<AbilityCooldowns>();
+                    cooldownc.get_mut(e).map(|x| x.remaining = remaining);
+                }
+            }
+            Event::SetTeam { id, team } => {
+                if let Some(e) = self.get_entity(id) {
+                    let mut teamc = self.world.write::<Team>();
+                    match team {
+                        Some(team) => {
+                            teamc.insert(e, team);
+                        }
+                        None => {
+                            teamc.remove(e);
+                        }
+                    }
                 }
             }
         }
     }
 
+    /// For clients, which don't run the systems, to keep cooldowns counting
+    /// down between the server's updates.
+    pub fn count_down_cooldowns(&mut self, time: f64) {
+        let mut cooldownc = self.world.write::<AbilityCooldowns>();
+        for cooldowns in (&mut cooldownc).join() {
+            cooldowns.count_down(time);
+        }
+    }
+
     pub fn run_events(&mut self, events: &[Event]) {
         for e in events {
             self.run_event(e.clone());
@@ -320,8 +451,15 @@ impl Game {
 
     pub fn tick(&mut self, time: f64) -> Vec<Event> {
         self.world.maintain();
-
-        let context = Context::new(time, self.entity_map.clone(), self.next_entity_id.clone());
+        self.elapsed += time;
+
+        let context = Context::new(
+            time,
+            self.elapsed,
+            self.entity_map.clone(),
+            self.next_entity_id.clone(),
+            self.history.clone(),
+        );
         self.world.add_resource(context.clone()); // XXX
         let mut dispatcher = register_systems(specs::DispatcherBuilder::new()).build();
         dispatcher.dispatch(&mut self.world.res);
@@ -331,12 +469,30 @@ impl Game {
         self.run_events(&events);
 
         self.world.maintain();
+        self.record_positions();
 
         events
     }
 
+    fn record_positions(&mut self) {
+        let history = match self.history {
+            Some(ref history) => history.clone(),
+            None => return,
+        };
+        let positions = {
+            let idc = self.world.read::<EntityID>();
+            let positionc = self.world.read::<Position>();
+            let positions: HashMap<EntityID, Point> = (&idc, &positionc)
+                .join()
+                .map(|(&id, position)| (id, position.point))
+                .collect();
+            positions
+        };
+        history.lock().unwrap().record(self.elapsed, positions);
+    }
+
     pub fn events_for_loading(&mut self) -> Vec<Event> {
-        let mut events = Vec::new();
+        let mut events = vec![Event::SetMap(self.map)];
 
         for &id in &self.entity_ids {
             let e = self.get_entity(id).unwrap();
@@ -371,7 +527,7 @@ impl Game {
                     events.push(Event::AddProjectile {
                         id,
                         position: pos,
-                        target: unit.target.clone(),
+                        target: unit.target(),
                         damage: proj.damage,
                         team,
                         owner: proj.owner,
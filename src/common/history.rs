@@ -0,0 +1,92 @@
+use std::collections::{HashMap, VecDeque};
+
+use common::*;
+
+/// The furthest back hit detection is rewound for a laggy shooter, in seconds.
+/// Any more and players would be hit well after they'd dodged.
+pub const MAX_REWIND: f64 = 0.2;
+
+/// Where every entity was over the last `MAX_REWIND` seconds, recorded once a
+/// tick by the server.
+pub struct PositionHistory {
+    /// Oldest first, by game time in seconds.
+    snapshots: VecDeque<(f64, HashMap<EntityID, Point>)>,
+}
+
+impl PositionHistory {
+    pub fn new() -> Self {
+        PositionHistory { snapshots: VecDeque::new() }
+    }
+
+    pub fn record(&mut self, time: f64, positions: HashMap<EntityID, Point>) {
+        self.snapshots.push_back((time, positions));
+
+        // keep one snapshot from at least MAX_REWIND ago
+        while self.snapshots.len() > 1 && self.snapshots[1].0 <= time - MAX_REWIND {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// The snapshot closest to `time`, if any have been recorded.
+    pub fn positions_at(&self, time: f64) -> Option<&HashMap<EntityID, Point>> {
+        self.snapshots
+            .iter()
+            .min_by(|a, b| {
+                (a.0 - time).abs().partial_cmp(&(b.0 - time).abs()).unwrap()
+            })
+            .map(|&(_, ref positions)| positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use common::{EntityID, Point};
+    use super::*;
+
+    const UNIT: EntityID = EntityID(0);
+
+    fn at(x: f64) -> HashMap<EntityID, Point> {
+        let mut positions = HashMap::new();
+        positions.insert(UNIT, Point::new(x, 0.0));
+        positions
+    }
+
+    fn x_at(history: &PositionHistory, time: f64) -> Option<f64> {
+        history.positions_at(time).map(|positions| positions[&UNIT].x)
+    }
+
+    #[test]
+    fn nothing_recorded_means_no_positions() {
+        assert!(PositionHistory::new().positions_at(1.0).is_none());
+    }
+
+    #[test]
+    fn the_closest_snapshot_is_used() {
+        let mut history = PositionHistory::new();
+        history.record(1.0, at(10.0));
+        history.record(1.05, at(20.0));
+        history.record(1.1, at(30.0));
+
+        assert_eq!(x_at(&history, 1.04), Some(20.0));
+        assert_eq!(x_at(&history, 1.09), Some(30.0));
+        // further back than anything recorded
+        assert_eq!(x_at(&history, 0.5), Some(10.0));
+    }
+
+    #[test]
+    fn snapshots_older_than_max_rewind_are_dropped() {
+        let tick_length = 1.0 / 16.0;
+        let mut history = PositionHistory::new();
+        for tick in 0..100 {
+            history.record(tick as f64 * tick_length, at(tick as f64));
+        }
+
+        // the oldest kept is the newest from at least MAX_REWIND ago
+        let now = 99.0 * tick_length;
+        assert_eq!(x_at(&history, 0.0), Some(95.0));
+        assert!(now - 95.0 * tick_length >= MAX_REWIND);
+        assert!(now - 96.0 * tick_length < MAX_REWIND);
+        assert_eq!(history.snapshots.len(), 5);
+    }
+}
@@ -1,6 +1,11 @@
 use common::*;
 
-use specs;
+use specs::{self, Join};
+
+/// How close an enemy must be for a unit on attack-move to go after it.
+pub const ACQUISITION_RANGE: f64 = 500.0;
+
+pub const BASIC_ATTACK_DAMAGE: u16 = 5;
 
 // pub trait Ability {
 //     fn run(&self, &Game) -> Vec<Event>;
@@ -37,6 +42,13 @@ pub enum HeroKind {
 }
 
 impl HeroKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match &*name.to_lowercase() {
+            "john" => Some(HeroKind::John),
+            _ => None,
+        }
+    }
+
     pub fn radius(self) -> f64 {
         match self {
             HeroKind::John => 50.0,
@@ -60,6 +72,106 @@ impl HeroKind {
             HeroKind::John => 0.8,
         }
     }
+
+    pub fn ability_count(self) -> usize {
+        match self {
+            HeroKind::John => 1,
+        }
+    }
+
+    pub fn ability_name(self, ability_id: u32) -> Option<&'static str> {
+        match (self, ability_id) {
+            (HeroKind::John, 0) => Some("Shoot"),
+            _ => None,
+        }
+    }
+
+    /// In seconds. `None` if the hero has no such ability.
+    pub fn ability_cooldown(self, ability_id: u32) -> Option<f64> {
+        match (self, ability_id) {
+            (HeroKind::John, 0) => Some(2.0),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapKind {
+    Arena,
+}
+
+impl MapKind {
+    pub fn width(self) -> f64 {
+        match self {
+            MapKind::Arena => 3000.0,
+        }
+    }
+
+    pub fn height(self) -> f64 {
+        match self {
+            MapKind::Arena => 3000.0,
+        }
+    }
+
+    /// Maps are centred on the origin. Returns the top-left and bottom-right corners.
+    pub fn bounds(self) -> (Point, Point) {
+        let (hw, hh) = (self.width() / 2.0, self.height() / 2.0);
+        (Point::new(-hw, -hh), Point::new(hw, hh))
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GameMode {
+    FreeForAll,
+    Teams,
+}
+
+impl GameMode {
+    pub fn team_count(self) -> u8 {
+        match self {
+            GameMode::FreeForAll => 0,
+            GameMode::Teams => 2,
+        }
+    }
+}
+
+/// Starts the cooldown if the hero has the ability and it's ready. Returns
+/// false if the ability can't be used.
+pub fn start_cooldown(hero: HeroKind, cooldowns: &mut AbilityCooldowns, ability_id: u32) -> bool {
+    let cooldown = match hero.ability_cooldown(ability_id) {
+        Some(cooldown) => cooldown,
+        None => return false,
+    };
+    if !cooldowns.is_ready(ability_id) {
+        return false;
+    }
+
+    cooldowns.remaining[ability_id as usize] = cooldown;
+    true
+}
+
+/// The event for `origin` using an ability aimed at `mouse_position`.
+/// `id` is used for any entity the ability creates.
+pub fn ability_event(
+    hero: HeroKind,
+    ability_id: u32,
+    origin: EntityID,
+    position: Point,
+    team: Option<Team>,
+    mouse_position: Point,
+    id: EntityID,
+) -> Option<Event> {
+    match (hero, ability_id) {
+        (HeroKind::John, 0) => Some(Event::AddProjectile {
+            id,
+            position,
+            target: Target::Position(mouse_position),
+            damage: Damage::new(10, DamageKind::Magical),
+            owner: origin,
+            team,
+        }),
+        _ => None,
+    }
 }
 
 pub fn can_attack(
@@ -81,6 +193,34 @@ pub fn can_attack(
     true
 }
 
+/// The closest entity that `this` can attack, no further than `range` away.
+pub fn nearest_enemy(
+    this: specs::Entity,
+    range: f64,
+    entities: &specs::Entities,
+    idc: &RS<EntityID>,
+    positionc: &RS<Position>,
+    hitboxc: &RS<Hitbox>,
+    teamc: &RS<Team>,
+    hitpointsc: &RS<Hitpoints>,
+) -> Option<EntityID> {
+    let position = match positionc.get(this) {
+        Some(position) => position.point,
+        None => return None,
+    };
+    let hitbox = hitboxc.get(this);
+
+    (&**entities, idc, positionc)
+        .join()
+        .filter(|&(e, _, _)| e != this && can_attack(this, e, teamc, hitpointsc))
+        .map(|(e, &id, p)| {
+            (id, shortest_distance_between(position, p.point, hitbox, hitboxc.get(e)))
+        })
+        .filter(|&(_, d)| d <= range)
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(id, _)| id)
+}
+
 pub fn shortest_distance_between(
     this_point: Point,
     other_point: Point,
@@ -3,6 +3,9 @@ pub const DEFAULT_PORT: u16 = 26137;
 mod stream;
 pub use self::stream::*;
 
+mod auth;
+pub use self::auth::*;
+
 mod game;
 pub use self::game::*;
 
@@ -12,6 +15,9 @@ pub use self::component::*;
 mod system;
 pub use self::system::*;
 
+mod history;
+pub use self::history::*;
+
 mod event;
 pub use self::event::*;
 
@@ -21,4 +27,19 @@ pub use self::vector::*;
 mod command;
 pub use self::command::*;
 
+mod discovery;
+pub use self::discovery::*;
+
+mod reliable;
+pub use self::reliable::*;
+
+mod udp;
+pub use self::udp::*;
+
+mod conditioner;
+pub use self::conditioner::*;
+
+mod tls;
+pub use self::tls::*;
+
 pub mod logic;
@@ -0,0 +1,598 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read};
+use std::mem;
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// Starts every packet, so that stray datagrams are ignored.
+const MAGIC: u32 = 0x6d6f_6261;
+
+const DATA: u8 = 0;
+const DISCONNECT: u8 = 1;
+
+const RELIABLE: u8 = 0;
+const UNRELIABLE: u8 = 1;
+
+/// Small enough to not be fragmented on any sensible network.
+pub const MAX_PACKET_SIZE: usize = 1200;
+/// Magic, kind, sequence, ack and ack bits.
+const HEADER_SIZE: usize = 17;
+/// Channel, message ID, whether more fragments follow, and length.
+const ENTRY_HEADER_SIZE: usize = 8;
+const MAX_FRAGMENT_SIZE: usize = MAX_PACKET_SIZE - HEADER_SIZE - ENTRY_HEADER_SIZE;
+
+/// Reliable messages further ahead of the next one expected than this are
+/// dropped. The packet isn't acked, so they're resent.
+const MAX_RELIABLE_AHEAD: u32 = 4096;
+/// Without hearing from the other end for this long, the connection is dead.
+const TIMEOUT_MS: u64 = 10_000;
+/// Packets are sent at least this often, so that the other end knows we're
+/// still here.
+const KEEPALIVE_MS: u64 = 500;
+const MIN_RESEND_MS: u64 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Delivered exactly once, in the order sent.
+    Reliable,
+    /// May be lost, and a message is dropped if a newer one has already
+    /// arrived. Messages too big for one packet are sent reliably instead.
+    Unreliable,
+}
+
+struct Outgoing {
+    payload: Vec<u8>,
+    /// More fragments of the same message follow.
+    more: bool,
+    last_sent: Option<Instant>,
+}
+
+/// One end of a connection over an unreliable transport. This only builds and
+/// reads packets; sending and receiving them is up to the owner.
+///
+/// Sequence numbers and message IDs are never wrapped, which at 60 packets a
+/// second is years away.
+pub struct UdpEndpoint {
+    /// Of the next packet sent. 0 is used to mean no packet.
+    sequence: u32,
+    /// The newest packet received.
+    remote_sequence: u32,
+    /// Bit `n` is set if packet `remote_sequence - n - 1` was received.
+    received_bits: u32,
+    /// We've received something that hasn't been acked yet.
+    ack_pending: bool,
+    /// When each packet was sent, and the reliable messages in it.
+    sent_packets: HashMap<u32, (Instant, Vec<u32>)>,
+
+    reliable_out: BTreeMap<u32, Outgoing>,
+    next_reliable_out: u32,
+    reliable_in: BTreeMap<u32, (bool, Vec<u8>)>,
+    next_reliable_in: u32,
+    /// The fragments so far of a reliable message that was split up.
+    partial: Vec<u8>,
+
+    unreliable_out: Vec<(u32, Vec<u8>)>,
+    next_unreliable_out: u32,
+    newest_unreliable_in: Option<u32>,
+
+    last_sent: Option<Instant>,
+    last_received: Instant,
+    /// Smoothed round trip time, in seconds.
+    rtt: Option<f64>,
+    disconnected: bool,
+}
+
+impl UdpEndpoint {
+    pub fn new(now: Instant) -> Self {
+        UdpEndpoint {
+            sequence: 1,
+            remote_sequence: 0,
+            received_bits: 0,
+            ack_pending: false,
+            sent_packets: HashMap::new(),
+            reliable_out: BTreeMap::new(),
+            next_reliable_out: 0,
+            reliable_in: BTreeMap::new(),
+            next_reliable_in: 0,
+            partial: Vec::new(),
+            unreliable_out: Vec::new(),
+            next_unreliable_out: 0,
+            newest_unreliable_in: None,
+            last_sent: None,
+            last_received: now,
+            rtt: None,
+            disconnected: false,
+        }
+    }
+
+    /// Queues a message, to go out with the next `packets`.
+    pub fn send(&mut self, channel: Channel, payload: Vec<u8>) {
+        if channel == Channel::Unreliable && payload.len() <= MAX_FRAGMENT_SIZE {
+            let id = self.next_unreliable_out;
+            self.next_unreliable_out += 1;
+            self.unreliable_out.push((id, payload));
+            return;
+        }
+
+        let count = ((payload.len() + MAX_FRAGMENT_SIZE - 1) / MAX_FRAGMENT_SIZE).max(1);
+        for i in 0..count {
+            let start = i * MAX_FRAGMENT_SIZE;
+            let end = (start + MAX_FRAGMENT_SIZE).min(payload.len());
+            let id = self.next_reliable_out;
+            self.next_reliable_out += 1;
+            self.reliable_out.insert(
+                id,
+                Outgoing {
+                    payload: payload[start..end].to_vec(),
+                    more: i + 1 < count,
+                    last_sent: None,
+                },
+            );
+        }
+    }
+
+    /// Reads a packet from the other end, returning the messages it completes
+    /// in the order they should be handled.
+    pub fn receive(&mut self, packet: &[u8], now: Instant) -> io::Result<Vec<Vec<u8>>> {
+        let mut r = Cursor::new(packet);
+        if r.read_u32::<BigEndian>()? != MAGIC {
+            return Err(invalid("not a moba packet"));
+        }
+
+        match r.read_u8()? {
+            DATA => {}
+            DISCONNECT => {
+                self.disconnected = true;
+                return Ok(Vec::new());
+            }
+            _ => return Err(invalid("unknown packet kind")),
+        }
+
+        let sequence = r.read_u32::<BigEndian>()?;
+        let ack = r.read_u32::<BigEndian>()?;
+        let ack_bits = r.read_u32::<BigEndian>()?;
+
+        let mut unreliable = Vec::new();
+        let mut dropped = false;
+        while (r.position() as usize) < packet.len() {
+            let channel = r.read_u8()?;
+            let id = r.read_u32::<BigEndian>()?;
+            let more = r.read_u8()? != 0;
+            let len = r.read_u16::<BigEndian>()? as usize;
+            let mut payload = vec![0; len];
+            r.read_exact(&mut payload)?;
+
+            match channel {
+                RELIABLE => {
+                    if id >= self.next_reliable_in {
+                        if id - self.next_reliable_in < MAX_RELIABLE_AHEAD {
+                            self.reliable_in.insert(id, (more, payload));
+                        } else {
+                            dropped = true;
+                        }
+                    }
+                }
+                UNRELIABLE => {
+                    if self.newest_unreliable_in.map_or(true, |newest| id > newest) {
+                        self.newest_unreliable_in = Some(id);
+                        unreliable.push(payload);
+                    }
+                }
+                _ => return Err(invalid("unknown channel")),
+            }
+        }
+
+        // only once the whole packet made sense
+        self.last_received = now;
+        if !dropped {
+            self.record_received(sequence);
+        }
+        self.record_acks(ack, ack_bits, now);
+
+        let mut messages = Vec::new();
+        loop {
+            let next = self.next_reliable_in;
+            let (more, payload) = match self.reliable_in.remove(&next) {
+                Some(fragment) => fragment,
+                None => break,
+            };
+            self.next_reliable_in += 1;
+            self.partial.extend_from_slice(&payload);
+            if !more {
+                messages.push(mem::replace(&mut self.partial, Vec::new()));
+            }
+        }
+        // after reliable ones, which may add what these refer to
+        messages.extend(unreliable);
+
+        Ok(messages)
+    }
+
+    fn record_received(&mut self, sequence: u32) {
+        if sequence > self.remote_sequence {
+            let shift = (sequence - self.remote_sequence) as u64;
+            self.received_bits = if shift > 32 || self.remote_sequence == 0 {
+                0
+            } else {
+                (((self.received_bits as u64) << shift) | (1 << (shift - 1))) as u32
+            };
+            self.remote_sequence = sequence;
+        } else {
+            let age = self.remote_sequence - sequence;
+            if age >= 1 && age <= 32 {
+                self.received_bits |= 1 << (age - 1);
+            }
+        }
+        self.ack_pending = true;
+    }
+
+    fn record_acks(&mut self, ack: u32, ack_bits: u32, now: Instant) {
+        if ack == 0 {
+            return;
+        }
+
+        self.acked(ack, now);
+        for i in 0..32 {
+            if ack_bits & (1 << i) != 0 && ack > i + 1 {
+                self.acked(ack - i - 1, now);
+            }
+        }
+
+        // older packets can't be acked any more, but their reliable messages
+        // are resent on a timer anyway
+        let oldest = ack.saturating_sub(32);
+        self.sent_packets.retain(|&sequence, _| sequence >= oldest);
+    }
+
+    fn acked(&mut self, sequence: u32, now: Instant) {
+        if let Some((sent, messages)) = self.sent_packets.remove(&sequence) {
+            let sample = secs(now.duration_since(sent));
+            self.rtt = Some(match self.rtt {
+                Some(rtt) => rtt * 0.9 + sample * 0.1,
+                None => sample,
+            });
+
+            for id in messages {
+                self.reliable_out.remove(&id);
+            }
+        }
+    }
+
+    /// The packets to send now: queued messages, reliable ones that haven't
+    /// been acked in time, and acks or keepalives when there's nothing else.
+    pub fn packets(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let resend_after = self.resend_after();
+        let mut due = self.reliable_out
+            .iter()
+            .filter(|&(_, outgoing)| {
+                outgoing
+                    .last_sent
+                    .map_or(true, |sent| now.duration_since(sent) >= resend_after)
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<u32>>()
+            .into_iter()
+            .peekable();
+        let mut unreliable = mem::replace(&mut self.unreliable_out, Vec::new())
+            .into_iter()
+            .peekable();
+
+        let mut packets = Vec::new();
+        loop {
+            let mut packet = self.header(DATA);
+            let mut reliable = Vec::new();
+
+            while let Some(&id) = due.peek() {
+                let outgoing = self.reliable_out.get_mut(&id).unwrap();
+                if packet.len() + ENTRY_HEADER_SIZE + outgoing.payload.len() > MAX_PACKET_SIZE {
+                    break;
+                }
+                write_entry(&mut packet, RELIABLE, id, outgoing.more, &outgoing.payload);
+                outgoing.last_sent = Some(now);
+                reliable.push(id);
+                due.next();
+            }
+
+            while let Some(len) = unreliable.peek().map(|&(_, ref payload)| payload.len()) {
+                if packet.len() + ENTRY_HEADER_SIZE + len > MAX_PACKET_SIZE {
+                    break;
+                }
+                let (id, payload) = unreliable.next().unwrap();
+                write_entry(&mut packet, UNRELIABLE, id, false, &payload);
+            }
+
+            if packet.len() == HEADER_SIZE {
+                let keepalive = self.last_sent.map_or(true, |sent| {
+                    now.duration_since(sent) >= Duration::from_millis(KEEPALIVE_MS)
+                });
+                if packets.is_empty() && (self.ack_pending || keepalive) {
+                    packets.push(self.sent(packet, reliable, now));
+                }
+                return packets;
+            }
+
+            packets.push(self.sent(packet, reliable, now));
+        }
+    }
+
+    fn header(&self, kind: u8) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+        packet.write_u32::<BigEndian>(MAGIC).unwrap();
+        packet.write_u8(kind).unwrap();
+        packet.write_u32::<BigEndian>(self.sequence).unwrap();
+        packet.write_u32::<BigEndian>(self.remote_sequence).unwrap();
+        packet.write_u32::<BigEndian>(self.received_bits).unwrap();
+        packet
+    }
+
+    fn sent(&mut self, packet: Vec<u8>, reliable: Vec<u32>, now: Instant) -> Vec<u8> {
+        self.sent_packets.insert(self.sequence, (now, reliable));
+        self.sequence += 1;
+        self.last_sent = Some(now);
+        self.ack_pending = false;
+        packet
+    }
+
+    /// Tells the other end we're going away. Best effort, as it isn't resent.
+    pub fn disconnect_packet(&self) -> Vec<u8> {
+        self.header(DISCONNECT)
+    }
+
+    fn resend_after(&self) -> Duration {
+        let rtt_ms = self.rtt.map_or(0, |rtt| (rtt * 2000.0) as u64);
+        Duration::from_millis(rtt_ms.max(MIN_RESEND_MS))
+    }
+
+    /// Round trip time in seconds, once a packet has been acked.
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    /// The other end said it was going away.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        now.duration_since(self.last_received) >= Duration::from_millis(TIMEOUT_MS)
+    }
+
+    /// The size of the reliable messages that haven't been acked yet.
+    pub fn unacked_bytes(&self) -> usize {
+        self.reliable_out.values().map(|outgoing| outgoing.payload.len()).sum()
+    }
+
+    /// Everything sent has been acked, or was unreliable and has gone out.
+    pub fn is_idle(&self) -> bool {
+        self.reliable_out.is_empty() && self.unreliable_out.is_empty()
+    }
+}
+
+/// Whether `packet` looks like it's meant for a `UdpEndpoint`.
+pub fn is_udp_packet(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_SIZE && Cursor::new(packet).read_u32::<BigEndian>().ok() == Some(MAGIC)
+}
+
+fn write_entry(packet: &mut Vec<u8>, channel: u8, id: u32, more: bool, payload: &[u8]) {
+    packet.write_u8(channel).unwrap();
+    packet.write_u32::<BigEndian>(id).unwrap();
+    packet.write_u8(more as u8).unwrap();
+    packet.write_u16::<BigEndian>(payload.len() as u16).unwrap();
+    packet.extend_from_slice(payload);
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use common::{Conditioner, LinkConditions};
+    use super::*;
+
+    const STEP_MS: u64 = 10;
+
+    /// Two endpoints talking over simulated networks, on a simulated clock.
+    struct Link {
+        now: Instant,
+        a: UdpEndpoint,
+        b: UdpEndpoint,
+        a_to_b: Conditioner<Vec<u8>>,
+        b_to_a: Conditioner<Vec<u8>>,
+        /// What each end has received.
+        at_a: Vec<Vec<u8>>,
+        at_b: Vec<Vec<u8>>,
+    }
+
+    impl Link {
+        fn new(conditions: LinkConditions) -> Self {
+            let now = Instant::now();
+            Link {
+                now,
+                a: UdpEndpoint::new(now),
+                b: UdpEndpoint::new(now),
+                a_to_b: Conditioner::new(conditions.clone(), false),
+                b_to_a: Conditioner::new(conditions, false),
+                at_a: Vec::new(),
+                at_b: Vec::new(),
+            }
+        }
+
+        fn step(&mut self) {
+            self.now += Duration::from_millis(STEP_MS);
+            let now = self.now;
+
+            for packet in self.a.packets(now) {
+                let len = packet.len();
+                self.a_to_b.push(packet, len, now);
+            }
+            for packet in self.b.packets(now) {
+                let len = packet.len();
+                self.b_to_a.push(packet, len, now);
+            }
+
+            for packet in self.a_to_b.pop_due(now) {
+                let messages = self.b.receive(&packet, now).unwrap();
+                self.at_b.extend(messages);
+            }
+            for packet in self.b_to_a.pop_due(now) {
+                let messages = self.a.receive(&packet, now).unwrap();
+                self.at_a.extend(messages);
+            }
+        }
+
+        /// Until both ends are idle, or a simulated minute has passed.
+        fn settle(&mut self) {
+            for _ in 0..60_000 / STEP_MS {
+                self.step();
+                if self.a.is_idle() && self.b.is_idle() && self.a_to_b.next_due().is_none() &&
+                    self.b_to_a.next_due().is_none()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn bad_network() -> LinkConditions {
+        LinkConditions {
+            latency_ms: 50,
+            jitter_ms: 40,
+            loss: 0.2,
+            duplicate: 0.1,
+            bandwidth_kbps: None,
+        }
+    }
+
+    /// Numbered, and some big enough to be split into fragments.
+    fn messages(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| {
+                let len = if i % 10 == 0 { MAX_PACKET_SIZE * 3 } else { 4 };
+                let mut message = vec![(i % 251) as u8; len];
+                message[0] = (i / 251) as u8;
+                message
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reliable_messages_arrive_once_and_in_order() {
+        let mut link = Link::new(bad_network());
+        let sent = messages(200);
+        for message in &sent {
+            link.a.send(Channel::Reliable, message.clone());
+            link.step();
+        }
+        link.settle();
+
+        assert_eq!(link.at_b, sent);
+        assert!(link.a.is_idle());
+    }
+
+    #[test]
+    fn reliable_messages_go_both_ways() {
+        let mut link = Link::new(bad_network());
+        let sent = messages(50);
+        for message in &sent {
+            link.a.send(Channel::Reliable, message.clone());
+            link.b.send(Channel::Reliable, message.clone());
+            link.step();
+        }
+        link.settle();
+
+        assert_eq!(link.at_b, sent);
+        assert_eq!(link.at_a, sent);
+    }
+
+    #[test]
+    fn unreliable_messages_never_go_backwards() {
+        let mut link = Link::new(bad_network());
+        for i in 0..500u32 {
+            link.a.send(Channel::Unreliable, vec![(i >> 8) as u8, i as u8]);
+            link.step();
+        }
+        link.settle();
+
+        let received = link.at_b
+            .iter()
+            .map(|m| (m[0] as u32) << 8 | m[1] as u32)
+            .collect::<Vec<u32>>();
+        assert!(!received.is_empty());
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn everything_arrives_on_a_perfect_network() {
+        let mut link = Link::new(LinkConditions::default());
+        for i in 0..100u8 {
+            link.a.send(Channel::Unreliable, vec![i]);
+            link.step();
+        }
+        link.settle();
+
+        assert_eq!(link.at_b, (0..100u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn too_big_unreliable_messages_are_sent_reliably() {
+        let mut link = Link::new(bad_network());
+        let big = vec![7; MAX_PACKET_SIZE * 2];
+        link.a.send(Channel::Unreliable, big.clone());
+        link.settle();
+
+        assert_eq!(link.at_b, vec![big]);
+    }
+
+    #[test]
+    fn reliable_messages_too_far_ahead_are_resent() {
+        let mut link = Link::new(LinkConditions::default());
+        let count = MAX_RELIABLE_AHEAD as usize + 100;
+        for i in 0..count {
+            link.a.send(Channel::Reliable, vec![(i >> 8) as u8, i as u8]);
+        }
+
+        // lose the first packet, so that everything else is ahead of what b expects
+        let now = link.now;
+        let mut packets = link.a.packets(now).into_iter();
+        packets.next();
+        for packet in packets {
+            let messages = link.b.receive(&packet, now).unwrap();
+            assert!(messages.is_empty());
+        }
+        link.settle();
+
+        assert_eq!(link.at_b.len(), count);
+        for (i, message) in link.at_b.iter().enumerate() {
+            assert_eq!(*message, vec![(i >> 8) as u8, i as u8]);
+        }
+    }
+
+    #[test]
+    fn disconnects_are_noticed() {
+        let mut link = Link::new(LinkConditions::default());
+        let packet = link.a.disconnect_packet();
+        link.b.receive(&packet, link.now).unwrap();
+        assert!(link.b.is_disconnected());
+    }
+
+    #[test]
+    fn silence_times_out() {
+        let now = Instant::now();
+        let endpoint = UdpEndpoint::new(now);
+        assert!(!endpoint.timed_out(now + Duration::from_millis(TIMEOUT_MS - 1)));
+        assert!(endpoint.timed_out(now + Duration::from_millis(TIMEOUT_MS)));
+    }
+
+    #[test]
+    fn stray_packets_are_rejected() {
+        let mut endpoint = UdpEndpoint::new(Instant::now());
+        assert!(endpoint.receive(b"not a moba packet at all", Instant::now()).is_err());
+        assert!(!is_udp_packet(b"short"));
+    }
+}
@@ -1,83 +1,402 @@
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use std::sync::{Arc, Mutex};
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 use std::io::{self, Read, Write};
 use std::thread;
+use std::marker::PhantomData;
+use std::fmt;
 use chan;
-use common::{Command, EntityID, Event, Team};
+use rustls;
+use common::{Channel, Command, DelayLine, EntityID, Event, LinkConditions, PlayerID, Team,
+             TlsLink, TlsReader, UdpLink};
 
-// XXX: separate into client->server and server->client
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MatchID(pub u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChatChannel {
+    All,
+    Team,
+    Whisper,
+    /// Server announcements and command replies.
+    System,
+}
+
+/// Bumped whenever a change to the messages would confuse older builds.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The server supports `ClientMessage::Pause` and `Unpause`.
+pub const CAPABILITY_PAUSE: &str = "pause";
+/// The server supports `Command::Queue`.
+pub const CAPABILITY_ORDER_QUEUE: &str = "order_queue";
+/// The client answers `ServerMessage::Ping`, so the server can measure its
+/// latency for lag compensation.
+pub const CAPABILITY_SERVER_PING: &str = "server_ping";
+/// The client understands `ServerMessage::NetStats`.
+pub const CAPABILITY_NET_STATS: &str = "net_stats";
+/// The client answers `ServerMessage::AuthChallenge`, so it can sign in to a
+/// registered name.
+pub const CAPABILITY_AUTH: &str = "auth";
+/// The client understands `Event::SetCooldowns`.
+pub const CAPABILITY_COOLDOWNS: &str = "cooldowns";
+
+/// Optional features this build supports. The client sends its list when it
+/// connects, and the server replies with the ones they have in common.
+/// Capabilities are strings rather than an enum so that builds can ignore
+/// ones they don't know.
+pub const CAPABILITIES: &[&str] = &[
+    CAPABILITY_PAUSE,
+    CAPABILITY_ORDER_QUEUE,
+    CAPABILITY_SERVER_PING,
+    CAPABILITY_NET_STATS,
+    CAPABILITY_AUTH,
+    CAPABILITY_COOLDOWNS,
+];
+
+/// The capabilities in `theirs` that this build supports too.
+pub fn common_capabilities(theirs: &[String]) -> Vec<String> {
+    theirs
+        .iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// Sent from the client to the server.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub enum Message {
-    Connect { name: String, team: Option<Team> },
-    AcceptConnection { message: String },
+pub enum ClientMessage {
+    /// `match_id` of `None` puts the player into the matchmaking queue.
+    /// Clients from before the protocol was versioned send no version, and
+    /// are read as version 0.
+    Connect {
+        #[serde(default)]
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        name: String,
+        team: Option<Team>,
+        match_id: Option<MatchID>,
+    },
     Ping { id: u64 },
+    /// Answers `ServerMessage::Ping`.
     ReturnPing { id: u64 },
-    Kick { reason: String },
+    /// Answers `ServerMessage::AuthChallenge` with `auth_proof`, or `None`
+    /// if we have no token.
+    AuthResponse { proof: Option<String> },
+    /// Sent first, before `Connect`, to switch the connection to TLS.
+    StartTls,
     Quit,
-    SendChat { message: String },
-    ReceiveChat { user: String, message: String },
+    SendChat {
+        message: String,
+        channel: ChatChannel,
+    },
     Command(Command),
+    Pause,
+    Unpause,
+}
+
+/// Sent from the server to the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerMessage {
+    /// `capabilities` are the ones both sides support. `player_id` is set if
+    /// the client signed in.
+    AcceptConnection {
+        message: String,
+        #[serde(default)]
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        #[serde(default)]
+        player_id: Option<PlayerID>,
+    },
+    /// Sent instead of accepting a client with `CAPABILITY_AUTH` that wants a
+    /// registered name, which must prove it has the name's token.
+    AuthChallenge { nonce: String },
+    /// Agrees to `ClientMessage::StartTls`. Everything after this, both ways,
+    /// goes over TLS.
+    StartTls,
+    ReturnPing { id: u64 },
+    /// Only sent to clients with `CAPABILITY_SERVER_PING`.
+    Ping { id: u64 },
+    /// Also used to turn away clients that can't join, e.g. because their
+    /// protocol version doesn't match, and to end a match someone surrendered.
+    Kick { reason: String },
+    ReceiveChat {
+        user: String,
+        message: String,
+        channel: ChatChannel,
+    },
+    /// `remaining_budget` is the pausing team's (or player's, without teams)
+    /// pause time left, in seconds, or `None` if an admin paused.
+    Paused {
+        by: String,
+        remaining_budget: Option<u32>,
+    },
+    UnpauseCountdown { seconds: u32 },
+    Resumed,
     CommandByPlayer { command: Command, player: EntityID },
     Events(Vec<Event>),
     SetPlayerEntityID(EntityID),
+    /// Every player's connection, sent now and then to clients with
+    /// `CAPABILITY_NET_STATS`.
+    NetStats(Vec<(EntityID, NetStats)>),
+}
+
+/// How a player's connection is doing, as measured by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetStats {
+    /// Round trip time, once the client has answered a ping.
+    pub rtt_ms: Option<u32>,
+    /// How much the round trip time varies.
+    pub jitter_ms: Option<u32>,
+    /// Messages over TCP, datagrams over UDP.
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Waiting to be sent, or over UDP, to be acknowledged.
+    pub queued_bytes: u64,
+}
+
+impl fmt::Display for NetStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.rtt_ms, self.jitter_ms) {
+            (Some(rtt), Some(jitter)) => write!(f, "ping {}ms (jitter {}ms)", rtt, jitter)?,
+            _ => write!(f, "ping unknown")?,
+        }
+        write!(
+            f,
+            ", {}/{} packets and {}/{} KB in/out, {} KB queued",
+            self.packets_in,
+            self.packets_out,
+            self.bytes_in / 1024,
+            self.bytes_out / 1024,
+            self.queued_bytes / 1024
+        )
+    }
+}
+
+/// Something that can be sent over a `Stream`.
+pub trait Message: Serialize + DeserializeOwned + Send + 'static {
+    /// True if the sender won't send anything after this.
+    fn is_last(&self) -> bool {
+        false
+    }
+
+    /// Which channel the message goes over when the transport has a choice.
+    fn channel(&self) -> Channel {
+        Channel::Reliable
+    }
 }
 
+impl Message for ClientMessage {
+    fn is_last(&self) -> bool {
+        match *self {
+            ClientMessage::Quit => true,
+            _ => false,
+        }
+    }
+}
+
+impl Message for ServerMessage {
+    /// Positions and cooldowns are sent again, so losing some doesn't
+    /// matter. See `Event::is_superseded`.
+    fn channel(&self) -> Channel {
+        match *self {
+            ServerMessage::Events(ref events)
+                if !events.is_empty() && events.iter().all(Event::is_superseded) => {
+                Channel::Unreliable
+            }
+            _ => Channel::Reliable,
+        }
+    }
+}
+
+/// What TCP messages are written to.
 #[derive(Clone)]
-pub struct Stream {
-    reader: Arc<Mutex<TcpStream>>,
-    writer: Arc<Mutex<TcpStream>>,
-    incoming: chan::Receiver<io::Result<Message>>,
+enum Transport {
+    Plain(Arc<Mutex<TcpStream>>),
+    Tls(Arc<TlsLink>),
 }
 
-fn read_packet(stream: &mut TcpStream) -> io::Result<String> {
+#[derive(Clone)]
+enum Writer {
+    /// With a delay line if the link is being conditioned.
+    Tcp(Transport, Option<DelayLine<String>>),
+    Udp(Arc<UdpLink>),
+}
+
+/// Reads `R`s and writes `W`s, over TCP or UDP.
+pub struct Stream<R, W> {
+    writer: Writer,
+    incoming: chan::Receiver<io::Result<R>>,
+    _writes: PhantomData<W>,
+}
+
+/// The client's end of a connection.
+pub type ClientStream = Stream<ServerMessage, ClientMessage>;
+/// The server's end of a connection.
+pub type ServerStream = Stream<ClientMessage, ServerMessage>;
+
+// derived Clone would need R and W to be Clone
+impl<R, W> Clone for Stream<R, W> {
+    fn clone(&self) -> Self {
+        Stream {
+            writer: self.writer.clone(),
+            incoming: self.incoming.clone(),
+            _writes: PhantomData,
+        }
+    }
+}
+
+fn read_packet<T: Read>(stream: &mut T) -> io::Result<String> {
     let size = stream.read_u32::<BigEndian>()?;
 
     let mut buf = vec![0; size as usize];
 
     stream.read_exact(&mut buf)?;
 
-    Ok(String::from_utf8(buf).unwrap()) // XXX
+    String::from_utf8(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
 }
 
-impl Stream {
+impl<R: Message, W: Message> Stream<R, W> {
     pub fn new(inner: TcpStream) -> Self {
-        let (send, recv) = chan::sync(32);
+        Stream::with_conditions(inner, LinkConditions::default())
+    }
 
+    /// Holds messages back in both directions, as if sent over a network
+    /// with the given conditions.
+    pub fn with_conditions(inner: TcpStream, conditions: LinkConditions) -> Self {
         inner.set_nodelay(true).unwrap();
-        let stream = Stream {
-            writer: Arc::new(Mutex::new(inner.try_clone().unwrap())),
-            reader: Arc::new(Mutex::new(inner)),
-            incoming: recv,
+        let writer = Transport::Plain(Arc::new(Mutex::new(inner.try_clone().unwrap())));
+        Stream::over_tcp(writer, inner, conditions)
+    }
+
+    /// Over TLS, once both ends have agreed to it, see `negotiate_tls`. Only
+    /// a server presenting the certificate `config` trusts is talked to.
+    pub fn with_tls(
+        inner: TcpStream,
+        config: &Arc<rustls::ClientConfig>,
+        conditions: LinkConditions,
+    ) -> Self {
+        inner.set_nodelay(true).unwrap();
+        let link = Arc::new(TlsLink::new(config, inner.try_clone().unwrap()));
+        let reader = TlsReader::new(link.clone(), inner);
+        Stream::over_tcp(Transport::Tls(link), reader, conditions)
+    }
+
+    fn over_tcp<T>(writer: Transport, mut reader: T, conditions: LinkConditions) -> Self
+    where
+        T: Read + Send + 'static,
+    {
+        let (send, recv) = chan::sync(32);
+
+        let delay = if conditions.is_perfect() {
+            None
+        } else {
+            let writer = writer.clone();
+            Some(DelayLine::spawn(conditions.clone(), true, move |packet: String| {
+                // a failed write shows up as the connection closing
+                let _ = write_packet(&writer, &packet);
+            }))
         };
 
+        thread::spawn(move || {
+            let mut deliver = move |packet: io::Result<String>| {
+                let packet = packet.and_then(|packet| decode_message::<R>(&packet));
+
+                let last = match packet {
+                    Ok(ref message) => message.is_last(),
+                    Err(_) => true,
+                };
+                send.send(packet);
+                !last
+            };
+
+            if conditions.is_perfect() {
+                while deliver(read_packet(&mut reader)) {}
+                return;
+            }
+
+            // io::Error can't be cloned, so errors are held back as strings
+            let delayed = DelayLine::spawn(conditions, true, move |packet: Result<String, String>| {
+                deliver(packet.map_err(|err| {
+                    io::Error::new(io::ErrorKind::ConnectionAborted, err)
+                }));
+            });
+            loop {
+                let packet = read_packet(&mut reader);
+                let size = packet.as_ref().map(|packet| packet.len() + 4).unwrap_or(0);
+                let failed = packet.is_err();
+                delayed.push(packet.map_err(|err| err.to_string()), size);
+                if failed {
+                    return;
+                }
+            }
+        });
+
+        Stream {
+            writer: Writer::Tcp(writer, delay),
+            incoming: recv,
+            _writes: PhantomData,
+        }
+    }
+
+    /// Over UDP, so that lost packets only hold up reliable messages. The
+    /// connection only fails once the other end doesn't answer.
+    pub fn connect_udp(addr: SocketAddr, conditions: LinkConditions) -> io::Result<Self> {
+        let (send, recv) = chan::sync(32);
+
+        let link = Arc::new(UdpLink::connect(addr, conditions)?);
         {
-            let reader = stream.reader.clone();
-            thread::spawn(move || loop {
-                let packet = read_packet(&mut reader.lock().unwrap());
-                let packet = packet.map(|packet| decode_message(&packet));
-
-                match packet {
-                    Ok(Message::Quit) |
-                    Err(_) => {
-                        send.send(packet);
-                        // send.send(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
-                        return;
-                    }
-                    _ => send.send(packet),
+            let link = link.clone();
+            thread::spawn(move || {
+                let mut stopped = false;
+                let err = UdpLink::run(&link, |payload| {
+                    let message = String::from_utf8(payload)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                        .and_then(|s| decode_message::<R>(&s));
+
+                    let last = match message {
+                        Ok(ref message) => message.is_last(),
+                        Err(_) => true,
+                    };
+                    send.send(message);
+                    stopped = last;
+                    !last
+                });
+                if !stopped {
+                    send.send(Err(err));
                 }
             });
         }
 
-        stream
+        Ok(Stream {
+            writer: Writer::Udp(link),
+            incoming: recv,
+            _writes: PhantomData,
+        })
     }
 
-    pub fn write_message(&mut self, message: Message) -> io::Result<()> {
-        self.write_packet(&encode_message(message))
+    pub fn write_message(&mut self, message: W) -> io::Result<()> {
+        match self.writer {
+            Writer::Tcp(_, Some(ref delay)) => {
+                let packet = encode_message(&message);
+                let size = packet.len() + 4;
+                delay.push(packet, size);
+                Ok(())
+            }
+            Writer::Tcp(ref writer, None) => write_packet(writer, &encode_message(&message)),
+            Writer::Udp(ref link) => {
+                link.send(message.channel(), encode_message(&message).into_bytes())
+            }
+        }
     }
 
-    pub fn try_get_message(&self) -> Option<io::Result<Message>> {
+    pub fn try_get_message(&self) -> Option<io::Result<R>> {
         let inc = &self.incoming;
         chan_select! {
             default => return None,
@@ -85,31 +404,52 @@ impl Stream {
         };
     }
 
-    pub fn get_message(&self) -> io::Result<Message> {
+    pub fn get_message(&self) -> io::Result<R> {
         self.incoming.recv().unwrap()
     }
+}
 
-    fn write_packet(&mut self, s: &str) -> io::Result<()> {
-        let mut writer = self.writer.lock().unwrap();
+fn write_packet(writer: &Transport, s: &str) -> io::Result<()> {
+    assert!(s.len() <= u32::max_value() as usize);
 
-        assert!(s.len() <= u32::max_value() as usize);
+    match *writer {
+        Transport::Plain(ref writer) => {
+            let mut writer = writer.lock().unwrap();
+            writer.write_u32::<BigEndian>(s.len() as u32)?;
+            writer.write_all(s.as_bytes())?;
+        }
+        Transport::Tls(ref link) => {
+            // in one go, so it's one record rather than two
+            let mut packet = Vec::with_capacity(4 + s.len());
+            packet.write_u32::<BigEndian>(s.len() as u32)?;
+            packet.extend_from_slice(s.as_bytes());
+            link.write_all(&packet)?;
+        }
+    }
 
-        writer.write_u32::<BigEndian>(s.len() as u32)?;
+    Ok(())
+}
 
-        writer.write_all(s.as_bytes())?;
+/// Asks the server to switch `stream` to TLS, before anything else is sent.
+/// Fails with the server's reason if it won't.
+pub fn negotiate_tls(stream: &mut TcpStream) -> io::Result<()> {
+    let writer = Transport::Plain(Arc::new(Mutex::new(stream.try_clone()?)));
+    write_packet(&writer, &encode_message(&ClientMessage::StartTls))?;
 
-        Ok(())
+    match decode_message::<ServerMessage>(&read_packet(stream)?)? {
+        ServerMessage::StartTls => Ok(()),
+        ServerMessage::Kick { reason } => {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, reason))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "the server didn't agree to TLS")),
     }
 }
 
-pub fn decode_message(s: &str) -> Message {
-    let message: Message = serde_json::from_str(&s).unwrap();
-    // println!("DESERIALISED: {:?}", message);
-    message
+/// Fails for messages this build doesn't understand, e.g. from a newer one.
+pub fn decode_message<M: Message>(s: &str) -> io::Result<M> {
+    serde_json::from_str(&s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
 }
 
-pub fn encode_message(message: Message) -> String {
-    let s = serde_json::to_string(&message).unwrap();
-    // println!("SERIALISED: {}", s);
-    s
+pub fn encode_message<M: Message>(message: &M) -> String {
+    serde_json::to_string(message).unwrap()
 }
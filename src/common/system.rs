@@ -12,6 +12,12 @@ pub type WS<'a, T> = specs::WriteStorage<'a, T>;
 pub fn register_systems<'a, 'b>(
     d: specs::DispatcherBuilder<'a, 'b>,
 ) -> specs::DispatcherBuilder<'a, 'b> {
+    let d = d.add(AbilityCooldownSystem, "AbilityCooldownSystem", &[]);
+    let d = d.add(
+        QueuedAbilitySystem,
+        "QueuedAbilitySystem",
+        &["AbilityCooldownSystem"],
+    );
     let d = d.add(UpdateVelocitySystem, "UpdateVelocitySystem", &[]);
     let d = d.add(MotionSystem, "MotionSystem", &["UpdateVelocitySystem"]);
     let d = d.add_barrier();
@@ -33,25 +39,32 @@ pub struct ContextInner {
 #[derive(Clone)]
 pub struct Context {
     time: f64,
+    /// Game time at the end of this tick.
+    elapsed: f64,
     inner: Arc<Mutex<ContextInner>>,
     entity_map: Arc<Mutex<HashMap<EntityID, specs::Entity>>>, // should be read only
     next_entity_id: Arc<Mutex<u32>>,
+    history: Option<Arc<Mutex<PositionHistory>>>, // should be read only
 }
 
 impl Context {
     pub fn new(
         time: f64,
+        elapsed: f64,
         entity_map: Arc<Mutex<HashMap<EntityID, specs::Entity>>>,
         next_entity_id: Arc<Mutex<u32>>,
+        history: Option<Arc<Mutex<PositionHistory>>>,
     ) -> Self {
         Context {
             time,
+            elapsed,
             inner: Arc::new(Mutex::new(ContextInner {
                 events: Vec::new(),
                 collisions: HashMap::new(),
             })),
             entity_map,
             next_entity_id,
+            history,
         }
     }
 
@@ -91,6 +104,27 @@ impl Context {
         }
     }
 
+    /// Where each of `ids` was `rewind` seconds ago, leaving out any that
+    /// weren't around then. `None` if nothing was recorded then.
+    pub fn rewound_positions<I>(&self, rewind: f64, ids: I) -> Option<Vec<(EntityID, Point)>>
+    where
+        I: IntoIterator<Item = EntityID>,
+    {
+        let history = match self.history {
+            Some(ref history) => history.lock().unwrap(),
+            None => return None,
+        };
+        let positions = match history.positions_at(self.elapsed - rewind) {
+            Some(positions) => positions,
+            None => return None,
+        };
+        Some(
+            ids.into_iter()
+                .filter_map(|id| positions.get(&id).map(|&point| (id, point)))
+                .collect(),
+        )
+    }
+
     pub fn events(&self) -> Vec<Event> {
         self.inner.lock().unwrap().events.clone()
     }
@@ -108,6 +142,92 @@ impl Context {
     }
 }
 
+#[derive(SystemData)]
+pub struct AbilityCooldownData<'a> {
+    cooldownc: WS<'a, AbilityCooldowns>,
+
+    c: specs::Fetch<'a, Context>,
+}
+
+pub struct AbilityCooldownSystem;
+
+impl<'a> specs::System<'a> for AbilityCooldownSystem {
+    type SystemData = AbilityCooldownData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        for cooldowns in (&mut data.cooldownc).join() {
+            cooldowns.count_down(data.c.time);
+        }
+    }
+}
+
+#[derive(SystemData)]
+pub struct QueuedAbilityData<'a> {
+    unitc: RS<'a, Unit>,
+    playerc: RS<'a, Player>,
+    positionc: RS<'a, Position>,
+    teamc: RS<'a, Team>,
+    idc: RS<'a, EntityID>,
+    cooldownc: WS<'a, AbilityCooldowns>,
+
+    c: specs::Fetch<'a, Context>,
+}
+
+/// Uses abilities that have reached the front of a unit's order queue.
+pub struct QueuedAbilitySystem;
+
+impl<'a> specs::System<'a> for QueuedAbilitySystem {
+    type SystemData = QueuedAbilityData<'a>;
+
+    fn run(&mut self, mut data: Self::SystemData) {
+        for (&id, unit, player, position, cooldowns) in (
+            &data.idc,
+            &data.unitc,
+            &data.playerc,
+            &data.positionc,
+            &mut data.cooldownc,
+        ).join()
+        {
+            let (ability_id, mouse_position) = match unit.current_order() {
+                Some(&Order::UseAbility {
+                         ability_id,
+                         mouse_position,
+                     }) => (ability_id, mouse_position),
+                _ => continue,
+            };
+            let hero = player.hero;
+
+            // an ability on cooldown is waited for, but one that can never be used is skipped
+            let mouse_position = match (hero.ability_cooldown(ability_id), mouse_position) {
+                (Some(_), Some(mouse_position)) => mouse_position,
+                _ => {
+                    data.c.push_event(Event::CompleteOrder(id));
+                    continue;
+                }
+            };
+
+            if !logic::start_cooldown(hero, cooldowns, ability_id) {
+                continue;
+            }
+
+            let entity = data.c.get_entity(id).unwrap();
+            data.c.push_event(Event::AbilityUsed { id, ability_id });
+            if let Some(event) = logic::ability_event(
+                hero,
+                ability_id,
+                id,
+                position.point,
+                data.teamc.get(entity).cloned(),
+                mouse_position,
+                data.c.next_entity_id(),
+            ) {
+                data.c.push_event(event);
+            }
+            data.c.push_event(Event::CompleteOrder(id));
+        }
+    }
+}
+
 #[derive(SystemData)]
 pub struct UpdateVelocityData<'a> {
     unitc: RS<'a, Unit>,
@@ -118,12 +238,18 @@ pub struct UpdateVelocityData<'a> {
     teamc: RS<'a, Team>,
     basic_attackerc: RS<'a, BasicAttacker>,
     hitboxc: RS<'a, Hitbox>,
+    projectilec: RS<'a, Projectile>,
+    entities: specs::Entities<'a>,
 
     c: specs::Fetch<'a, Context>,
 }
 
 pub struct UpdateVelocitySystem;
 
+/// How close a unit must get to a position for the order to be complete, at
+/// least. Units that could reach it this tick arrive straight away.
+const ARRIVAL_DISTANCE: f64 = 1.0;
+
 impl<'a> specs::System<'a> for UpdateVelocitySystem {
     type SystemData = UpdateVelocityData<'a>;
 
@@ -134,15 +260,72 @@ impl<'a> specs::System<'a> for UpdateVelocitySystem {
             (&data.idc, &data.unitc, &mut data.velocityc, &data.positionc).join()
         {
             let speed = unit.speed;
-            let hitbox = data.hitboxc.get(data.c.get_entity(id).unwrap());
+            let entity = data.c.get_entity(id).unwrap();
+            let hitbox = data.hitboxc.get(entity);
+
+            let mode = unit.mode();
 
-            *velocity = match unit.target {
+            if mode == UnitMode::HoldPosition {
+                *velocity = Velocity::new(0.0, 0.0);
+                continue;
+            }
+
+            // on attack-move, enemies near the path take priority over the destination
+            let acquired = match (mode, unit.target()) {
+                (UnitMode::AttackMove, Target::Position(_)) => logic::nearest_enemy(
+                    entity,
+                    logic::ACQUISITION_RANGE,
+                    &data.entities,
+                    &data.idc,
+                    &data.positionc,
+                    &data.hitboxc,
+                    &data.teamc,
+                    &data.hitpointsc,
+                ),
+                _ => None,
+            };
+            let target = match acquired {
+                Some(e) => Target::Entity(e),
+                None => unit.target(),
+            };
+
+            // Moves are complete on arrival and attacks once the target is gone.
+            // Projectiles are removed on impact instead.
+            if data.projectilec.get(entity).is_none() {
+                let complete = match target {
+                    Target::Nothing => false,
+                    Target::Position(p) => {
+                        let distance = position.point.distance_to(p);
+                        let arrived = distance <= (speed * time).max(ARRIVAL_DISTANCE);
+                        if arrived && distance > 0.0 {
+                            // the last step would be too small for MotionSystem
+                            data.c.push_event(Event::EntityMove(id, p));
+                        }
+                        arrived
+                    }
+                    Target::Entity(e) => data.c.get_entity(e).is_none(),
+                };
+
+                if complete {
+                    data.c.push_event(Event::CompleteOrder(id));
+                    *velocity = Velocity::new(0.0, 0.0);
+                    continue;
+                }
+            }
+
+            *velocity = match target {
                 Target::Nothing => Velocity::new(0.0, 0.0),
                 Target::Position(p) => {
                     calculate_velocity(position.point, p, hitbox, None, speed, time, None)
                 }
                 Target::Entity(e) => {
-                    let e = data.c.get_entity(e).unwrap();
+                    let e = match data.c.get_entity(e) {
+                        Some(e) => e,
+                        None => {
+                            *velocity = Velocity::new(0.0, 0.0);
+                            continue;
+                        }
+                    };
                     let target = data.positionc.get(e).unwrap();
 
                     let range = data.basic_attackerc
@@ -246,6 +429,8 @@ pub struct BasicAttackerData<'a> {
     idc: RS<'a, EntityID>,
     basic_attackerc: WS<'a, BasicAttacker>,
     hitboxc: RS<'a, Hitbox>,
+    hitpointsc: RS<'a, Hitpoints>,
+    entities: specs::Entities<'a>,
 
     c: specs::Fetch<'a, Context>,
 }
@@ -280,9 +465,32 @@ impl<'a> specs::System<'a> for BasicAttackerSystem {
                 continue;
             }
 
-            match unit.target {
-                Target::Entity(target_id) => {
-                    let target_e = data.c.get_entity(target_id).unwrap();
+            let range = match unit.mode() {
+                UnitMode::Normal => None,
+                UnitMode::AttackMove => Some(logic::ACQUISITION_RANGE),
+                UnitMode::HoldPosition => Some(basic_attacker.range),
+            };
+            let target = match (unit.target(), range) {
+                (Target::Entity(target_id), _) => Some(target_id),
+                (_, Some(range)) => logic::nearest_enemy(
+                    entity,
+                    range,
+                    &data.entities,
+                    &data.idc,
+                    &data.positionc,
+                    &data.hitboxc,
+                    &data.teamc,
+                    &data.hitpointsc,
+                ),
+                (_, None) => None,
+            };
+
+            match target {
+                Some(target_id) => {
+                    let target_e = match data.c.get_entity(target_id) {
+                        Some(target_e) => target_e,
+                        None => continue,
+                    };
 
                     if basic_attacker.range <
                         logic::shortest_distance_between(
@@ -295,16 +503,19 @@ impl<'a> specs::System<'a> for BasicAttackerSystem {
                     }
 
                     basic_attacker.time_until_next_attack = 1.0 / basic_attacker.attack_speed;
+
+                    let damage = Damage::new(logic::BASIC_ATTACK_DAMAGE, DamageKind::Physical);
+
                     data.c.push_event(Event::AddProjectile {
                         id: data.c.next_entity_id(),
                         position: position.point,
                         target: Target::Entity(target_id),
-                        damage: 5,
+                        damage,
                         team: data.teamc.get(entity).cloned(),
                         owner: id,
                     })
                 }
-                _ => {}
+                None => {}
             }
         }
     }
@@ -317,35 +528,88 @@ pub struct ProjectileData<'a> {
     projectilec: RS<'a, Projectile>,
     teamc: RS<'a, Team>,
     hitpointsc: RS<'a, Hitpoints>,
+    positionc: RS<'a, Position>,
+    hitboxc: RS<'a, Hitbox>,
 
     c: specs::Fetch<'a, Context>,
 }
 
+/// What a skillshot at `point` would have hit `rewind` seconds ago, going by
+/// where everything was then. Falls back to what it hits now if nothing was
+/// recorded that long ago.
+fn rewound_hits(
+    data: &ProjectileData,
+    id: EntityID,
+    point: Point,
+    rewind: f64,
+) -> Vec<EntityID> {
+    // only what could be hit now: anything gone since can't be
+    let targets = (&data.idc, &data.hitboxc)
+        .join()
+        .map(|(&other, _)| other)
+        .filter(|&other| other != id);
+    let positions = match data.c.rewound_positions(rewind, targets) {
+        Some(positions) => positions,
+        None => return data.c.get_collisions(id, None).iter().map(|c| c.obj2).collect(),
+    };
+    let hitbox = data.c.get_entity(id).and_then(|e| data.hitboxc.get(e));
+
+    positions
+        .into_iter()
+        .filter_map(|(other, other_point)| {
+            let other_hitbox = match data.c.get_entity(other).and_then(|e| data.hitboxc.get(e)) {
+                Some(other_hitbox) => other_hitbox,
+                None => return None,
+            };
+            let distance = logic::shortest_distance_between(
+                point,
+                other_point,
+                hitbox,
+                Some(other_hitbox),
+            );
+            if distance <= 0.0 { Some(other) } else { None }
+        })
+        .collect()
+}
+
 pub struct ProjectileSystem;
 
 impl<'a> specs::System<'a> for ProjectileSystem {
     type SystemData = ProjectileData<'a>;
 
     fn run(&mut self, data: Self::SystemData) {
-        for (&id, unit, projectile) in (&data.idc, &data.unitc, &data.projectilec).join() {
-            let target_entity_id = match unit.target {
+        for (&id, unit, projectile, position) in
+            (&data.idc, &data.unitc, &data.projectilec, &data.positionc).join()
+        {
+            let target_entity_id = match unit.target() {
                 Target::Entity(e) => Some(e),
                 _ => None,
             };
 
-            for collision in &data.c.get_collisions(id, target_entity_id) {
-                if projectile.owner == collision.obj2 {
+            // only skillshots are aimed by hand, so only they are rewound
+            let hits = if target_entity_id.is_none() && projectile.rewind > 0.0 {
+                rewound_hits(&data, id, position.point, projectile.rewind)
+            } else {
+                data.c
+                    .get_collisions(id, target_entity_id)
+                    .iter()
+                    .map(|c| c.obj2)
+                    .collect()
+            };
+
+            for &hit in &hits {
+                if projectile.owner == hit {
                     continue;
                 }
 
                 if logic::can_attack(
                     data.c.get_entity(id).unwrap(),
-                    data.c.get_entity(collision.obj2).unwrap(),
+                    data.c.get_entity(hit).unwrap(),
                     &data.teamc,
                     &data.hitpointsc,
                 ) {
                     data.c.push_event(Event::DamageEntity {
-                        id: collision.obj2,
+                        id: hit,
                         damage: projectile.damage,
                     });
                     data.c.push_event(Event::RemoveEntity(id));
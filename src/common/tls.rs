@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use rustls::{self, Certificate, ClientSession, PrivateKey, RootCertStore, ServerCertVerified,
+             ServerCertVerifier, Session, TLSError};
+use rustls::internal::pemfile;
+use webpki;
+
+/// What the client asks for. Certificates are pinned rather than checked
+/// against a name, so this only has to be a valid DNS name, and servers can
+/// be reached by IP address or on loopback.
+const SERVER_NAME: &str = "moba-server";
+
+fn tls_error(err: TLSError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("TLS error: {:?}", err))
+}
+
+fn invalid_file(path: &str, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{} has no {}", path, what))
+}
+
+/// Every certificate in a PEM file.
+pub fn load_certs(path: &str) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(ref certs) if certs.is_empty() => Err(invalid_file(path, "certificates")),
+        Ok(certs) => Ok(certs),
+        Err(()) => Err(invalid_file(path, "readable certificates")),
+    }
+}
+
+/// The first private key in a PEM file, PKCS#8 or RSA.
+pub fn load_private_key(path: &str) -> io::Result<PrivateKey> {
+    let mut pem = Vec::new();
+    File::open(path)?.read_to_end(&mut pem)?;
+
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| invalid_file(path, "private key"))
+}
+
+/// For a server with the certificate chain and key in the given PEM files.
+pub fn server_config(cert_path: &str, key_path: &str) -> io::Result<Arc<rustls::ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config.set_single_cert(certs, key);
+    Ok(Arc::new(config))
+}
+
+/// Only accepts servers presenting this exact certificate, which is how
+/// self-signed certificates are trusted.
+struct PinnedCert(Certificate);
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        match presented_certs.first() {
+            Some(cert) if *cert == self.0 => Ok(ServerCertVerified::assertion()),
+            Some(_) => Err(TLSError::General(
+                "the server's certificate isn't the one we trust".into(),
+            )),
+            None => Err(TLSError::NoCertificatesPresented),
+        }
+    }
+}
+
+/// For a client that trusts only the server certificate in the given PEM file.
+pub fn client_config(cert_path: &str) -> io::Result<Arc<rustls::ClientConfig>> {
+    let cert = load_certs(cert_path)?.remove(0);
+
+    let mut config = rustls::ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(PinnedCert(cert)));
+    Ok(Arc::new(config))
+}
+
+/// Writes out whatever TLS records the session has ready.
+fn write_records<S: Session>(session: &mut S, socket: &mut TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(socket)?;
+    }
+    Ok(())
+}
+
+/// Everything decrypted so far.
+pub fn read_plaintext<S: Session>(session: &mut S, buf: &mut Vec<u8>) -> io::Result<()> {
+    let mut chunk = [0; 4096];
+    loop {
+        match session.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Hands `raw` bytes from the socket to the session.
+pub fn receive_records<S: Session>(session: &mut S, mut raw: &[u8]) -> io::Result<()> {
+    while !raw.is_empty() {
+        session.read_tls(&mut raw)?;
+        session.process_new_packets().map_err(tls_error)?;
+    }
+    Ok(())
+}
+
+/// The client's end of a TLS connection over a blocking socket. The socket is
+/// read by a `TlsReader` on one thread, while messages are written from others.
+pub struct TlsLink {
+    session: Mutex<ClientSession>,
+    socket: Mutex<TcpStream>,
+}
+
+impl TlsLink {
+    /// The handshake happens along with the first write.
+    pub fn new(config: &Arc<rustls::ClientConfig>, socket: TcpStream) -> Self {
+        let name = webpki::DNSNameRef::try_from_ascii_str(SERVER_NAME).unwrap();
+        TlsLink {
+            session: Mutex::new(ClientSession::new(config, name)),
+            socket: Mutex::new(socket),
+        }
+    }
+
+    pub fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        session.write_all(bytes)?;
+        write_records(&mut *session, &mut self.socket.lock().unwrap())
+    }
+}
+
+/// Reads decrypted bytes from a `TlsLink`.
+pub struct TlsReader {
+    link: Arc<TlsLink>,
+    socket: TcpStream,
+    plaintext: Vec<u8>,
+}
+
+impl TlsReader {
+    /// `socket` is a clone of the link's, for reading.
+    pub fn new(link: Arc<TlsLink>, socket: TcpStream) -> Self {
+        TlsReader {
+            link,
+            socket,
+            plaintext: Vec::new(),
+        }
+    }
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 4096];
+        while self.plaintext.is_empty() {
+            // not while holding the session, which writers need
+            let n = self.socket.read(&mut raw)?;
+            if n == 0 {
+                return Ok(0);
+            }
+
+            let mut session = self.link.session.lock().unwrap();
+            receive_records(&mut *session, &raw[..n])?;
+            read_plaintext(&mut *session, &mut self.plaintext)?;
+            // handshake messages, and anything written while it was going on
+            write_records(&mut *session, &mut self.link.socket.lock().unwrap())?;
+        }
+
+        let n = buf.len().min(self.plaintext.len());
+        buf[..n].copy_from_slice(&self.plaintext[..n]);
+        self.plaintext.drain(..n);
+        Ok(n)
+    }
+}
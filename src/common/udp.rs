@@ -0,0 +1,142 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use common::{Channel, Conditioner, LinkConditions, UdpEndpoint};
+
+/// How often the link wakes up to resend and ack, when nothing arrives.
+const UPDATE_MS: u64 = 20;
+
+/// The client's end of a UDP connection to the server.
+pub struct UdpLink {
+    socket: UdpSocket,
+    endpoint: Mutex<UdpEndpoint>,
+    conditions: LinkConditions,
+    /// Packets on their way out, held back by `conditions`.
+    outgoing: Mutex<Conditioner<Vec<u8>>>,
+}
+
+impl UdpLink {
+    /// Nothing is sent until the first message, so this only fails if we
+    /// can't make a socket at all.
+    pub fn connect(addr: SocketAddr, conditions: LinkConditions) -> io::Result<Self> {
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(Duration::from_millis(UPDATE_MS)))?;
+
+        Ok(UdpLink {
+            socket,
+            endpoint: Mutex::new(UdpEndpoint::new(Instant::now())),
+            outgoing: Mutex::new(Conditioner::new(conditions.clone(), false)),
+            conditions,
+        })
+    }
+
+    pub fn send(&self, channel: Channel, payload: Vec<u8>) -> io::Result<()> {
+        let mut endpoint = self.endpoint.lock().unwrap();
+        if endpoint.is_disconnected() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the server closed the connection",
+            ));
+        }
+
+        endpoint.send(channel, payload);
+        self.flush(&mut endpoint)
+    }
+
+    fn flush(&self, endpoint: &mut UdpEndpoint) -> io::Result<()> {
+        let now = Instant::now();
+        let mut outgoing = self.outgoing.lock().unwrap();
+        for packet in endpoint.packets(now) {
+            let size = packet.len();
+            outgoing.push(packet, size, now);
+        }
+
+        for packet in outgoing.pop_due(now) {
+            match self.socket.send(&packet) {
+                Ok(_) => {}
+                // lost like any other packet, and resent if it has to be
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives until the connection is lost, handing each message to
+    /// `deliver`, which returns false to stop. Also stops once nothing but
+    /// this holds on to `link`.
+    pub fn run<F>(link: &Arc<UdpLink>, mut deliver: F) -> io::Error
+    where
+        F: FnMut(Vec<u8>) -> bool,
+    {
+        let mut buf = vec![0; 65536];
+        let mut incoming = Conditioner::new(link.conditions.clone(), false);
+        let update = Duration::from_millis(UPDATE_MS);
+
+        loop {
+            // wake up in time for anything held back
+            let now = Instant::now();
+            let next_due = incoming
+                .next_due()
+                .into_iter()
+                .chain(link.outgoing.lock().unwrap().next_due())
+                .min();
+            let timeout = match next_due {
+                Some(due) if due <= now => Duration::from_millis(1),
+                Some(due) => (due - now).min(update),
+                None => update,
+            };
+            if let Err(err) = link.socket.set_read_timeout(Some(timeout)) {
+                return err;
+            }
+
+            match link.socket.recv(&mut buf) {
+                Ok(n) => incoming.push(buf[..n].to_vec(), n, Instant::now()),
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock ||
+                        err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => return err,
+            }
+
+            let mut messages = Vec::new();
+            {
+                let mut endpoint = link.endpoint.lock().unwrap();
+                for packet in incoming.pop_due(Instant::now()) {
+                    // stray datagrams are ignored
+                    if let Ok(received) = endpoint.receive(&packet, Instant::now()) {
+                        messages.extend(received);
+                    }
+                }
+            }
+
+            // not while holding the endpoint, which `deliver` could wait on
+            for message in messages {
+                if !deliver(message) {
+                    return io::Error::new(io::ErrorKind::ConnectionAborted, "stopped");
+                }
+            }
+
+            let mut endpoint = link.endpoint.lock().unwrap();
+            if Arc::strong_count(link) == 1 {
+                let _ = link.socket.send(&endpoint.disconnect_packet());
+                return io::Error::new(io::ErrorKind::ConnectionAborted, "the connection was closed");
+            }
+            if endpoint.is_disconnected() {
+                return io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the server closed the connection",
+                );
+            }
+            if endpoint.timed_out(Instant::now()) {
+                return io::Error::new(io::ErrorKind::TimedOut, "the server stopped responding");
+            }
+            if let Err(err) = link.flush(&mut endpoint) {
+                return err;
+            }
+        }
+    }
+}
@@ -2,7 +2,7 @@ use std::ops::{Sub, Mul};
 use na::{Point2, Vector2, Isometry2};
 
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub struct Point {
     pub x: f64,
     pub y: f64,
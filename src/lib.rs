@@ -3,11 +3,13 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate toml;
 extern crate serde;
 extern crate byteorder;
 #[macro_use]
 extern crate chan;
 extern crate piston_window;
+#[macro_use]
 extern crate conrod;
 extern crate nalgebra as na;
 extern crate ncollide;
@@ -16,10 +18,19 @@ extern crate gfx_device_gl;
 #[macro_use]
 extern crate shred_derive;
 extern crate shred;
+extern crate mio;
+extern crate net2;
+extern crate sha2;
+extern crate rand;
+extern crate rustls;
+extern crate webpki;
 
 #[cfg(feature = "sdl2")]
 extern crate sdl2_window;
 
+#[cfg(feature = "audio")]
+extern crate rodio;
+
 pub mod common;
 pub mod client;
 pub mod server;
@@ -0,0 +1,223 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use rand::{OsRng, Rng};
+
+use common::{self, PlayerID};
+use super::Config;
+
+pub const MAX_NAME_LENGTH: usize = 24;
+
+/// A registered player. Only they know the token, so only they can use the name.
+pub struct Account {
+    pub id: PlayerID,
+    pub name: String,
+    token: String,
+}
+
+impl Account {
+    /// Whether `proof` is `common::auth_proof` of our token and `nonce`.
+    pub fn verify(&self, nonce: &str, proof: &str) -> bool {
+        let expected = common::auth_proof(&self.token, nonce);
+        // compared in full either way, so the time taken gives nothing away
+        expected.len() == proof.len() &&
+            expected
+                .bytes()
+                .zip(proof.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+pub type SharedAccounts = Arc<RwLock<Accounts>>;
+
+/// Registered players, read from a token file with a line per account:
+/// `<id> <name> <token>`. Blank lines and lines starting with `#` are ignored.
+pub struct Accounts {
+    accounts: Vec<Account>,
+}
+
+impl Accounts {
+    pub fn empty() -> Self {
+        Accounts { accounts: Vec::new() }
+    }
+
+    /// The file `config` names, if any.
+    pub fn for_config(config: &Config) -> Result<Self, String> {
+        match config.accounts {
+            Some(ref path) => Accounts::load(path).map_err(|err| format!("{}: {}", path, err)),
+            None => Ok(Accounts::empty()),
+        }
+    }
+
+    /// A file that doesn't exist yet has no accounts in it.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut s = String::new();
+        match File::open(path.as_ref()) {
+            Ok(mut file) => file.read_to_string(&mut s)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Accounts::empty()),
+            Err(err) => return Err(err),
+        };
+
+        let mut accounts = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            let id = match (words.len(), words[0].parse()) {
+                (3, Ok(id)) => PlayerID(id),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {} should be <id> <name> <token>", i + 1),
+                    ))
+                }
+            };
+            accounts.push(Account {
+                id,
+                name: words[1].into(),
+                token: words[2].into(),
+            });
+        }
+
+        Ok(Accounts { accounts })
+    }
+
+    /// Names are matched ignoring case, so that no one can pass for someone else.
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|a| a.name.to_lowercase() == name.to_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Registers `name` in the file at `path`, returning the new account's id
+    /// and token.
+    pub fn add<P: AsRef<Path>>(path: P, name: &str) -> io::Result<(PlayerID, String)> {
+        let accounts = Accounts::load(path.as_ref())?;
+        if let Err(reason) = check_name(name) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, reason));
+        }
+        if name.contains(char::is_whitespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "registered names can't contain spaces",
+            ));
+        }
+        if accounts.get(name).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is already registered", name),
+            ));
+        }
+
+        let id = PlayerID(accounts.accounts.iter().map(|a| a.id.0 + 1).max().unwrap_or(1));
+        let token = random_hex(16)?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{} {} {}", id.0, name, token)?;
+        Ok((id, token))
+    }
+}
+
+/// Returns why `name` can't be used, if it can't.
+pub fn check_name(name: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        Err("Your name can't be empty.".into())
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        Err(format!("Your name can't be longer than {} characters.", MAX_NAME_LENGTH))
+    } else if name.chars().any(char::is_control) {
+        Err("Your name can't contain control characters.".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// `bytes` random bytes from the OS, as hex.
+pub fn random_hex(bytes: usize) -> io::Result<String> {
+    let mut rng = OsRng::new()?;
+    let mut buf = vec![0; bytes];
+    rng.fill_bytes(&mut buf);
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{ErrorKind, Write};
+    use std::path::PathBuf;
+    use common::{self, PlayerID};
+    use super::*;
+
+    fn accounts_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("moba-accounts-test-{}.txt", name));
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    #[test]
+    fn only_the_right_proof_verifies() {
+        let account = Account {
+            id: PlayerID(1),
+            name: "alice".into(),
+            token: "secret".into(),
+        };
+        let proof = common::auth_proof("secret", "nonce");
+
+        assert!(account.verify("nonce", &proof));
+        assert!(!account.verify("other nonce", &proof));
+        assert!(!account.verify("nonce", &common::auth_proof("guess", "nonce")));
+        assert!(!account.verify("nonce", &proof[1..]));
+        assert!(!account.verify("nonce", ""));
+    }
+
+    #[test]
+    fn accounts_are_read_a_line_each() {
+        let path = accounts_file("load", "# comment\n\n1 alice aaaa\n  2 Bob bbbb  \n");
+        let accounts = Accounts::load(&path).unwrap();
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts.get("alice").unwrap().id, PlayerID(1));
+        // names are matched ignoring case
+        assert_eq!(accounts.get("BOB").unwrap().id, PlayerID(2));
+        assert!(accounts.get("carol").is_none());
+    }
+
+    #[test]
+    fn a_missing_file_has_no_accounts() {
+        let path = env::temp_dir().join("moba-accounts-test-missing.txt");
+        let _ = fs::remove_file(&path);
+        assert_eq!(Accounts::load(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        for (i, contents) in ["1 alice", "one alice aaaa", "1 alice aaaa extra"].iter().enumerate() {
+            let path = accounts_file(&format!("malformed-{}", i), contents);
+            match Accounts::load(&path) {
+                Err(ref err) if err.kind() == ErrorKind::InvalidData => {}
+                _ => panic!("{:?} was accepted", contents),
+            }
+        }
+    }
+
+    #[test]
+    fn added_accounts_can_be_loaded() {
+        let path = accounts_file("add", "1 alice aaaa\n");
+        let (id, token) = Accounts::add(&path, "bob").unwrap();
+        assert_eq!(id, PlayerID(2));
+        assert!(Accounts::add(&path, "Alice").is_err());
+        assert!(Accounts::add(&path, "two words").is_err());
+
+        let accounts = Accounts::load(&path).unwrap();
+        let proof = common::auth_proof(&token, "nonce");
+        assert!(accounts.get("bob").unwrap().verify("nonce", &proof));
+    }
+}
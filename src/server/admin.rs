@@ -0,0 +1,284 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time;
+
+use common::{logic, Point, Team, MatchID};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdminCommand {
+    Login { password: String },
+    Kick { name: String, reason: String },
+    SetTeam { name: String, team: Option<Team> },
+    Pause,
+    Resume,
+    Spawn {
+        hero: logic::HeroKind,
+        position: Point,
+    },
+    SetHitpoints { name: String, hitpoints: u16 },
+    Reload,
+    ShowConfig,
+    /// Everyone's if `name` is `None`.
+    NetStats { name: Option<String> },
+}
+
+pub const ADMIN_COMMANDS: &[&str] = &[
+    "login",
+    "kick",
+    "team",
+    "pause",
+    "resume",
+    "spawn",
+    "sethp",
+    "reload",
+    "config",
+    "netstats",
+];
+
+impl AdminCommand {
+    /// Returns `None` if `message` isn't an admin command at all.
+    pub fn parse(message: &str) -> Option<Result<AdminCommand, String>> {
+        if !message.starts_with('/') {
+            return None;
+        }
+
+        let words = message[1..].split_whitespace().collect::<Vec<&str>>();
+        let name = match words.first() {
+            Some(name) if ADMIN_COMMANDS.contains(name) => *name,
+            _ => return None,
+        };
+        let args = &words[1..];
+
+        let command = match (name, args.len()) {
+            ("login", 1) => Ok(AdminCommand::Login { password: args[0].into() }),
+            ("kick", n) if n >= 1 => {
+                Ok(AdminCommand::Kick {
+                    name: args[0].into(),
+                    reason: if n > 1 {
+                        args[1..].join(" ")
+                    } else {
+                        "Kicked by an admin.".into()
+                    },
+                })
+            }
+            ("team", 2) => {
+                let team = match args[1] {
+                    "none" => Ok(None),
+                    t => t.parse().map(|t| Some(Team(t))).map_err(|_| {
+                        format!("Invalid team: {}", t)
+                    }),
+                };
+                team.map(|team| {
+                    AdminCommand::SetTeam {
+                        name: args[0].into(),
+                        team,
+                    }
+                })
+            }
+            ("pause", 0) => Ok(AdminCommand::Pause),
+            ("resume", 0) => Ok(AdminCommand::Resume),
+            ("spawn", n) if n == 1 || n == 3 => {
+                let hero = logic::HeroKind::from_name(args[0]).ok_or_else(|| {
+                    format!("No hero named {}.", args[0])
+                });
+                let position: Result<Point, String> = if n == 3 {
+                    match (args[1].parse(), args[2].parse()) {
+                        (Ok(x), Ok(y)) => Ok(Point::new(x, y)),
+                        _ => Err("Invalid position.".into()),
+                    }
+                } else {
+                    Ok(Point::new(0.0, 0.0))
+                };
+                hero.and_then(|hero| {
+                    position.map(|position| AdminCommand::Spawn { hero, position })
+                })
+            }
+            ("sethp", 2) => {
+                args[1]
+                    .parse()
+                    .map(|hitpoints| {
+                        AdminCommand::SetHitpoints {
+                            name: args[0].into(),
+                            hitpoints,
+                        }
+                    })
+                    .map_err(|_| format!("Invalid hitpoints: {}", args[1]))
+            }
+            ("reload", 0) => Ok(AdminCommand::Reload),
+            ("config", 0) => Ok(AdminCommand::ShowConfig),
+            ("netstats", 0) => Ok(AdminCommand::NetStats { name: None }),
+            ("netstats", 1) => Ok(AdminCommand::NetStats { name: Some(args[0].into()) }),
+            _ => Err(usage(name).into()),
+        };
+
+        Some(command)
+    }
+}
+
+fn usage(command: &str) -> &'static str {
+    match command {
+        "login" => "Usage: /login <password>",
+        "kick" => "Usage: /kick <name> [reason]",
+        "team" => "Usage: /team <name> <team|none>",
+        "pause" => "Usage: /pause",
+        "resume" => "Usage: /resume",
+        "spawn" => "Usage: /spawn <hero> [x y]",
+        "sethp" => "Usage: /sethp <name> <hitpoints>",
+        "reload" => "Usage: /reload",
+        "config" => "Usage: /config",
+        "netstats" => "Usage: /netstats [name]",
+        _ => unreachable!(),
+    }
+}
+
+/// Records every admin action, to stdout and optionally to a file.
+pub struct AuditLog {
+    file: Option<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| println!("Couldn't open audit log {}: {}", path, err))
+            .ok();
+
+        AuditLog { file }
+    }
+
+    pub fn record(&mut self, match_id: MatchID, admin: &str, action: &str) {
+        let timestamp = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = format!("[{}] match {}: {}: {}", timestamp, match_id.0, admin, action);
+
+        println!("AUDIT {}", line);
+        if let Some(ref mut file) = self.file {
+            if let Err(err) = writeln!(file, "{}", line) {
+                println!("Couldn't write to audit log: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{logic, Point, Team};
+    use super::*;
+
+    fn parse(message: &str) -> Result<AdminCommand, String> {
+        AdminCommand::parse(message).expect("not an admin command")
+    }
+
+    #[test]
+    fn other_messages_arent_commands() {
+        assert!(AdminCommand::parse("hello").is_none());
+        assert!(AdminCommand::parse("login secret").is_none());
+        // chat commands are left to the chat
+        assert!(AdminCommand::parse("/w bob hi").is_none());
+        assert!(AdminCommand::parse("/").is_none());
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(parse("/pause"), Ok(AdminCommand::Pause));
+        assert_eq!(parse("/resume"), Ok(AdminCommand::Resume));
+        assert_eq!(parse("/reload"), Ok(AdminCommand::Reload));
+        assert_eq!(parse("/config"), Ok(AdminCommand::ShowConfig));
+        assert_eq!(parse("/netstats"), Ok(AdminCommand::NetStats { name: None }));
+    }
+
+    #[test]
+    fn login_takes_a_password() {
+        assert_eq!(
+            parse("/login hunter2"),
+            Ok(AdminCommand::Login { password: "hunter2".into() })
+        );
+        assert_eq!(parse("/login"), Err(usage("login").into()));
+        assert_eq!(parse("/login two words"), Err(usage("login").into()));
+    }
+
+    #[test]
+    fn kick_has_a_default_reason() {
+        assert_eq!(
+            parse("/kick bob"),
+            Ok(AdminCommand::Kick {
+                name: "bob".into(),
+                reason: "Kicked by an admin.".into(),
+            })
+        );
+        assert_eq!(
+            parse("/kick bob  stop   feeding"),
+            Ok(AdminCommand::Kick {
+                name: "bob".into(),
+                reason: "stop feeding".into(),
+            })
+        );
+        assert_eq!(parse("/kick"), Err(usage("kick").into()));
+    }
+
+    #[test]
+    fn team_takes_a_number_or_none() {
+        assert_eq!(
+            parse("/team bob 1"),
+            Ok(AdminCommand::SetTeam {
+                name: "bob".into(),
+                team: Some(Team(1)),
+            })
+        );
+        assert_eq!(
+            parse("/team bob none"),
+            Ok(AdminCommand::SetTeam {
+                name: "bob".into(),
+                team: None,
+            })
+        );
+        assert_eq!(parse("/team bob red"), Err("Invalid team: red".into()));
+        assert_eq!(parse("/team bob 256"), Err("Invalid team: 256".into()));
+    }
+
+    #[test]
+    fn spawn_takes_an_optional_position() {
+        assert_eq!(
+            parse("/spawn John"),
+            Ok(AdminCommand::Spawn {
+                hero: logic::HeroKind::John,
+                position: Point::new(0.0, 0.0),
+            })
+        );
+        assert_eq!(
+            parse("/spawn john 10 -2.5"),
+            Ok(AdminCommand::Spawn {
+                hero: logic::HeroKind::John,
+                position: Point::new(10.0, -2.5),
+            })
+        );
+        assert_eq!(parse("/spawn nobody"), Err("No hero named nobody.".into()));
+        assert_eq!(parse("/spawn john 10 up"), Err("Invalid position.".into()));
+        assert_eq!(parse("/spawn john 10"), Err(usage("spawn").into()));
+    }
+
+    #[test]
+    fn sethp_takes_a_number() {
+        assert_eq!(
+            parse("/sethp bob 50"),
+            Ok(AdminCommand::SetHitpoints {
+                name: "bob".into(),
+                hitpoints: 50,
+            })
+        );
+        assert_eq!(parse("/sethp bob -1"), Err("Invalid hitpoints: -1".into()));
+    }
+
+    #[test]
+    fn netstats_takes_an_optional_name() {
+        assert_eq!(
+            parse("/netstats bob"),
+            Ok(AdminCommand::NetStats { name: Some("bob".into()) })
+        );
+        assert_eq!(parse("/netstats bob alice"), Err(usage("netstats").into()));
+    }
+}
@@ -0,0 +1,208 @@
+use std::thread;
+use std::time;
+use std::sync::{Arc, Mutex};
+
+use common::{self, ClientMessage, MatchID, PlayerID, ServerMessage, Team};
+use super::{SharedConfig, SharedAccounts, Accounts, Connection, JoiningPlayer, Lobby, check_name,
+            random_hex};
+
+/// How long a client has to answer its challenge.
+const CHALLENGE_TIMEOUT_SECS: u64 = 10;
+/// How often answers to challenges are checked for.
+const POLL_MS: u64 = 10;
+
+/// Who a client says it is, from its `Connect`.
+pub struct Login {
+    pub name: String,
+    pub team: Option<Team>,
+    pub match_id: Option<MatchID>,
+    /// Already negotiated.
+    pub capabilities: Vec<String>,
+}
+
+struct Challenge {
+    connection: Connection,
+    login: Login,
+    nonce: String,
+    sent: time::Instant,
+}
+
+/// Lets clients in once they've shown they are who they say they are, and
+/// sends them on to the lobby. Anyone can use a name that isn't registered,
+/// unless the server requires accounts. Clients signing in are waited on by a
+/// thread of its own, so the event loop never has to.
+#[derive(Clone)]
+pub struct Authenticator {
+    accounts: SharedAccounts,
+    config: SharedConfig,
+    lobby: Arc<Mutex<Lobby>>,
+    challenges: Arc<Mutex<Vec<Challenge>>>,
+}
+
+impl Authenticator {
+    pub fn spawn(
+        accounts: SharedAccounts,
+        config: SharedConfig,
+        lobby: Arc<Mutex<Lobby>>,
+    ) -> Self {
+        let authenticator = Authenticator {
+            accounts,
+            config,
+            lobby,
+            challenges: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        {
+            let authenticator = authenticator.clone();
+            thread::spawn(move || loop {
+                thread::sleep(time::Duration::from_millis(POLL_MS));
+                authenticator.check_challenges();
+            });
+        }
+
+        authenticator
+    }
+
+    pub fn login(&self, mut connection: Connection, login: Login) {
+        if let Err(reason) = check_name(&login.name) {
+            kick(connection, &login.name, reason);
+            return;
+        }
+
+        if self.config.read().unwrap().require_tls && !connection.is_encrypted() {
+            let reason = "This server only accepts encrypted connections. \
+                          Connect with --tls and the server's certificate."
+                .into();
+            kick(connection, &login.name, reason);
+            return;
+        }
+
+        let registered = self.accounts.read().unwrap().get(&login.name).is_some();
+        let can_sign_in = login.capabilities.iter().any(|c| c == common::CAPABILITY_AUTH);
+
+        if registered && can_sign_in {
+            let nonce = match random_hex(16) {
+                Ok(nonce) => nonce,
+                Err(err) => {
+                    println!("Couldn't make a challenge: {}", err);
+                    kick(connection, &login.name, "The server couldn't sign you in.".into());
+                    return;
+                }
+            };
+            let challenge = ServerMessage::AuthChallenge { nonce: nonce.clone() };
+            if connection.write_message(challenge).is_ok() {
+                self.challenges.lock().unwrap().push(Challenge {
+                    connection,
+                    login,
+                    nonce,
+                    sent: time::Instant::now(),
+                });
+            }
+        } else if registered {
+            let reason = format!(
+                "{} is a registered name, and your client is too old to sign in.",
+                login.name
+            );
+            kick(connection, &login.name, reason);
+        } else if self.config.read().unwrap().require_account {
+            let reason = "This server only lets registered players in.".into();
+            kick(connection, &login.name, reason);
+        } else {
+            self.accept(connection, login, None);
+        }
+    }
+
+    fn accept(&self, mut connection: Connection, login: Login, player_id: Option<PlayerID>) {
+        let message = match player_id {
+            Some(_) => format!("Welcome back, {}.", login.name),
+            None => "Welcome to moba alpha.".into(),
+        };
+        if let Err(err) = connection.write_message(ServerMessage::AcceptConnection {
+            message,
+            protocol_version: common::PROTOCOL_VERSION,
+            capabilities: login.capabilities.clone(),
+            player_id,
+        })
+        {
+            println!("Couldn't accept {}: {}", login.name, err);
+            return;
+        }
+
+        let player = JoiningPlayer {
+            connection,
+            name: login.name,
+            team: login.team,
+            capabilities: login.capabilities,
+            player_id,
+        };
+        self.lobby.lock().unwrap().route(player, login.match_id);
+    }
+
+    fn check_challenges(&self) {
+        let now = time::Instant::now();
+        let mut answered = Vec::new();
+        {
+            let accounts = self.accounts.read().unwrap();
+            let mut challenges = self.challenges.lock().unwrap();
+            let mut i = 0;
+            while i < challenges.len() {
+                let result = check_answer(&mut challenges[i], &accounts, now);
+                match result {
+                    Some(result) => answered.push((challenges.remove(i), result)),
+                    None => i += 1,
+                }
+            }
+        }
+
+        // not while holding the challenges, since accepting takes the lobby
+        for (challenge, result) in answered {
+            match result {
+                Ok(id) => {
+                    println!("{} signed in as player {}", challenge.login.name, id.0);
+                    self.accept(challenge.connection, challenge.login, Some(id));
+                }
+                Err(reason) => kick(challenge.connection, &challenge.login.name, reason),
+            }
+        }
+    }
+}
+
+/// The player's id if they've proved who they are, or why not. `None` while
+/// we're still waiting to hear back.
+fn check_answer(
+    challenge: &mut Challenge,
+    accounts: &Accounts,
+    now: time::Instant,
+) -> Option<Result<PlayerID, String>> {
+    let proof = match challenge.connection.try_get_message() {
+        Some(Ok(ClientMessage::AuthResponse { proof })) => proof,
+        Some(Ok(_)) => {
+            return Some(Err("Your client didn't answer the sign in challenge.".into()))
+        }
+        Some(Err(err)) => return Some(Err(err.to_string())),
+        None if now - challenge.sent > time::Duration::from_secs(CHALLENGE_TIMEOUT_SECS) => {
+            return Some(Err("Signing in took too long.".into()))
+        }
+        None => return None,
+    };
+
+    let account = match accounts.get(&challenge.login.name) {
+        Some(account) => account,
+        None => return Some(Err("That account no longer exists.".into())),
+    };
+
+    Some(match proof {
+        Some(ref proof) if account.verify(&challenge.nonce, proof) => {
+            // as registered, whatever case they typed it in
+            challenge.login.name = account.name.clone();
+            Ok(account.id)
+        }
+        Some(_) => Err(format!("That isn't {}'s token.", account.name)),
+        None => Err(format!("{} is a registered name. Set your token to sign in.", account.name)),
+    })
+}
+
+fn kick(mut connection: Connection, name: &str, reason: String) {
+    println!("Rejecting {}: {}", name, reason);
+    let _ = connection.write_message(ServerMessage::Kick { reason });
+}
@@ -0,0 +1,456 @@
+use std::time;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use common::{ServerMessage, EntityID, Team, ChatChannel};
+
+pub const MAX_MESSAGE_LENGTH: usize = 200;
+
+/// At most `FLOOD_LIMIT` messages may be sent within `FLOOD_WINDOW_SECS`.
+const FLOOD_LIMIT: usize = 5;
+const FLOOD_WINDOW_SECS: u64 = 5;
+
+/// A player as seen by the chat.
+#[derive(Clone, Debug)]
+pub struct ChatMember {
+    pub id: EntityID,
+    pub name: String,
+    pub team: Option<Team>,
+}
+
+/// Routes chat messages and runs chat commands. Produces the messages to send
+/// and leaves the actual sending to the match.
+pub struct Chat {
+    recent_messages: HashMap<EntityID, VecDeque<time::Instant>>,
+    /// muter -> muted
+    mutes: HashMap<EntityID, HashSet<EntityID>>,
+    surrender_votes: HashMap<Team, HashSet<EntityID>>,
+    /// A team whose surrender vote passed, for the match to end.
+    surrendered: Option<Team>,
+}
+
+impl Chat {
+    pub fn new() -> Self {
+        Chat {
+            recent_messages: HashMap::new(),
+            mutes: HashMap::new(),
+            surrender_votes: HashMap::new(),
+            surrendered: None,
+        }
+    }
+
+    pub fn remove_member(&mut self, id: EntityID) {
+        self.recent_messages.remove(&id);
+        self.mutes.remove(&id);
+        for muted in self.mutes.values_mut() {
+            muted.remove(&id);
+        }
+        for votes in self.surrender_votes.values_mut() {
+            votes.remove(&id);
+        }
+    }
+
+    /// The team that has just surrendered, if any.
+    pub fn take_surrender(&mut self) -> Option<Team> {
+        self.surrendered.take()
+    }
+
+    pub fn handle_message(
+        &mut self,
+        sender: &ChatMember,
+        message: String,
+        channel: ChatChannel,
+        members: &[ChatMember],
+    ) -> Vec<(EntityID, ServerMessage)> {
+        let message = message.trim().to_string();
+        if message.is_empty() {
+            return Vec::new();
+        }
+
+        // the others are only sent by the server
+        if channel != ChatChannel::All && channel != ChatChannel::Team {
+            return vec![system_message(sender.id, "You can only chat to all or your team.".into())];
+        }
+
+        if message.chars().count() > MAX_MESSAGE_LENGTH {
+            return vec![
+                system_message(
+                    sender.id,
+                    format!(
+                        "Messages can be at most {} characters long.",
+                        MAX_MESSAGE_LENGTH
+                    )
+                ),
+            ];
+        }
+
+        if self.is_flooding(sender.id) {
+            return vec![
+                system_message(sender.id, "You are sending messages too quickly.".into()),
+            ];
+        }
+
+        if message.starts_with('/') {
+            return self.run_command(sender, &message[1..], members);
+        }
+
+        members
+            .iter()
+            .filter(|m| {
+                // without a team, team chat only goes back to the sender
+                channel == ChatChannel::All || m.id == sender.id ||
+                    (sender.team.is_some() && m.team == sender.team)
+            })
+            .filter(|m| !self.is_muted(m.id, sender.id))
+            .map(|m| {
+                (
+                    m.id,
+                    ServerMessage::ReceiveChat {
+                        user: sender.name.clone(),
+                        message: message.clone(),
+                        channel,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    pub fn is_flooding(&mut self, id: EntityID) -> bool {
+        let now = time::Instant::now();
+        let window = time::Duration::from_secs(FLOOD_WINDOW_SECS);
+        let recent = self.recent_messages.entry(id).or_insert_with(
+            || VecDeque::new(),
+        );
+
+        while recent.front().map_or(false, |&t| now - t > window) {
+            recent.pop_front();
+        }
+
+        if recent.len() >= FLOOD_LIMIT {
+            return true;
+        }
+
+        recent.push_back(now);
+        false
+    }
+
+    fn is_muted(&self, muter: EntityID, muted: EntityID) -> bool {
+        self.mutes.get(&muter).map_or(false, |m| m.contains(&muted))
+    }
+
+    fn run_command(
+        &mut self,
+        sender: &ChatMember,
+        command: &str,
+        members: &[ChatMember],
+    ) -> Vec<(EntityID, ServerMessage)> {
+        let mut words = command.splitn(3, ' ');
+        let name = words.next().unwrap_or("");
+
+        match name {
+            "w" | "whisper" => {
+                let (target, message) = match (words.next(), words.next()) {
+                    (Some(target), Some(message)) => (target, message.trim()),
+                    _ => return vec![system_message(sender.id, "Usage: /w <name> <message>".into())],
+                };
+
+                let target = match find_member(members, target) {
+                    Some(target) => target,
+                    None => return vec![no_such_player(sender.id, target)],
+                };
+
+                let mut out = vec![
+                    (
+                        sender.id,
+                        ServerMessage::ReceiveChat {
+                            user: format!("To {}", target.name),
+                            message: message.into(),
+                            channel: ChatChannel::Whisper,
+                        },
+                    ),
+                ];
+                if !self.is_muted(target.id, sender.id) {
+                    out.push((
+                        target.id,
+                        ServerMessage::ReceiveChat {
+                            user: format!("From {}", sender.name),
+                            message: message.into(),
+                            channel: ChatChannel::Whisper,
+                        },
+                    ));
+                }
+                out
+            }
+            "mute" => {
+                let target = match words.next() {
+                    Some(target) => target,
+                    None => return vec![system_message(sender.id, "Usage: /mute <name>".into())],
+                };
+
+                let target = match find_member(members, target) {
+                    Some(target) => target,
+                    None => return vec![no_such_player(sender.id, target)],
+                };
+
+                if target.id == sender.id {
+                    return vec![system_message(sender.id, "You can't mute yourself.".into())];
+                }
+
+                let muted = self.mutes.entry(sender.id).or_insert_with(|| HashSet::new());
+                let reply = if muted.remove(&target.id) {
+                    format!("Unmuted {}.", target.name)
+                } else {
+                    muted.insert(target.id);
+                    format!("Muted {}. Use /mute again to unmute.", target.name)
+                };
+                vec![system_message(sender.id, reply)]
+            }
+            "ff" | "surrender" => {
+                let team = match sender.team {
+                    Some(team) => team,
+                    None => {
+                        return vec![
+                            system_message(sender.id, "You need to be on a team to surrender.".into()),
+                        ]
+                    }
+                };
+
+                let team_members = members
+                    .iter()
+                    .filter(|m| m.team == Some(team))
+                    .collect::<Vec<_>>();
+                let votes = {
+                    let votes = self.surrender_votes.entry(team).or_insert_with(
+                        || HashSet::new(),
+                    );
+                    votes.insert(sender.id);
+                    votes.len()
+                };
+                let needed = team_members.len() / 2 + 1;
+
+                if votes >= needed {
+                    // the match ends, and tells everyone why
+                    self.surrender_votes.remove(&team);
+                    self.surrendered = Some(team);
+                    Vec::new()
+                } else {
+                    team_members
+                        .iter()
+                        .map(|m| {
+                            system_message(
+                                m.id,
+                                format!(
+                                    "{} voted to surrender ({}/{}). Type /ff to agree.",
+                                    sender.name,
+                                    votes,
+                                    needed
+                                ),
+                            )
+                        })
+                        .collect()
+                }
+            }
+            _ => {
+                vec![
+                    system_message(
+                        sender.id,
+                        format!("Unknown command /{}. Commands: /w, /mute, /ff", name),
+                    ),
+                ]
+            }
+        }
+    }
+}
+
+fn find_member<'a>(members: &'a [ChatMember], name: &str) -> Option<&'a ChatMember> {
+    members
+        .iter()
+        .find(|m| m.name == name)
+        .or_else(|| {
+            members
+                .iter()
+                .find(|m| m.name.to_lowercase() == name.to_lowercase())
+        })
+}
+
+fn no_such_player(to: EntityID, name: &str) -> (EntityID, ServerMessage) {
+    system_message(to, format!("No player named {}.", name))
+}
+
+pub fn system_message(to: EntityID, message: String) -> (EntityID, ServerMessage) {
+    (
+        to,
+        ServerMessage::ReceiveChat {
+            user: "".into(),
+            message,
+            channel: ChatChannel::System,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{ChatChannel, EntityID, ServerMessage, Team};
+    use super::*;
+
+    fn member(id: usize, name: &str, team: Option<Team>) -> ChatMember {
+        ChatMember {
+            id: EntityID(id),
+            name: name.into(),
+            team,
+        }
+    }
+
+    fn teams() -> Vec<ChatMember> {
+        vec![
+            member(0, "alice", Some(Team(0))),
+            member(1, "bob", Some(Team(0))),
+            member(2, "carol", Some(Team(1))),
+        ]
+    }
+
+    fn recipients(messages: &[(EntityID, ServerMessage)]) -> Vec<usize> {
+        messages.iter().map(|&(id, _)| id.0).collect()
+    }
+
+    fn channel(message: &(EntityID, ServerMessage)) -> ChatChannel {
+        match message.1 {
+            ServerMessage::ReceiveChat { channel, .. } => channel,
+            ref other => panic!("expected chat, got {:?}", other),
+        }
+    }
+
+    fn send(
+        chat: &mut Chat,
+        members: &[ChatMember],
+        from: usize,
+        message: &str,
+        channel: ChatChannel,
+    ) -> Vec<(EntityID, ServerMessage)> {
+        let sender = members[from].clone();
+        chat.handle_message(&sender, message.into(), channel, members)
+    }
+
+    #[test]
+    fn all_chat_goes_to_everyone() {
+        let members = teams();
+        let out = send(&mut Chat::new(), &members, 0, "hi", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn team_chat_stays_in_the_team() {
+        let members = teams();
+        let out = send(&mut Chat::new(), &members, 0, "hi", ChatChannel::Team);
+        assert_eq!(recipients(&out), vec![0, 1]);
+    }
+
+    #[test]
+    fn team_chat_without_teams_only_goes_to_the_sender() {
+        let members = vec![member(0, "alice", None), member(1, "bob", None)];
+        let out = send(&mut Chat::new(), &members, 0, "hi", ChatChannel::Team);
+        assert_eq!(recipients(&out), vec![0]);
+    }
+
+    #[test]
+    fn server_channels_cant_be_sent_to() {
+        let members = teams();
+        for &forged in &[ChatChannel::System, ChatChannel::Whisper] {
+            let out = send(&mut Chat::new(), &members, 0, "hi", forged);
+            assert_eq!(recipients(&out), vec![0]);
+            assert_eq!(channel(&out[0]), ChatChannel::System);
+        }
+    }
+
+    #[test]
+    fn long_and_empty_messages_are_rejected() {
+        let members = teams();
+        let long = "a".repeat(MAX_MESSAGE_LENGTH + 1);
+        let out = send(&mut Chat::new(), &members, 0, &long, ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0]);
+        assert_eq!(channel(&out[0]), ChatChannel::System);
+
+        assert!(send(&mut Chat::new(), &members, 0, "   ", ChatChannel::All).is_empty());
+    }
+
+    #[test]
+    fn flooding_is_limited() {
+        let members = teams();
+        let mut chat = Chat::new();
+        for _ in 0..FLOOD_LIMIT {
+            let out = send(&mut chat, &members, 0, "hi", ChatChannel::All);
+            assert_eq!(recipients(&out), vec![0, 1, 2]);
+        }
+
+        let out = send(&mut chat, &members, 0, "hi", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0]);
+        assert_eq!(channel(&out[0]), ChatChannel::System);
+
+        // the limit is per player
+        let out = send(&mut chat, &members, 1, "hi", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn whispers_go_to_one_player() {
+        let members = teams();
+        let out = send(&mut Chat::new(), &members, 0, "/w Carol psst", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0, 2]);
+        assert!(out.iter().all(|m| channel(m) == ChatChannel::Whisper));
+    }
+
+    #[test]
+    fn muted_players_arent_heard() {
+        let members = teams();
+        let mut chat = Chat::new();
+        send(&mut chat, &members, 1, "/mute alice", ChatChannel::All);
+
+        let out = send(&mut chat, &members, 0, "hi", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0, 2]);
+        let out = send(&mut chat, &members, 0, "/w bob hi", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0]);
+
+        // muting again unmutes
+        send(&mut chat, &members, 1, "/mute alice", ChatChannel::All);
+        let out = send(&mut chat, &members, 0, "hi", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_majority_of_the_team_surrenders() {
+        let members = teams();
+        let mut chat = Chat::new();
+
+        let out = send(&mut chat, &members, 0, "/ff", ChatChannel::All);
+        assert_eq!(recipients(&out), vec![0, 1]);
+        assert_eq!(chat.take_surrender(), None);
+
+        send(&mut chat, &members, 1, "/ff", ChatChannel::All);
+        assert_eq!(chat.take_surrender(), Some(Team(0)));
+        assert_eq!(chat.take_surrender(), None);
+    }
+
+    #[test]
+    fn surrendering_needs_a_team() {
+        let members = vec![member(0, "alice", None)];
+        let mut chat = Chat::new();
+        send(&mut chat, &members, 0, "/ff", ChatChannel::All);
+        assert_eq!(chat.take_surrender(), None);
+    }
+
+    #[test]
+    fn votes_of_players_who_left_dont_count() {
+        let members = teams();
+        let mut chat = Chat::new();
+        send(&mut chat, &members, 0, "/ff", ChatChannel::All);
+        chat.remove_member(EntityID(0));
+
+        let members = vec![
+            members[1].clone(),
+            member(3, "dave", Some(Team(0))),
+            members[2].clone(),
+        ];
+        let sender = members[0].clone();
+        chat.handle_message(&sender, "/ff".into(), ChatChannel::All, &members);
+        assert_eq!(chat.take_surrender(), None);
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use toml;
+
+use common::{self, logic};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref err) => write!(f, "couldn't read config: {}", err),
+            ConfigError::Parse(ref err) => write!(f, "couldn't parse config: {}", err),
+            ConfigError::Invalid(ref reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(err: io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::Parse(err)
+    }
+}
+
+pub type SharedConfig = Arc<RwLock<Config>>;
+
+/// The most ticks per second a match can run at.
+pub const MAX_TICK_RATE: u32 = 1000;
+
+/// Server settings, read from a TOML file. Missing fields take their default values.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Shown in the client's server list.
+    pub name: String,
+    /// Whether to answer LAN discovery queries, on `common::DISCOVERY_PORT`.
+    pub lan_discovery: bool,
+    pub bind_address: net::IpAddr,
+    /// TCP, and UDP if `udp` is set.
+    pub port: u16,
+    /// Whether clients may connect over UDP as well as TCP.
+    pub udp: bool,
+    pub tick_rate: u32,
+    /// How many matches can run at once.
+    pub max_matches: usize,
+    /// Per match.
+    pub max_players: usize,
+    pub team_size: usize,
+    /// Total seconds each team, or each player without teams, may keep the
+    /// game paused.
+    pub pause_budget: u32,
+    pub map: logic::MapKind,
+    pub game_mode: logic::GameMode,
+    pub admin_password: Option<String>,
+    /// Where admin actions are recorded.
+    pub audit_log: String,
+    /// The token file of registered players, see `Accounts`. Read when the
+    /// server starts, and again by `/reload`.
+    pub accounts: Option<String>,
+    /// Turn away players who don't sign in to an account.
+    pub require_account: bool,
+    /// PEM files of the certificate chain and private key, to let clients
+    /// connect over TLS. Read when the server starts.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Turn away clients that don't connect over TLS.
+    pub require_tls: bool,
+
+    /// The file this config was loaded from, if any.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// From the command line, and kept over reloads.
+    #[serde(skip)]
+    overrides: Overrides,
+}
+
+/// Settings given on the command line, which take precedence over the file.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub name: Option<String>,
+    pub bind_address: Option<net::IpAddr>,
+    pub port: Option<u16>,
+    pub tick_rate: Option<u32>,
+    pub max_players: Option<usize>,
+    pub team_size: Option<usize>,
+    pub map: Option<logic::MapKind>,
+    pub game_mode: Option<logic::GameMode>,
+    pub admin_password: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: "moba server".into(),
+            lan_discovery: true,
+            bind_address: net::IpAddr::V4(net::Ipv4Addr::new(127, 0, 0, 1)),
+            port: common::DEFAULT_PORT,
+            udp: true,
+            tick_rate: 60,
+            max_matches: 8,
+            max_players: 10,
+            team_size: 5,
+            pause_budget: 120,
+            map: logic::MapKind::Arena,
+            game_mode: logic::GameMode::FreeForAll,
+            admin_password: None,
+            audit_log: "admin.log".into(),
+            accounts: None,
+            require_account: false,
+            tls_cert: None,
+            tls_key: None,
+            require_tls: false,
+            path: None,
+            overrides: Overrides::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let mut s = String::new();
+        File::open(path.as_ref())?.read_to_string(&mut s)?;
+
+        let mut config: Config = toml::from_str(&s)?;
+        config.path = Some(path.as_ref().to_owned());
+        Ok(config)
+    }
+
+    /// Applies command line settings, now and on every reload.
+    pub fn set_overrides(&mut self, overrides: Overrides) {
+        if let Some(ref name) = overrides.name {
+            self.name = name.clone();
+        }
+        if let Some(bind_address) = overrides.bind_address {
+            self.bind_address = bind_address;
+        }
+        if let Some(port) = overrides.port {
+            self.port = port;
+        }
+        if let Some(tick_rate) = overrides.tick_rate {
+            self.tick_rate = tick_rate;
+        }
+        if let Some(max_players) = overrides.max_players {
+            self.max_players = max_players;
+        }
+        if let Some(team_size) = overrides.team_size {
+            self.team_size = team_size;
+        }
+        if let Some(map) = overrides.map {
+            self.map = map;
+        }
+        if let Some(game_mode) = overrides.game_mode {
+            self.game_mode = game_mode;
+        }
+        if let Some(ref admin_password) = overrides.admin_password {
+            self.admin_password = Some(admin_password.clone());
+        }
+        self.overrides = overrides;
+    }
+
+    /// Checks for settings the server can't run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.into()));
+        if self.tick_rate == 0 || self.tick_rate > MAX_TICK_RATE {
+            return invalid(&format!("tick_rate has to be from 1 to {}", MAX_TICK_RATE));
+        }
+        if self.max_matches == 0 {
+            return invalid("max_matches has to be at least 1");
+        }
+        if self.max_players == 0 {
+            return invalid("max_players has to be at least 1");
+        }
+        if self.team_size == 0 {
+            return invalid("team_size has to be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Re-reads the file this config was loaded from, and applies the same
+    /// command line overrides. The bind address, port and UDP setting can't
+    /// change while the server is running, so they are kept.
+    pub fn reload(&self) -> Result<Self, ConfigError> {
+        let mut config = match self.path {
+            Some(ref path) => Config::load(path)?,
+            None => {
+                return Err(ConfigError::Io(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "the server wasn't started with a config file",
+                )))
+            }
+        };
+
+        config.bind_address = self.bind_address;
+        config.port = self.port;
+        config.udp = self.udp;
+        config.set_overrides(self.overrides.clone());
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn bind_addr(&self) -> net::SocketAddr {
+        net::SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// Number of queued players needed to start a match.
+    pub fn queue_match_size(&self) -> usize {
+        match self.game_mode {
+            logic::GameMode::FreeForAll => self.max_players,
+            logic::GameMode::Teams => {
+                self.team_size * self.game_mode.team_count() as usize
+            }
+        }.min(self.max_players)
+            .max(1)
+    }
+
+    /// The config as TOML, with the admin password hidden.
+    pub fn to_display_string(&self) -> String {
+        let mut config = self.clone();
+        if config.admin_password.is_some() {
+            config.admin_password = Some("********".into());
+        }
+        toml::to_string(&config).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use common::logic::GameMode;
+    use super::*;
+
+    fn reason(result: Result<(), ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("expected an invalid config, got {:?}", other),
+        }
+    }
+
+    /// Writes `contents` to a file of its own, and loads it.
+    fn load(name: &str, contents: &str) -> Config {
+        let path = env::temp_dir().join(format!("moba-config-test-{}.toml", name));
+        File::create(&path)
+            .unwrap()
+            .write_all(contents.as_bytes())
+            .unwrap();
+        Config::load(&path).unwrap()
+    }
+
+    #[test]
+    fn the_default_config_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn tick_rate_has_to_be_in_range() {
+        let mut config = Config::default();
+        config.tick_rate = 0;
+        assert!(reason(config.validate()).contains("tick_rate"));
+        config.tick_rate = MAX_TICK_RATE + 1;
+        assert!(reason(config.validate()).contains("tick_rate"));
+        config.tick_rate = MAX_TICK_RATE;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn counts_have_to_be_at_least_one() {
+        let mut config = Config::default();
+        config.max_matches = 0;
+        assert!(reason(config.validate()).contains("max_matches"));
+
+        let mut config = Config::default();
+        config.max_players = 0;
+        assert!(reason(config.validate()).contains("max_players"));
+
+        let mut config = Config::default();
+        config.team_size = 0;
+        assert!(reason(config.validate()).contains("team_size"));
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let mut config = load("precedence", "name = \"from file\"\ntick_rate = 30\nmax_players = 4");
+        config.set_overrides(Overrides {
+            tick_rate: Some(20),
+            game_mode: Some(GameMode::Teams),
+            ..Overrides::default()
+        });
+
+        assert_eq!(config.name, "from file");
+        assert_eq!(config.tick_rate, 20);
+        assert_eq!(config.max_players, 4);
+        assert_eq!(config.game_mode, GameMode::Teams);
+    }
+
+    #[test]
+    fn reloading_keeps_overrides_and_the_address() {
+        let mut config = load("reload", "tick_rate = 30\nport = 4000");
+        config.set_overrides(Overrides {
+            tick_rate: Some(20),
+            ..Overrides::default()
+        });
+        config.port = 5000;
+
+        let path = config.path.clone().unwrap();
+        File::create(&path)
+            .unwrap()
+            .write_all(b"tick_rate = 45\nport = 4001\nmax_players = 6")
+            .unwrap();
+        let reloaded = config.reload().unwrap();
+
+        assert_eq!(reloaded.tick_rate, 20);
+        assert_eq!(reloaded.max_players, 6);
+        assert_eq!(reloaded.port, 5000);
+    }
+
+    #[test]
+    fn reloading_rejects_invalid_settings() {
+        let config = load("invalid-reload", "max_players = 3");
+        let path = config.path.clone().unwrap();
+        File::create(&path)
+            .unwrap()
+            .write_all(b"max_players = 0")
+            .unwrap();
+        assert!(config.reload().is_err());
+    }
+
+    #[test]
+    fn reloading_needs_a_file() {
+        match Config::default().reload() {
+            Err(ConfigError::Io(_)) => {}
+            other => panic!("expected an io error, got {:?}", other),
+        }
+    }
+}
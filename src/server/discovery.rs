@@ -0,0 +1,116 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use net2::UdpBuilder;
+
+use common::{self, DiscoveryPacket, ServerInfo};
+use super::{Lobby, SharedConfig};
+
+/// Answers LAN discovery queries until the socket fails.
+pub fn answer_discovery(config: SharedConfig, lobby: Arc<Mutex<Lobby>>) -> io::Result<()> {
+    let socket = bind_discovery()?;
+    answer_queries(&socket, config, lobby)
+}
+
+/// Broadcasts only reach sockets bound to the unspecified address, so the
+/// socket is, whatever the server is bound to. The address is reused so that
+/// several servers on one machine can all answer broadcasts, though a query
+/// sent straight to this machine only reaches one of them.
+fn bind_discovery() -> io::Result<UdpSocket> {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), common::DISCOVERY_PORT);
+    let socket = UdpBuilder::new_v4()?.reuse_address(true)?.bind(addr)?;
+    println!("Answering discovery queries on {}", addr);
+    Ok(socket)
+}
+
+/// Only queries from the local network are answered, since the reply is much
+/// bigger than the query and could be used to flood a spoofed address.
+fn answer_queries(
+    socket: &UdpSocket,
+    config: SharedConfig,
+    lobby: Arc<Mutex<Lobby>>,
+) -> io::Result<()> {
+    let mut buf = [0; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf)?;
+        // a server on loopback can only be joined from the same machine
+        let loopback_only = config.read().unwrap().bind_address.is_loopback();
+        let local = if loopback_only {
+            from.ip().is_loopback()
+        } else {
+            is_local(from.ip())
+        };
+        if !local {
+            continue;
+        }
+
+        match common::decode_packet(&buf[..len]) {
+            Some(DiscoveryPacket::Query) => {}
+            _ => continue,
+        }
+
+        let info = {
+            let config = config.read().unwrap();
+            ServerInfo {
+                name: config.name.clone(),
+                map: config.map,
+                game_mode: config.game_mode,
+                players: lobby.lock().unwrap().total_player_count(),
+                max_players: config.max_players,
+                version: common::VERSION.into(),
+                port: config.port,
+            }
+        };
+
+        // the querier may have gone away already, which is no reason to stop
+        let _ = socket.send_to(&common::encode_packet(&DiscoveryPacket::Reply(info)), from);
+    }
+}
+
+/// Loopback, private and link-local addresses.
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // unique local fc00::/7 and link-local fe80::/10
+            ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::Duration;
+    use common;
+    use super::super::{Accounts, Config, Lobby};
+    use super::*;
+
+    #[test]
+    fn discover_finds_a_server_on_loopback() {
+        let mut config = Config::default();
+        config.name = "discovery test".into();
+        config.bind_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        config.port = 26200;
+        let config = Arc::new(RwLock::new(config));
+        let accounts = Arc::new(RwLock::new(Accounts::empty()));
+        let lobby = Arc::new(Mutex::new(Lobby::new(config.clone(), accounts)));
+
+        // bound before querying, so the query can't arrive first
+        let socket = bind_discovery().unwrap();
+        thread::spawn(move || answer_queries(&socket, config, lobby));
+
+        let servers = common::discover(Duration::from_millis(500)).unwrap();
+        let &(addr, ref info) = servers
+            .iter()
+            .find(|&&(_, ref info)| info.name == "discovery test")
+            .expect("the server wasn't found");
+        assert_eq!(addr.ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(addr.port(), 26200);
+        assert_eq!(info.players, 0);
+        assert_eq!(info.version, common::VERSION);
+    }
+}
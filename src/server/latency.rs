@@ -0,0 +1,169 @@
+use std::time;
+use std::collections::HashMap;
+
+/// How often each player is pinged.
+const PING_INTERVAL_MS: u64 = 1000;
+
+/// Pings unanswered for this long are given up on.
+const PING_TIMEOUT_SECS: u64 = 10;
+
+/// How much each new round trip moves the smoothed one.
+const SMOOTHING: f64 = 0.25;
+
+fn secs(d: time::Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1000000000.0
+}
+
+/// Measures one player's round trip time by pinging them, for clients with
+/// `CAPABILITY_SERVER_PING`.
+pub struct Latency {
+    next_id: u64,
+    /// When each unanswered ping was sent.
+    pending: HashMap<u64, time::Instant>,
+    last_ping: Option<time::Instant>,
+    /// Smoothed, in seconds.
+    rtt: Option<f64>,
+    last_sample: Option<f64>,
+    /// How much consecutive round trips differ, smoothed, in seconds.
+    jitter: f64,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Latency {
+            next_id: 0,
+            pending: HashMap::new(),
+            last_ping: None,
+            rtt: None,
+            last_sample: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// The id of a ping to send, if one is due.
+    pub fn ping(&mut self, now: time::Instant) -> Option<u64> {
+        if let Some(last_ping) = self.last_ping {
+            if now - last_ping < time::Duration::from_millis(PING_INTERVAL_MS) {
+                return None;
+            }
+        }
+
+        let timeout = time::Duration::from_secs(PING_TIMEOUT_SECS);
+        self.pending.retain(|_, &mut sent| now - sent < timeout);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, now);
+        self.last_ping = Some(now);
+        Some(id)
+    }
+
+    /// Returns the new smoothed round trip time, or `None` if `id` wasn't a
+    /// ping we're waiting on.
+    pub fn pong(&mut self, id: u64, now: time::Instant) -> Option<f64> {
+        let sent = match self.pending.remove(&id) {
+            Some(sent) => sent,
+            None => return None,
+        };
+
+        let sample = secs(now - sent);
+        if let Some(last_sample) = self.last_sample {
+            self.jitter += ((sample - last_sample).abs() - self.jitter) * SMOOTHING;
+        }
+        self.last_sample = Some(sample);
+
+        let rtt = match self.rtt {
+            Some(rtt) => rtt + (sample - rtt) * SMOOTHING,
+            None => sample,
+        };
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// In seconds, or `None` before the first ping is answered.
+    pub fn rtt(&self) -> Option<f64> {
+        self.rtt
+    }
+
+    /// In seconds, or `None` before the first ping is answered.
+    pub fn jitter(&self) -> Option<f64> {
+        self.rtt.map(|_| self.jitter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+
+    fn after(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("no value");
+        assert!((actual - expected).abs() < 1e-9, "{} isn't {}", actual, expected);
+    }
+
+    /// Pings at `sent` milliseconds after `start`, answered `rtt` later.
+    fn round_trip(latency: &mut Latency, start: Instant, sent: u64, rtt: u64) -> Option<f64> {
+        let id = latency.ping(after(start, sent)).expect("no ping was due");
+        latency.pong(id, after(start, sent + rtt))
+    }
+
+    #[test]
+    fn pings_are_sent_once_an_interval() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        assert!(latency.ping(start).is_some());
+        assert!(latency.ping(after(start, PING_INTERVAL_MS - 1)).is_none());
+        assert!(latency.ping(after(start, PING_INTERVAL_MS)).is_some());
+    }
+
+    #[test]
+    fn the_first_round_trip_is_taken_as_is() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        assert_eq!(latency.rtt(), None);
+        assert_eq!(latency.jitter(), None);
+
+        assert_close(round_trip(&mut latency, start, 0, 100), 0.1);
+        assert_close(latency.jitter(), 0.0);
+    }
+
+    #[test]
+    fn later_round_trips_are_smoothed() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        round_trip(&mut latency, start, 0, 100);
+
+        let rtt = round_trip(&mut latency, start, 1000, 200);
+        assert_close(rtt, 0.1 + 0.1 * SMOOTHING);
+        assert_close(latency.rtt(), 0.1 + 0.1 * SMOOTHING);
+        assert_close(latency.jitter(), 0.1 * SMOOTHING);
+    }
+
+    #[test]
+    fn unknown_and_repeated_pongs_are_ignored() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        let id = latency.ping(start).unwrap();
+
+        assert_eq!(latency.pong(id + 1, after(start, 50)), None);
+        assert!(latency.pong(id, after(start, 50)).is_some());
+        assert_eq!(latency.pong(id, after(start, 60)), None);
+        assert_close(latency.rtt(), 0.05);
+    }
+
+    #[test]
+    fn pings_unanswered_for_too_long_are_given_up_on() {
+        let start = Instant::now();
+        let mut latency = Latency::new();
+        let id = latency.ping(start).unwrap();
+
+        // the next ping clears out the ones that timed out
+        latency.ping(after(start, PING_TIMEOUT_SECS * 1000)).unwrap();
+        assert_eq!(latency.pong(id, after(start, PING_TIMEOUT_SECS * 1000 + 1)), None);
+        assert_eq!(latency.rtt(), None);
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use common::{MatchID, ServerMessage};
+use super::{SharedConfig, SharedAccounts, Match, MatchHandle, JoiningPlayer};
+
+/// Routes incoming players to matches, starting new matches as needed.
+pub struct Lobby {
+    config: SharedConfig,
+    /// Handed to each match, for `/reload`.
+    accounts: SharedAccounts,
+    matches: HashMap<MatchID, MatchHandle>,
+    queue: Vec<JoiningPlayer>,
+    next_match_id: u32,
+}
+
+impl Lobby {
+    pub fn new(config: SharedConfig, accounts: SharedAccounts) -> Self {
+        Lobby {
+            config,
+            accounts,
+            matches: HashMap::new(),
+            queue: Vec::new(),
+            next_match_id: 0,
+        }
+    }
+
+    pub fn match_ids(&self) -> Vec<MatchID> {
+        self.matches
+            .iter()
+            .filter(|&(_, m)| !m.is_finished())
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    pub fn player_count(&self, id: MatchID) -> Option<usize> {
+        self.matches.get(&id).map(|m| m.player_count())
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Everyone in a match or the matchmaking queue.
+    pub fn total_player_count(&self) -> usize {
+        self.matches.values().map(|m| m.player_count()).sum::<usize>() + self.queue.len()
+    }
+
+    /// Sends `player` to the match with the given id, starting it if it isn't
+    /// running. If `id` is `None`, the player waits in the matchmaking queue
+    /// instead.
+    pub fn route(&mut self, player: JoiningPlayer, id: Option<MatchID>) {
+        self.matches.retain(|_, m| !m.is_finished());
+
+        match id {
+            Some(id) => self.join(player, id),
+            None => self.enqueue(player),
+        }
+    }
+
+    fn join(&mut self, player: JoiningPlayer, id: MatchID) {
+        let player = match self.matches.get(&id) {
+            Some(handle) => {
+                println!("Routing {} to match {}", player.name, id.0);
+                match handle.add_player(player) {
+                    Ok(()) => return,
+                    // it finished just now
+                    Err(player) => player,
+                }
+            }
+            None => player,
+        };
+        self.matches.remove(&id);
+
+        if !self.has_room_for_match() {
+            kick(player, "The server is running as many matches as it can.".into());
+        } else {
+            println!("Routing {} to match {}", player.name, id.0);
+            let _ = self.start_match(id).add_player(player);
+        }
+    }
+
+    fn enqueue(&mut self, player: JoiningPlayer) {
+        println!("{} joined the matchmaking queue", player.name);
+        self.queue.push(player);
+        // anyone who left while waiting
+        self.queue.retain(|p| !p.connection.is_closed());
+
+        let queue_match_size = self.config.read().unwrap().queue_match_size();
+        if self.queue.len() >= queue_match_size && self.has_room_for_match() {
+            let id = self.next_free_match_id();
+            let players = self.queue
+                .drain(..queue_match_size)
+                .collect::<Vec<JoiningPlayer>>();
+
+            println!("Matchmaking queue filled, starting match {}", id.0);
+            let handle = self.start_match(id);
+            for player in players {
+                let _ = handle.add_player(player);
+            }
+        }
+    }
+
+    fn has_room_for_match(&self) -> bool {
+        self.matches.len() < self.config.read().unwrap().max_matches
+    }
+
+    fn next_free_match_id(&mut self) -> MatchID {
+        while self.matches.contains_key(&MatchID(self.next_match_id)) {
+            self.next_match_id = self.next_match_id.wrapping_add(1);
+        }
+        MatchID(self.next_match_id)
+    }
+
+    fn start_match(&mut self, id: MatchID) -> &MatchHandle {
+        let handle = Match::new(id, self.config.clone(), self.accounts.clone()).spawn();
+        self.matches.entry(id).or_insert(handle)
+    }
+}
+
+fn kick(mut player: JoiningPlayer, reason: String) {
+    println!("Rejecting {}: {}", player.name, reason);
+    let _ = player.connection.write_message(ServerMessage::Kick { reason });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::{Arc, RwLock};
+    use common::{MatchID, ServerMessage};
+    use super::super::{Accounts, Config, Connection, JoiningPlayer};
+    use super::*;
+
+    fn lobby(max_matches: usize, max_players: usize) -> Lobby {
+        let mut config = Config::default();
+        config.max_matches = max_matches;
+        config.max_players = max_players;
+        config.audit_log = env::temp_dir()
+            .join("moba-lobby-test.log")
+            .to_string_lossy()
+            .into_owned();
+        Lobby::new(
+            Arc::new(RwLock::new(config)),
+            Arc::new(RwLock::new(Accounts::empty())),
+        )
+    }
+
+    /// A player, and a handle to see what they were sent.
+    fn player(name: &str) -> (JoiningPlayer, Connection) {
+        let connection = Connection::detached();
+        let watcher = connection.duplicate();
+        let player = JoiningPlayer {
+            connection,
+            name: name.into(),
+            team: None,
+            capabilities: Vec::new(),
+            player_id: None,
+        };
+        (player, watcher)
+    }
+
+    fn was_kicked(connection: &Connection) -> bool {
+        connection.sent_messages().iter().any(|message| match *message {
+            ServerMessage::Kick { .. } => true,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn joining_by_id_starts_that_match() {
+        let mut lobby = lobby(8, 10);
+        let (alice, alice_connection) = player("alice");
+        let (bob, bob_connection) = player("bob");
+
+        lobby.route(alice, Some(MatchID(3)));
+        lobby.route(bob, Some(MatchID(3)));
+
+        assert_eq!(lobby.match_ids(), vec![MatchID(3)]);
+        assert!(!was_kicked(&alice_connection));
+        assert!(!was_kicked(&bob_connection));
+    }
+
+    #[test]
+    fn no_more_than_max_matches_are_started() {
+        let mut lobby = lobby(1, 10);
+        let (alice, alice_connection) = player("alice");
+        let (bob, bob_connection) = player("bob");
+
+        lobby.route(alice, Some(MatchID(1)));
+        lobby.route(bob, Some(MatchID(2)));
+
+        assert_eq!(lobby.match_ids(), vec![MatchID(1)]);
+        assert!(!was_kicked(&alice_connection));
+        assert!(was_kicked(&bob_connection));
+    }
+
+    #[test]
+    fn running_matches_can_be_joined_at_capacity() {
+        let mut lobby = lobby(1, 10);
+        let (alice, _) = player("alice");
+        let (bob, bob_connection) = player("bob");
+
+        lobby.route(alice, Some(MatchID(1)));
+        lobby.route(bob, Some(MatchID(1)));
+
+        assert!(!was_kicked(&bob_connection));
+    }
+
+    #[test]
+    fn a_full_queue_starts_a_match() {
+        let mut lobby = lobby(8, 2);
+        let (alice, _) = player("alice");
+        let (bob, _) = player("bob");
+
+        lobby.route(alice, None);
+        assert_eq!(lobby.queue_len(), 1);
+        assert!(lobby.match_ids().is_empty());
+
+        lobby.route(bob, None);
+        assert_eq!(lobby.queue_len(), 0);
+        assert_eq!(lobby.match_ids().len(), 1);
+    }
+
+    #[test]
+    fn players_who_left_the_queue_dont_count() {
+        let mut lobby = lobby(8, 2);
+        let (alice, alice_connection) = player("alice");
+        let (bob, _) = player("bob");
+
+        lobby.route(alice, None);
+        alice_connection.close("gone");
+        lobby.route(bob, None);
+
+        assert_eq!(lobby.queue_len(), 1);
+        assert!(lobby.match_ids().is_empty());
+    }
+
+    #[test]
+    fn the_queue_waits_for_room_for_a_match() {
+        let mut lobby = lobby(1, 1);
+        let (alice, _) = player("alice");
+        let (bob, _) = player("bob");
+
+        lobby.route(alice, Some(MatchID(5)));
+        lobby.route(bob, None);
+
+        assert_eq!(lobby.queue_len(), 1);
+        assert_eq!(lobby.match_ids(), vec![MatchID(5)]);
+    }
+}
@@ -0,0 +1,826 @@
+use std::thread;
+use std::time;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+
+use common::{self, ClientMessage, ServerMessage, Game, logic, Point, EntityID, Event,
+             Team, MatchID, ChatChannel, NetStats, PlayerID};
+use super::{SharedConfig, SharedAccounts, Accounts, Connection, Chat, ChatMember, AdminCommand, AuditLog, Pause, Pauser,
+            Latency, system_message};
+
+/// A player on their way to a match.
+pub struct JoiningPlayer {
+    pub connection: Connection,
+    pub name: String,
+    pub team: Option<Team>,
+    /// Negotiated with the player's client.
+    pub capabilities: Vec<String>,
+    /// Set if they signed in to an account.
+    pub player_id: Option<PlayerID>,
+}
+
+/// How many ticks a unit's position keeps being sent after it stops, so that
+/// clients on UDP get it even if some of those messages are lost.
+const SETTLE_TICKS: u32 = 10;
+
+/// How often clients are sent everyone's `NetStats`.
+const NET_STATS_INTERVAL_MS: u64 = 1000;
+
+/// How often clients are sent their hero's cooldowns, besides whenever an
+/// ability is used.
+const COOLDOWN_SYNC_INTERVAL_MS: u64 = 1000;
+
+/// How long a match is kept running with no one in it.
+const EMPTY_MATCH_SECS: u64 = 30;
+
+/// The lobby's view of a running match.
+pub struct MatchHandle {
+    joining_players: Arc<Mutex<Vec<JoiningPlayer>>>,
+    player_count: Arc<AtomicUsize>,
+    finished: Arc<AtomicBool>,
+}
+
+impl MatchHandle {
+    /// Gives the player back if the match has finished.
+    pub fn add_player(&self, player: JoiningPlayer) -> Result<(), JoiningPlayer> {
+        let mut joining_players = self.joining_players.lock().unwrap();
+        // set while holding joining_players, so no one is added after the match has looked
+        if self.finished.load(Ordering::SeqCst) {
+            return Err(player);
+        }
+        joining_players.push(player);
+        Ok(())
+    }
+
+    /// Once a match has been empty for a while, its thread exits and it can
+    /// be forgotten.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// Includes players who have been routed here but not yet added to the game.
+    pub fn player_count(&self) -> usize {
+        self.player_count.load(Ordering::SeqCst) + self.joining_players.lock().unwrap().len()
+    }
+}
+
+pub struct Match {
+    id: MatchID,
+    config: SharedConfig,
+    accounts: SharedAccounts,
+    game: Game,
+    chat: Chat,
+    admins: HashSet<EntityID>,
+    audit_log: AuditLog,
+    pause: Pause,
+    streams: HashMap<EntityID, Connection>,
+    /// Negotiated with each player's client.
+    capabilities: HashMap<EntityID, Vec<String>>,
+    /// Players who signed in to an account.
+    player_ids: HashMap<EntityID, PlayerID>,
+    /// Only for players whose clients answer pings.
+    latencies: HashMap<EntityID, Latency>,
+    last_net_stats: time::Instant,
+    last_cooldown_sync: time::Instant,
+    /// Units that have stopped moving, and for how many ticks.
+    settling: HashMap<EntityID, u32>,
+    joining_players: Arc<Mutex<Vec<JoiningPlayer>>>,
+    player_count: Arc<AtomicUsize>,
+    finished: Arc<AtomicBool>,
+    /// Set once a team surrenders. Everyone has been sent away, and the
+    /// match stops.
+    over: bool,
+}
+
+impl Match {
+    pub fn new(id: MatchID, config: SharedConfig, accounts: SharedAccounts) -> Self {
+        let mut game = Game::new();
+        game.set_map(config.read().unwrap().map);
+        game.keep_history();
+        let audit_log = AuditLog::open(&config.read().unwrap().audit_log);
+        let pause = Pause::new(config.read().unwrap().pause_budget);
+
+        Match {
+            id,
+            config,
+            accounts,
+            game,
+            chat: Chat::new(),
+            admins: HashSet::new(),
+            audit_log,
+            pause,
+            streams: HashMap::new(),
+            capabilities: HashMap::new(),
+            player_ids: HashMap::new(),
+            latencies: HashMap::new(),
+            last_net_stats: time::Instant::now(),
+            last_cooldown_sync: time::Instant::now(),
+            settling: HashMap::new(),
+            joining_players: Arc::new(Mutex::new(Vec::new())),
+            player_count: Arc::new(AtomicUsize::new(0)),
+            finished: Arc::new(AtomicBool::new(false)),
+            over: false,
+        }
+    }
+
+    /// Runs the match on its own tick thread.
+    pub fn spawn(mut self) -> MatchHandle {
+        let handle = MatchHandle {
+            joining_players: self.joining_players.clone(),
+            player_count: self.player_count.clone(),
+            finished: self.finished.clone(),
+        };
+
+        thread::spawn(move || self.run());
+
+        handle
+    }
+
+    fn run(&mut self) {
+        println!("Starting match {}", self.id.0);
+
+        let mut id = 0;
+        let mut empty_since = time::Instant::now();
+
+        loop {
+            // println!("Starting tick {}", id);
+            let start_time = time::Instant::now();
+
+            // read every tick, in case the config was reloaded
+            let tick_rate = self.config.read().unwrap().tick_rate;
+            let tick_dur = time::Duration::from_secs(1) / tick_rate;
+
+            self.tick(1.0 / tick_rate as f64);
+
+            let elapsed_tick_dur = start_time.elapsed();
+            if elapsed_tick_dur < tick_dur {
+                thread::sleep(tick_dur - elapsed_tick_dur);
+            } else {
+                println!("Match {}: fully used tick time!!!", self.id.0);
+            }
+
+            id += 1;
+
+            if self.over {
+                if self.try_finish() {
+                    println!("Match {} is over, stopping it", self.id.0);
+                    return;
+                }
+            } else if !self.streams.is_empty() {
+                empty_since = time::Instant::now();
+            } else if empty_since.elapsed() > time::Duration::from_secs(EMPTY_MATCH_SECS) &&
+                       self.try_finish()
+            {
+                println!("Match {} is empty, stopping it", self.id.0);
+                return;
+            }
+        }
+    }
+
+    /// Marks the match finished, unless someone is on their way in.
+    fn try_finish(&mut self) -> bool {
+        let joining_players = self.joining_players.lock().unwrap();
+        if !joining_players.is_empty() {
+            return false;
+        }
+        self.finished.store(true, Ordering::SeqCst);
+        true
+    }
+
+    // Writes only fail once a connection has closed, and the player is removed
+    // the next time we read from it, so failures are ignored here.
+    fn broadcast(&mut self, message: ServerMessage) {
+        for stream in self.streams.values_mut() {
+            let _ = stream.write_message(message.clone());
+        }
+    }
+
+    fn send_to(&mut self, id: EntityID, message: ServerMessage) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            let _ = stream.write_message(message);
+        }
+    }
+
+    fn chat_members(&mut self) -> Vec<ChatMember> {
+        let game = &mut self.game;
+        game.players()
+            .to_owned()
+            .into_iter()
+            .map(|id| {
+                ChatMember {
+                    id,
+                    name: game.with_component::<common::Player, _, _>(id, |p| p.name().to_string())
+                        .unwrap(),
+                    team: game.clone_component::<Team>(id),
+                }
+            })
+            .collect()
+    }
+
+    fn handle_chat(
+        &mut self,
+        sender: EntityID,
+        message: String,
+        channel: ChatChannel,
+    ) -> Vec<Event> {
+        let members = self.chat_members();
+        let sender = match members.iter().find(|m| m.id == sender) {
+            Some(sender) => sender.clone(),
+            None => return Vec::new(),
+        };
+
+        let command = AdminCommand::parse(message.trim());
+        // rate limited like chat, so passwords can't be guessed at tick rate
+        if command.is_some() && self.chat.is_flooding(sender.id) {
+            self.reply(sender.id, "You are sending messages too quickly.".into());
+            return Vec::new();
+        }
+
+        match command {
+            Some(Ok(command)) => return self.run_admin_command(&sender, command, &members),
+            Some(Err(usage)) => {
+                let (id, message) = system_message(sender.id, usage);
+                self.send_to(id, message);
+                return Vec::new();
+            }
+            None => {}
+        }
+
+        for (id, message) in self.chat.handle_message(&sender, message, channel, &members) {
+            self.send_to(id, message);
+        }
+
+        match self.chat.take_surrender() {
+            Some(team) => self.surrender(team),
+            None => Vec::new(),
+        }
+    }
+
+    /// Ends the match with every other team winning, and sends everyone away.
+    fn surrender(&mut self, team: Team) -> Vec<Event> {
+        println!("Match {}: team {} surrendered", self.id.0, team.0);
+        self.over = true;
+
+        let players = self.streams.keys().cloned().collect::<Vec<_>>();
+        let mut events = Vec::new();
+        for id in players {
+            let reason = if self.game.clone_component::<Team>(id) == Some(team) {
+                "Your team surrendered.".to_string()
+            } else {
+                format!("Team {} surrendered. You win!", team.0)
+            };
+            self.send_to(id, ServerMessage::Kick { reason });
+            events.extend(self.remove_player(id));
+        }
+        events
+    }
+
+    fn reply(&mut self, to: EntityID, message: String) {
+        let (id, message) = system_message(to, message);
+        self.send_to(id, message);
+    }
+
+    fn run_admin_command(
+        &mut self,
+        sender: &ChatMember,
+        command: AdminCommand,
+        members: &[ChatMember],
+    ) -> Vec<Event> {
+        // audited by account where there is one, since names can be reused
+        let admin = match self.player_ids.get(&sender.id) {
+            Some(player_id) => format!("{} (player {})", sender.name, player_id.0),
+            None => format!("{} (guest)", sender.name),
+        };
+
+        if let AdminCommand::Login { password } = command {
+            let correct = match self.config.read().unwrap().admin_password {
+                Some(ref admin_password) => *admin_password == password,
+                None => false,
+            };
+
+            if correct {
+                self.admins.insert(sender.id);
+                self.audit_log.record(self.id, &admin, "logged in");
+                self.reply(sender.id, "Logged in as admin.".into());
+            } else {
+                self.audit_log.record(self.id, &admin, "failed login");
+                self.reply(sender.id, "Incorrect password.".into());
+            }
+            return Vec::new();
+        }
+
+        if !self.admins.contains(&sender.id) {
+            self.reply(sender.id, "You need to /login first.".into());
+            return Vec::new();
+        }
+
+        self.audit_log.record(self.id, &admin, &format!("{:?}", command));
+
+        let find = |name: &str| members.iter().find(|m| m.name == name).map(|m| m.id);
+        let mut events = Vec::new();
+
+        match command {
+            AdminCommand::Login { .. } => unreachable!(),
+            AdminCommand::Kick { name, reason } => {
+                match find(&name) {
+                    Some(id) => {
+                        self.send_to(id, ServerMessage::Kick { reason: reason.clone() });
+                        events.extend(self.remove_player(id));
+                        self.broadcast_system(format!("{} was kicked: {}", name, reason));
+                    }
+                    None => self.reply(sender.id, format!("No player named {}.", name)),
+                }
+            }
+            AdminCommand::SetTeam { name, team } => {
+                match find(&name) {
+                    Some(id) => events.push(Event::SetTeam { id, team }),
+                    None => self.reply(sender.id, format!("No player named {}.", name)),
+                }
+            }
+            AdminCommand::Pause => {
+                let name = format!("{} (admin)", sender.name);
+                let result = self.pause.pause(&name, Pauser::Admin, time::Instant::now());
+                self.handle_pause_result(sender.id, result);
+            }
+            AdminCommand::Resume => {
+                let result = self.pause.unpause(Pauser::Admin, time::Instant::now());
+                self.handle_pause_result(sender.id, result);
+            }
+            AdminCommand::Spawn { hero, position } => {
+                let id = self.game.next_entity_id();
+                let name = format!("{:?} (bot)", hero);
+                let team = None;
+                self.game.add_hero(id, hero, name.clone(), position, team);
+                // added directly, since running an AddHero event would register a player
+                self.broadcast(ServerMessage::Events(vec![
+                    Event::AddHero {
+                        id,
+                        hero,
+                        position,
+                        name,
+                        team,
+                    },
+                ]));
+            }
+            AdminCommand::SetHitpoints { name, hitpoints } => {
+                match find(&name) {
+                    Some(id) => {
+                        events.push(Event::SetHitpoints {
+                            id,
+                            current: hitpoints,
+                        })
+                    }
+                    None => self.reply(sender.id, format!("No player named {}.", name)),
+                }
+            }
+            AdminCommand::Reload => {
+                let reloaded = self.config
+                    .read()
+                    .unwrap()
+                    .reload()
+                    .map_err(|err| err.to_string())
+                    .and_then(|config| {
+                        Accounts::for_config(&config).map(|accounts| (config, accounts))
+                    });
+                match reloaded {
+                    Ok((config, accounts)) => {
+                        let message = format!(
+                            "Config reloaded, with {} registered players.",
+                            accounts.len()
+                        );
+                        *self.config.write().unwrap() = config;
+                        *self.accounts.write().unwrap() = accounts;
+                        self.reply(sender.id, message);
+                    }
+                    Err(err) => self.reply(sender.id, format!("Reload failed: {}", err)),
+                }
+            }
+            AdminCommand::ShowConfig => {
+                let config = self.config.read().unwrap().to_display_string();
+                for line in config.lines() {
+                    self.reply(sender.id, line.into());
+                }
+            }
+            AdminCommand::NetStats { name } => {
+                let players: Vec<&ChatMember> = match name {
+                    Some(name) => members.iter().filter(|m| m.name == name).collect(),
+                    None => members.iter().collect(),
+                };
+                if players.is_empty() {
+                    self.reply(sender.id, "No such player.".into());
+                }
+                for member in players {
+                    if let Some(stats) = self.net_stats(member.id) {
+                        let line = format!("{}: {}", member.name, stats);
+                        self.reply(sender.id, line);
+                    }
+                }
+            }
+        }
+
+        self.game.run_events(&events);
+        events
+    }
+
+    fn handle_pause_result(&mut self, player: EntityID, result: Result<ServerMessage, String>) {
+        match result {
+            Ok(message) => self.broadcast(message),
+            Err(reason) => self.reply(player, reason),
+        }
+    }
+
+    fn broadcast_system(&mut self, message: String) {
+        self.broadcast(ServerMessage::ReceiveChat {
+            user: "".into(),
+            message,
+            channel: ChatChannel::System,
+        });
+    }
+
+    /// Returns the event for removing the player, if they were still in the match.
+    fn remove_player(&mut self, id: EntityID) -> Option<Event> {
+        if self.streams.remove(&id).is_none() {
+            return None;
+        }
+
+        self.chat.remove_member(id);
+        self.admins.remove(&id);
+        self.capabilities.remove(&id);
+        self.player_ids.remove(&id);
+        self.latencies.remove(&id);
+        self.game.remove_entity(id);
+        Some(Event::RemoveEntity(id))
+    }
+
+    fn team_player_count(&mut self, team: Team) -> usize {
+        let game = &mut self.game;
+        game.players()
+            .to_owned()
+            .into_iter()
+            .filter(|&p| game.clone_component::<Team>(p) == Some(team))
+            .count()
+    }
+
+    /// Makes sure a joining player's name is unique in the match, or returns
+    /// why they can't join. Someone signing in again replaces their old
+    /// connection, which may be one that hasn't timed out yet.
+    fn make_room_for(&mut self, name: &str, player_id: Option<PlayerID>) -> Result<(), String> {
+        if let Some(player_id) = player_id {
+            let old = self.player_ids
+                .iter()
+                .find(|&(_, &p)| p == player_id)
+                .map(|(&id, _)| id);
+            if let Some(old) = old {
+                let reason = "You signed in from somewhere else.".to_string();
+                self.send_to(old, ServerMessage::Kick { reason });
+                if let Some(event) = self.remove_player(old) {
+                    self.broadcast(ServerMessage::Events(vec![event]));
+                }
+            }
+        }
+
+        let game = &mut self.game;
+        let taken = game.players().to_owned().into_iter().any(|p| {
+            game.with_component::<common::Player, _, _>(p, |c| c.name().to_lowercase())
+                .map_or(false, |n| n == name.to_lowercase())
+        });
+        if taken {
+            Err(format!("Someone called {} is already in this match.", name))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Picks a team for a joining player according to the game mode,
+    /// or returns the reason they can't join.
+    fn assign_team(&mut self, requested: Option<Team>) -> Result<Option<Team>, String> {
+        let config = self.config.read().unwrap().clone();
+        if self.streams.len() >= config.max_players {
+            return Err("The match is full.".into());
+        }
+
+        // teams are unrestricted in free-for-all
+        let team_count = config.game_mode.team_count();
+        if team_count == 0 {
+            return Ok(requested);
+        }
+
+        let team = match requested {
+            Some(team) => team,
+            None => {
+                (0..team_count)
+                    .map(Team)
+                    .min_by_key(|&t| self.team_player_count(t))
+                    .unwrap()
+            }
+        };
+
+        if team.0 >= team_count {
+            Err(format!("Team {} doesn't exist.", team.0))
+        } else if self.team_player_count(team) >= config.team_size {
+            Err(format!("Team {} is full.", team.0))
+        } else {
+            Ok(Some(team))
+        }
+    }
+
+    fn tick(&mut self, time: f64) {
+        let new_names = {
+            let jp = self.joining_players
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<JoiningPlayer>>();
+
+            let mut new_names = Vec::new();
+            for joining in jp {
+                let JoiningPlayer {
+                    connection: mut stream,
+                    name,
+                    team,
+                    capabilities,
+                    player_id,
+                } = joining;
+
+                let team = if self.over {
+                    Err("That match is over.".to_string())
+                } else {
+                    match self.make_room_for(&name, player_id) {
+                        Ok(()) => self.assign_team(team),
+                        Err(reason) => Err(reason),
+                    }
+                };
+                let team = match team {
+                    Ok(team) => team,
+                    Err(reason) => {
+                        println!("Rejecting {}: {}", name, reason);
+                        let _ = stream.write_message(ServerMessage::Kick { reason });
+                        continue;
+                    }
+                };
+
+                new_names.push(name.clone());
+                let id = self.game.next_entity_id();
+                let position = Point::new(0.0, 0.0);
+                let hero = logic::HeroKind::John;
+                let _ = stream.write_message(ServerMessage::SetPlayerEntityID(id));
+                let _ = stream.write_message(ServerMessage::Events(self.game.events_for_loading()));
+                self.game.add_player(id, hero, name.clone(), position, team);
+                self.streams.insert(id, stream);
+                self.capabilities.insert(id, capabilities);
+                if let Some(player_id) = player_id {
+                    self.player_ids.insert(id, player_id);
+                }
+                if self.has_capability(id, common::CAPABILITY_SERVER_PING) {
+                    self.latencies.insert(id, Latency::new());
+                }
+                self.broadcast(ServerMessage::Events(vec![
+                    Event::AddHero {
+                        id,
+                        hero,
+                        position,
+                        name,
+                        team,
+                    },
+                ]));
+
+            }
+            new_names
+        };
+
+        for name in new_names {
+            self.broadcast_system(format!("{} has connected!", name));
+        }
+
+        let mut commands = Vec::new();
+        let mut chats = Vec::new();
+        let mut pause_requests = Vec::new();
+        let mut events = Vec::new();
+        let mut players_to_remove = Vec::new();
+        let mut pongs = Vec::new();
+
+        for player in self.game.players().to_owned() {
+            let mut stream = self.streams.get_mut(&player).unwrap();
+            while let Some(message) = stream.try_get_message() {
+                if let Err(err) = message {
+                    println!("Error from client stream: {:?}", err);
+                    players_to_remove.push(player);
+                    break;
+                }
+
+                match message.unwrap() {
+                    ClientMessage::Ping { id } => {
+                        let _ = stream.write_message(ServerMessage::ReturnPing { id: id });
+                    }
+                    ClientMessage::ReturnPing { id } => pongs.push((player, id)),
+                    ClientMessage::Quit {} => {
+                        println!(
+                            "Quit: {}",
+                            self.game
+                                .with_component::<common::Player, _, _>(
+                                    player,
+                                    |c| { c.name().to_string() }
+                                )
+                                .unwrap()
+                        );
+
+                        players_to_remove.push(player);
+                        break;
+                    }
+                    ClientMessage::SendChat { message, channel } => {
+                        chats.push((player, message, channel))
+                    }
+                    ClientMessage::Command(command) => commands.push((command, player)),
+                    ClientMessage::Pause => pause_requests.push((player, true)),
+                    ClientMessage::Unpause => pause_requests.push((player, false)),
+                    _ => {}
+                }
+            }
+        }
+
+        self.update_latencies(pongs);
+        self.send_net_stats();
+
+        for (player, message, channel) in chats {
+            let es = self.handle_chat(player, message, channel);
+            events.extend(es);
+        }
+
+        let now = time::Instant::now();
+        for (player, pause) in pause_requests {
+            let by = Pauser::player(player, self.game.clone_component::<Team>(player));
+            let result = if pause {
+                let name = self.game
+                    .with_component::<common::Player, _, _>(player, |p| p.name().to_string())
+                    .unwrap();
+                self.pause.pause(&name, by, now)
+            } else {
+                self.pause.unpause(by, now)
+            };
+            self.handle_pause_result(player, result);
+        }
+
+        for message in self.pause.update(now) {
+            self.broadcast(message);
+        }
+
+        for player in players_to_remove {
+            events.extend(self.remove_player(player));
+        }
+
+        self.player_count.store(self.streams.len(), Ordering::SeqCst);
+
+        if !self.pause.is_paused() {
+            for (command, id) in commands {
+                if !self.streams.contains_key(&id) {
+                    continue;
+                }
+
+                let es = self.game.run_command(command, id);
+                self.game.run_events(&es);
+                events.extend(es);
+            }
+
+            events.extend(self.game.tick(time));
+        }
+
+        self.sync_cooldowns(&events);
+        self.broadcast_events(events);
+    }
+
+    /// Handles answered pings, lag compensating players by their round trip
+    /// time, and sends the pings that are due.
+    fn update_latencies(&mut self, pongs: Vec<(EntityID, u64)>) {
+        let now = time::Instant::now();
+
+        for (player, id) in pongs {
+            let rtt = match self.latencies.get_mut(&player) {
+                Some(latency) => latency.pong(id, now),
+                None => None,
+            };
+            if let Some(rtt) = rtt {
+                self.game.set_lag_compensation(player, rtt);
+            }
+        }
+
+        let mut pings = Vec::new();
+        for (&player, latency) in &mut self.latencies {
+            if let Some(id) = latency.ping(now) {
+                pings.push((player, id));
+            }
+        }
+        for (player, id) in pings {
+            self.send_to(player, ServerMessage::Ping { id });
+        }
+    }
+
+    fn has_capability(&self, player: EntityID, capability: &str) -> bool {
+        self.capabilities
+            .get(&player)
+            .map_or(false, |c| c.iter().any(|c| c == capability))
+    }
+
+    /// `None` if `player` isn't connected.
+    fn net_stats(&self, player: EntityID) -> Option<NetStats> {
+        let traffic = match self.streams.get(&player) {
+            Some(stream) => stream.traffic(),
+            None => return None,
+        };
+        let latency = self.latencies.get(&player);
+        let ms = |secs: f64| (secs * 1000.0).round() as u32;
+
+        Some(NetStats {
+            rtt_ms: latency.and_then(Latency::rtt).map(&ms),
+            jitter_ms: latency.and_then(Latency::jitter).map(&ms),
+            packets_in: traffic.packets_in,
+            packets_out: traffic.packets_out,
+            bytes_in: traffic.bytes_in,
+            bytes_out: traffic.bytes_out,
+            queued_bytes: traffic.queued_bytes as u64,
+        })
+    }
+
+    /// Sends everyone's stats to the clients that show them, when it's time.
+    fn send_net_stats(&mut self) {
+        if self.last_net_stats.elapsed() < time::Duration::from_millis(NET_STATS_INTERVAL_MS) {
+            return;
+        }
+        self.last_net_stats = time::Instant::now();
+
+        let players: Vec<EntityID> = self.streams.keys().cloned().collect();
+        let stats: Vec<(EntityID, NetStats)> = players
+            .iter()
+            .filter_map(|&id| self.net_stats(id).map(|stats| (id, stats)))
+            .collect();
+        for id in players {
+            if self.has_capability(id, common::CAPABILITY_NET_STATS) {
+                self.send_to(id, ServerMessage::NetStats(stats.clone()));
+            }
+        }
+    }
+
+    /// Sends each player their hero's cooldowns when they use an ability, and
+    /// now and then in case that was lost. Their client counts down in
+    /// between.
+    fn sync_cooldowns(&mut self, events: &[Event]) {
+        let due = self.last_cooldown_sync.elapsed() >=
+            time::Duration::from_millis(COOLDOWN_SYNC_INTERVAL_MS);
+        if due {
+            self.last_cooldown_sync = time::Instant::now();
+        }
+
+        let players: Vec<EntityID> = self.streams.keys().cloned().collect();
+        for id in players {
+            if !self.has_capability(id, common::CAPABILITY_COOLDOWNS) {
+                continue;
+            }
+            let used = events.iter().any(|event| match *event {
+                Event::AbilityUsed { id: user, .. } => user == id,
+                _ => false,
+            });
+            if !due && !used {
+                continue;
+            }
+
+            let cooldowns = self.game.clone_component::<common::AbilityCooldowns>(id);
+            if let Some(cooldowns) = cooldowns {
+                let event = Event::SetCooldowns {
+                    id,
+                    remaining: cooldowns.remaining,
+                };
+                self.send_to(id, ServerMessage::Events(vec![event]));
+            }
+        }
+    }
+
+    /// Moves go in a message of their own, which UDP clients may not get.
+    fn broadcast_events(&mut self, events: Vec<Event>) {
+        let (mut moves, others): (Vec<Event>, Vec<Event>) =
+            events.into_iter().partition(Event::is_superseded);
+
+        let mut settling = HashMap::new();
+        for event in &moves {
+            if let Event::EntityMove(id, _) = *event {
+                settling.insert(id, 0);
+            }
+        }
+        for (id, ticks) in self.settling.drain() {
+            if settling.contains_key(&id) || ticks >= SETTLE_TICKS {
+                continue;
+            }
+            if let Some(point) = self.game
+                .with_component::<common::Position, _, _>(id, |p| p.point)
+            {
+                moves.push(Event::EntityMove(id, point));
+                settling.insert(id, ticks + 1);
+            }
+        }
+        self.settling = settling;
+
+        self.broadcast(ServerMessage::Events(others));
+        if !moves.is_empty() {
+            self.broadcast(ServerMessage::Events(moves));
+        }
+    }
+}
@@ -1,223 +1,203 @@
-use std::net::{self, TcpListener, TcpStream};
 use std::io;
 use std::thread;
-use std::time;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use rustls;
 
-use common::{self, Message, Stream, Game, logic, Point, EntityID, Event, Team};
+use common::{self, ClientMessage, LinkConditions, ServerMessage};
 
-const TICKS_PER_SECOND: u32 = 60;
+mod matches;
+pub use self::matches::*;
 
-pub struct Server {
-    game: Game,
-    streams: HashMap<EntityID, Stream>,
-    joining_players: Arc<Mutex<Vec<(Stream, String, Option<Team>)>>>,
-}
+mod lobby;
+pub use self::lobby::*;
 
-impl Server {
-    pub fn new() -> Self {
-        Server {
-            game: Game::new(),
-            joining_players: Arc::new(Mutex::new(Vec::new())),
-            streams: HashMap::new(),
-        }
-    }
+mod config;
+pub use self::config::*;
 
-    pub fn serve(&mut self, port: u16) {
-        let jp = self.joining_players.clone();
-        thread::spawn(move || {
-            let addr = net::SocketAddrV4::new(net::Ipv4Addr::new(127, 0, 0, 1), port); // change to 0.0.0.0 to accept from all locations
+mod chat;
+pub use self::chat::*;
 
-            let listener = TcpListener::bind(addr).unwrap();
+mod admin;
+pub use self::admin::*;
 
-            // accept connections and process them, spawning a new thread for each one
-            for stream in listener.incoming() {
-                let jp = jp.clone();
-                thread::spawn(move || handle_client(stream.unwrap(), jp).unwrap());
-            }
-        });
+mod pause;
+pub use self::pause::*;
 
-        self.run();
-    }
+mod discovery;
+pub use self::discovery::*;
 
-    fn run(&mut self) {
-        let tick_dur = time::Duration::from_secs(1) / TICKS_PER_SECOND;
-        let mut id = 0;
+mod net;
+pub use self::net::*;
 
-        loop {
-            // println!("Starting tick {}", id);
-            let start_time = time::Instant::now();
+mod latency;
+pub use self::latency::*;
 
-            self.tick(1.0 / TICKS_PER_SECOND as f64);
+mod accounts;
+pub use self::accounts::*;
 
-            let elapsed_tick_dur = start_time.elapsed();
-            if elapsed_tick_dur < tick_dur {
-                thread::sleep(tick_dur - elapsed_tick_dur);
-            } else {
-                println!("Fully used tick time!!!");
-            }
+mod auth;
+pub use self::auth::*;
 
-            id += 1;
+pub struct Server {
+    config: SharedConfig,
+    lobby: Arc<Mutex<Lobby>>,
+    accounts: SharedAccounts,
+    conditions: LinkConditions,
+}
+
+impl Server {
+    pub fn new(config: Config) -> Self {
+        let accounts = Accounts::for_config(&config).unwrap_or_else(|err| panic!("{}", err));
+        if config.accounts.is_some() {
+            println!("{} registered players", accounts.len());
         }
-    }
 
-    fn broadcast(&mut self, message: Message) {
-        for stream in self.streams.values_mut() {
-            stream.write_message(message.clone()).unwrap();
+        let config = Arc::new(RwLock::new(config));
+        let accounts = Arc::new(RwLock::new(accounts));
+        Server {
+            lobby: Arc::new(Mutex::new(Lobby::new(config.clone(), accounts.clone()))),
+            config,
+            accounts,
+            conditions: LinkConditions::default(),
         }
     }
 
-    fn tick(&mut self, time: f64) {
-        let new_names = {
-            let jp = {
-                let mut x = self.joining_players.lock().unwrap();
-                let y = x.clone();
-                x.clear();
-                y
-            };
-
-            let new_names = jp.iter().map(|p| p.1.clone()).collect::<Vec<String>>();
-            for (mut stream, name, team) in jp {
-                let id = self.game.next_entity_id();
-                let position = Point::new(0.0, 0.0);
-                let hero = logic::HeroKind::John;
-                stream
-                    .write_message(Message::SetPlayerEntityID(id))
-                    .unwrap();
-                stream
-                    .write_message(Message::Events(self.game.events_for_loading()))
-                    .unwrap();
-                self.game.add_player(id, hero, name.clone(), position, team);
-                self.streams.insert(id, stream);
-                self.broadcast(Message::Events(vec![
-                    Event::AddHero {
-                        id,
-                        hero,
-                        position,
-                        name,
-                        team,
-                    },
-                ]));
+    /// Simulates a bad network between the server and every client, for
+    /// testing.
+    pub fn set_link_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
 
-            }
-            new_names
-        };
+    /// The effective config, after any command line overrides or reloads.
+    pub fn config(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
 
-        for name in &new_names {
-            self.broadcast(Message::ReceiveChat {
-                user: "".into(),
-                message: format!("{} has connected!", name),
-            })
-        }
+    pub fn lobby(&self) -> Arc<Mutex<Lobby>> {
+        self.lobby.clone()
+    }
 
-        let mut commands = Vec::new();
-        let mut events = Vec::new();
-        let mut players_to_remove = Vec::new();
-
-        for player in self.game.players().to_owned() {
-            let mut stream = self.streams.get_mut(&player).unwrap();
-            while let Some(message) = stream.try_get_message() {
-                if let Err(err) = message {
-                    println!("Error from client stream: {:?}", err);
-                    players_to_remove.push(player);
-                    break;
-                }
-
-                match message.unwrap() {
-                    Message::Ping { id } => {
-                        stream
-                            .write_message(Message::ReturnPing { id: id })
-                            .unwrap()
-                    }
-                    Message::Quit {} => {
-                        println!(
-                            "Quit: {}",
-                            self.game
-                                .with_component::<common::Player, _, _>(
-                                    player,
-                                    |c| { c.name().to_string() }
-                                )
-                                .unwrap()
-                        );
-
-                        players_to_remove.push(player);
-                        break;
-                    }
-                    Message::SendChat { message } => {}
-                    Message::Command(command) => commands.push((command, player)),
-                    _ => {}
-                }
-            }
-        }
+    pub fn serve(&mut self) {
+        let addr = self.config.read().unwrap().bind_addr();
+        println!("Listening on {}", addr);
 
-        for player in players_to_remove {
-            self.streams.remove(&player);
-            self.game.remove_entity(player);
-            events.push(Event::RemoveEntity(player));
-        }
+        let udp = self.config.read().unwrap().udp;
+        let tls = self.tls_config();
+        let mut event_loop = EventLoop::bind(addr, udp, self.conditions.clone(), tls).unwrap();
 
-        for (command, id) in commands {
-            let es = self.game.run_command(command, id);
-            self.game.run_events(&es);
-            events.extend(es);
+        if self.config.read().unwrap().lan_discovery {
+            let config = self.config.clone();
+            let lobby = self.lobby.clone();
+            thread::spawn(move || if let Err(err) = answer_discovery(config, lobby) {
+                println!("LAN discovery stopped: {}", err);
+            });
         }
 
-        events.extend(self.game.tick(time));
+        let authenticator =
+            Authenticator::spawn(self.accounts.clone(), self.config.clone(), self.lobby.clone());
+        event_loop
+            .run(|connection, message| handle_client(connection, message, &authenticator))
+            .unwrap();
+    }
 
-        self.broadcast(Message::Events(events));
+    /// For clients that ask for TLS, if the server has a certificate and key.
+    fn tls_config(&self) -> Option<Arc<rustls::ServerConfig>> {
+        let config = self.config.read().unwrap();
+        let (cert, key) = match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return None,
+            _ => panic!("tls_cert and tls_key have to be set together"),
+        };
+        match common::server_config(cert, key) {
+            Ok(tls) => {
+                println!("Accepting TLS connections with {}", cert);
+                Some(tls)
+            }
+            Err(err) => panic!("Couldn't load TLS certificate {} and key {}: {}", cert, key, err),
+        }
     }
 }
 
 fn handle_client(
-    stream: TcpStream,
-    joining_players: Arc<Mutex<Vec<(Stream, String, Option<Team>)>>>,
-) -> io::Result<()> {
-    println!("Connection from {}", stream.peer_addr().unwrap());
-    let mut stream = common::Stream::new(stream);
-
-    let m = stream.get_message().unwrap();
-    let (name, team) = match m {
-        Message::Connect { name, team } => {
-            println!("Name: {}", name);
-            (name, team)
+    mut connection: Connection,
+    message: io::Result<ClientMessage>,
+    authenticator: &Authenticator,
+) {
+    let m = match message {
+        Ok(m) => m,
+        Err(err) => {
+            // most likely a build too different to even send a Connect we understand
+            println!("Couldn't read connect message from {}: {}", connection.peer_addr(), err);
+            let _ = connection.write_message(ServerMessage::Kick {
+                reason: incompatible_reason(None),
+            });
+            return;
+        }
+    };
+    let (protocol_version, capabilities, name, team, match_id) = match m {
+        ClientMessage::Connect {
+            protocol_version,
+            capabilities,
+            name,
+            team,
+            match_id,
+        } => {
+            println!("Name: {} (protocol {})", name, protocol_version);
+            (protocol_version, capabilities, name, team, match_id)
+        }
+        ClientMessage::StartTls => {
+            // servers with a certificate never hand these on
+            let _ = connection.write_message(ServerMessage::Kick {
+                reason: "This server doesn't support encryption.".into(),
+            });
+            return;
         }
         _ => {
             println!("Client didn't send connect message, returning..");
-            return Ok(());
+            return;
         }
     };
 
-    stream
-        .write_message(Message::AcceptConnection {
-            message: "Welcome to moba alpha.".into(),
-        })?;
-
-    joining_players.lock().unwrap().push((stream, name, team));
-    Ok(())
-
-    // loop {
-    // let message = match stream.get_message() {
-    // Ok(message) => message,
-    // Err(err) => {
-    // println!("Client unexpectedly quit");
-    // return Err(err);
-    // }
-    // };
-    //
-    // match message {
-    // Message::Ping { id: id } => {
-    // match stream.write_message(Message::ReturnPing { id: id }) {
-    // Ok(()) => {}
-    // Err(err) => {
-    // println!("Ping failed");
-    // return Ok(());
-    // }
-    // }
-    // }
-    // Message::Quit {} => println!("Quit: {}", name),
-    // Message::SendChat { message } => {}
-    // _ => {}
-    // }
-    // }
+    if protocol_version != common::PROTOCOL_VERSION {
+        println!("Rejecting {}: protocol {}", name, protocol_version);
+        let _ = connection.write_message(ServerMessage::Kick {
+            reason: incompatible_reason(Some(protocol_version)),
+        });
+        return;
+    }
+
+    authenticator.login(
+        connection,
+        Login {
+            name: name.trim().into(),
+            team,
+            match_id,
+            capabilities: common::common_capabilities(&capabilities),
+        },
+    );
+}
+
+fn incompatible_reason(client_version: Option<u32>) -> String {
+    match client_version {
+        Some(version) if version > common::PROTOCOL_VERSION => {
+            format!(
+                "This server is older than your client (protocol {}, yours is {}).",
+                common::PROTOCOL_VERSION,
+                version
+            )
+        }
+        Some(version) => {
+            format!(
+                "Your client is too old for this server (protocol {}, this server needs {}).",
+                version,
+                common::PROTOCOL_VERSION
+            )
+        }
+        None => {
+            format!(
+                "Your client isn't compatible with this server (protocol {}).",
+                common::PROTOCOL_VERSION
+            )
+        }
+    }
 }
@@ -0,0 +1,1130 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ByteOrder};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use rustls::{self, ServerSession, Session};
+
+use common::{self, Channel, ClientMessage, Conditioner, LinkConditions, Message, ServerMessage,
+             UdpEndpoint};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const UDP: Token = Token(2);
+const FIRST_CLIENT: usize = 3;
+
+/// How often UDP clients are checked for resends and timeouts.
+const UDP_UPDATE_MS: u64 = 20;
+/// UDP clients that haven't acked anything yet may have spoofed their address,
+/// so only this many are kept at once.
+const MAX_PENDING_UDP_CLIENTS: usize = 64;
+/// A UDP client that hasn't acked anything in this long is forgotten.
+const PENDING_UDP_TIMEOUT_MS: u64 = 3000;
+
+/// A client with this much unsent data can't keep up, and is disconnected.
+const MAX_QUEUED_BYTES: usize = 4 * 1024 * 1024;
+/// Reading from a client pauses while this many of its messages are waiting
+/// for its match to handle them.
+const MAX_QUEUED_MESSAGES: usize = 256;
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Shared between the event loop and a client's `Connection`.
+struct Queues {
+    inbound: VecDeque<ClientMessage>,
+    /// Length prefixed messages, and the channel each should go over.
+    outbound: VecDeque<(Channel, Vec<u8>)>,
+    outbound_bytes: usize,
+    /// Why the connection closed, once it has. Messages that arrived before
+    /// can still be read.
+    closed: Option<String>,
+    /// The `Connection` has been dropped, so the socket is closed once
+    /// everything queued has been sent.
+    dropped: bool,
+    /// Switched to TLS at the client's request, and the handshake is done.
+    encrypted: bool,
+    traffic: Traffic,
+}
+
+/// What has gone over a connection, counted by the event loop.
+#[derive(Clone, Debug, Default)]
+pub struct Traffic {
+    /// Messages over TCP, datagrams over UDP.
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Waiting to be sent, or over UDP, to be acknowledged.
+    pub queued_bytes: usize,
+}
+
+/// Tells the event loop that a connection has something to do.
+#[derive(Clone)]
+struct Waker {
+    pending: Arc<Mutex<HashSet<Token>>>,
+    set_readiness: SetReadiness,
+}
+
+impl Waker {
+    fn wake(&self, token: Token) {
+        self.pending.lock().unwrap().insert(token);
+        // this only fails if the event loop has stopped, and then there's no one to wake
+        let _ = self.set_readiness.set_readiness(Ready::readable());
+    }
+}
+
+/// A match's end of a client connection. Nothing here waits on the socket:
+/// writes are queued for the event loop to send, and reads take messages it
+/// has already received.
+pub struct Connection {
+    token: Token,
+    queues: Arc<Mutex<Queues>>,
+    waker: Waker,
+    peer: SocketAddr,
+}
+
+impl Connection {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Whether the client has gone, or been disconnected.
+    pub fn is_closed(&self) -> bool {
+        self.queues.lock().unwrap().closed.is_some()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.queues.lock().unwrap().encrypted
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        let queues = self.queues.lock().unwrap();
+        let mut traffic = queues.traffic.clone();
+        // over UDP, this already holds what's waiting for acks
+        traffic.queued_bytes += queues.outbound_bytes;
+        traffic
+    }
+
+    /// Fails if the connection has closed. A client that has fallen too far
+    /// behind is disconnected rather than let the queue grow forever.
+    pub fn write_message(&mut self, message: ServerMessage) -> io::Result<()> {
+        let frame = encode_frame(&message);
+
+        {
+            let mut queues = self.queues.lock().unwrap();
+            if let Some(ref reason) = queues.closed {
+                return Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason.clone()));
+            }
+
+            if queues.outbound_bytes + frame.len() > MAX_QUEUED_BYTES {
+                let reason = "the client fell too far behind".to_string();
+                queues.closed = Some(reason.clone());
+                self.waker.wake(self.token);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, reason));
+            }
+
+            queues.outbound_bytes += frame.len();
+            queues.outbound.push_back((message.channel(), frame));
+        }
+
+        self.waker.wake(self.token);
+        Ok(())
+    }
+
+    /// The next message, if one has arrived. Once the connection has closed
+    /// and every message has been read, this returns why it closed.
+    pub fn try_get_message(&mut self) -> Option<io::Result<ClientMessage>> {
+        let mut queues = self.queues.lock().unwrap();
+        let was_full = queues.inbound.len() >= MAX_QUEUED_MESSAGES;
+
+        match queues.inbound.pop_front() {
+            Some(message) => {
+                if was_full {
+                    // reading stopped when the queue filled up
+                    self.waker.wake(self.token);
+                }
+                Some(Ok(message))
+            }
+            None => {
+                queues.closed.as_ref().map(|reason| {
+                    Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason.clone()))
+                })
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.queues.lock().unwrap().dropped = true;
+        self.waker.wake(self.token);
+    }
+}
+
+#[cfg(test)]
+impl Connection {
+    /// A connection with no event loop behind it, for testing what's done
+    /// with it.
+    pub fn detached() -> Self {
+        // nothing is registered with a poll, so waking does nothing
+        let (_, set_readiness) = Registration::new2();
+        Connection {
+            token: Token(FIRST_CLIENT),
+            queues: new_queues(),
+            waker: Waker {
+                pending: Arc::new(Mutex::new(HashSet::new())),
+                set_readiness,
+            },
+            peer: "127.0.0.1:0".parse().unwrap(),
+        }
+    }
+
+    /// Another handle on the same queues, to look at what has been done with
+    /// a connection after handing it off.
+    pub fn duplicate(&self) -> Self {
+        Connection {
+            token: self.token,
+            queues: self.queues.clone(),
+            waker: self.waker.clone(),
+            peer: self.peer,
+        }
+    }
+
+    /// As if the client had gone.
+    pub fn close(&self, reason: &str) {
+        close(&self.queues, reason.to_string());
+    }
+
+    /// Everything written that the event loop would send.
+    pub fn sent_messages(&self) -> Vec<ServerMessage> {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .outbound
+            .iter()
+            .map(|&(_, ref frame)| {
+                common::decode_message(str::from_utf8(&frame[4..]).unwrap()).unwrap()
+            })
+            .collect()
+    }
+}
+
+fn encode_frame(message: &ServerMessage) -> Vec<u8> {
+    let s = common::encode_message(message);
+    assert!(s.len() <= u32::max_value() as usize);
+
+    let mut frame = vec![0; 4];
+    BigEndian::write_u32(&mut frame, s.len() as u32);
+    frame.extend_from_slice(s.as_bytes());
+    frame
+}
+
+/// The event loop's side of a TCP connection.
+struct TcpClient {
+    socket: TcpStream,
+    peer: SocketAddr,
+    queues: Arc<Mutex<Queues>>,
+    read_buf: Vec<u8>,
+    /// Messages read, held back by the link conditions.
+    incoming: Conditioner<Vec<u8>>,
+    /// Frames taken from `queues`, held back by the link conditions.
+    outgoing: Conditioner<Vec<u8>>,
+    /// Frames ready to be written.
+    ready: VecDeque<Vec<u8>>,
+    /// How much of the first ready frame has been sent.
+    written: usize,
+    /// Whether the first message has been handed to `on_connect`.
+    accepted: bool,
+    /// If the server has a certificate.
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    /// Set once the client asks for TLS. Everything read from then on goes
+    /// through it, as does everything written once the reply has gone.
+    tls: Option<ServerSession>,
+    /// Frames at the front of `ready` to send in the clear, i.e. the reply
+    /// to `StartTls`.
+    plain_frames: usize,
+}
+
+impl TcpClient {
+    fn new(
+        socket: TcpStream,
+        peer: SocketAddr,
+        conditions: &LinkConditions,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+    ) -> Self {
+        TcpClient {
+            socket,
+            peer,
+            queues: new_queues(),
+            read_buf: Vec::new(),
+            incoming: Conditioner::new(conditions.clone(), true),
+            outgoing: Conditioner::new(conditions.clone(), true),
+            ready: VecDeque::new(),
+            written: 0,
+            accepted: false,
+            tls_config,
+            tls: None,
+            plain_frames: 0,
+        }
+    }
+
+    /// Reads and writes as much as the socket allows. Returns true once the
+    /// socket should be closed.
+    fn service<F>(&mut self, token: Token, waker: &Waker, on_connect: &mut F) -> bool
+    where
+        F: FnMut(Connection, io::Result<ClientMessage>),
+    {
+        if let Err(err) = self.read() {
+            self.close(err.to_string());
+        }
+        if let Err(err) = self.deliver_due(token, waker, on_connect) {
+            self.close(err.to_string());
+        }
+        if let Err(err) = self.flush() {
+            self.close(err.to_string());
+        }
+
+        let idle = self.ready.is_empty() && self.outgoing.next_due().is_none() &&
+            !self.tls.as_ref().map_or(false, |tls| tls.wants_write());
+        let queues = self.queues.lock().unwrap();
+        let done = queues.closed.is_some() ||
+            (queues.dropped && queues.outbound.is_empty() && idle);
+        done
+    }
+
+    /// When something held back by the link conditions is due.
+    fn next_due(&self) -> Option<Instant> {
+        self.incoming
+            .next_due()
+            .into_iter()
+            .chain(self.outgoing.next_due())
+            .min()
+    }
+
+    fn close(&mut self, reason: String) {
+        close(&self.queues, reason);
+    }
+
+    fn read(&mut self) -> io::Result<()> {
+        let mut buf = [0; 4096];
+
+        loop {
+            // carries on when the match catches up, see `Connection::try_get_message`
+            if self.queues.lock().unwrap().inbound.len() >= MAX_QUEUED_MESSAGES {
+                return Ok(());
+            }
+
+            match self.socket.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the client disconnected",
+                    ))
+                }
+                Ok(n) => {
+                    self.queues.lock().unwrap().traffic.bytes_in += n as u64;
+                    match self.tls {
+                        Some(ref mut tls) => {
+                            common::receive_records(tls, &buf[..n])?;
+                            common::read_plaintext(tls, &mut self.read_buf)?;
+                            if !tls.is_handshaking() {
+                                self.queues.lock().unwrap().encrypted = true;
+                            }
+                        }
+                        None => self.read_buf.extend_from_slice(&buf[..n]),
+                    }
+                    self.split_frames()?;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn split_frames(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+
+        while self.read_buf.len() >= 4 {
+            let len = BigEndian::read_u32(&self.read_buf[..4]) as usize;
+            if len > MAX_MESSAGE_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+            }
+            if self.read_buf.len() < 4 + len {
+                break;
+            }
+
+            let message = self.read_buf[4..4 + len].to_vec();
+            self.read_buf.drain(..4 + len);
+            self.queues.lock().unwrap().traffic.packets_in += 1;
+            self.incoming.push(message, 4 + len, now);
+        }
+
+        Ok(())
+    }
+
+    fn deliver_due<F>(&mut self, token: Token, waker: &Waker, on_connect: &mut F) -> io::Result<()>
+    where
+        F: FnMut(Connection, io::Result<ClientMessage>),
+    {
+        let due = self.incoming.pop_due(Instant::now());
+        let count = due.len();
+        for (i, message) in due.into_iter().enumerate() {
+            let message = str::from_utf8(&message)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                .and_then(common::decode_message::<ClientMessage>);
+
+            // without a certificate, it's up to `on_connect` to turn them away
+            let starts_tls = match message {
+                Ok(ClientMessage::StartTls) => !self.accepted && self.tls.is_none(),
+                _ => false,
+            };
+            if let (true, Some(config)) = (starts_tls, self.tls_config.clone()) {
+                // a client waits for our reply before sending anything else, so
+                // whatever it sent in the meantime would skip encryption
+                let plaintext = i + 1 < count || !self.read_buf.is_empty() ||
+                    self.incoming.next_due().is_some();
+                if plaintext {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the client sent more before switching to TLS",
+                    ));
+                }
+                self.start_tls(&config);
+                continue;
+            }
+
+            deliver(
+                &self.queues,
+                &mut self.accepted,
+                token,
+                self.peer,
+                waker,
+                message,
+                on_connect,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Replies in the clear, and switches to TLS. The connection only counts as
+    /// encrypted once the handshake is done.
+    fn start_tls(&mut self, config: &Arc<rustls::ServerConfig>) {
+        let frame = encode_frame(&ServerMessage::StartTls);
+        self.queues.lock().unwrap().outbound_bytes += frame.len();
+        // nothing else has been sent, since the client hasn't connected yet
+        self.ready.push_front(frame);
+        self.plain_frames = 1;
+        self.tls = Some(ServerSession::new(config));
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        {
+            let mut queues = self.queues.lock().unwrap();
+            // still counted in `outbound_bytes` until written
+            while let Some((_, frame)) = queues.outbound.pop_front() {
+                let size = frame.len();
+                self.outgoing.push(frame, size, now);
+            }
+        }
+        self.ready.extend(self.outgoing.pop_due(now));
+
+        loop {
+            if self.tls.is_some() && self.plain_frames == 0 {
+                return self.flush_tls();
+            }
+
+            let n = {
+                let frame = match self.ready.front() {
+                    Some(frame) => frame,
+                    None => return Ok(()),
+                };
+                match self.socket.write(&frame[self.written..]) {
+                    Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "write failed")),
+                    Ok(n) => n,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err),
+                }
+            };
+
+            self.written += n;
+            let mut queues = self.queues.lock().unwrap();
+            queues.traffic.bytes_out += n as u64;
+            if self.written == self.ready.front().unwrap().len() {
+                let frame = self.ready.pop_front().unwrap();
+                self.written = 0;
+                queues.outbound_bytes -= frame.len();
+                queues.traffic.packets_out += 1;
+                self.plain_frames = self.plain_frames.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Encrypts ready frames, but only as the socket takes them, so that a
+    /// slow client still backs up into `queues`.
+    fn flush_tls(&mut self) -> io::Result<()> {
+        let tls = self.tls.as_mut().unwrap();
+
+        loop {
+            while tls.wants_write() {
+                match tls.write_tls(&mut self.socket) {
+                    Ok(n) => self.queues.lock().unwrap().traffic.bytes_out += n as u64,
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+
+            if tls.is_handshaking() {
+                return Ok(());
+            }
+            let frame = match self.ready.pop_front() {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+            tls.write_all(&frame)?;
+
+            let mut queues = self.queues.lock().unwrap();
+            queues.outbound_bytes -= frame.len();
+            queues.traffic.packets_out += 1;
+        }
+    }
+}
+
+fn new_queues() -> Arc<Mutex<Queues>> {
+    Arc::new(Mutex::new(Queues {
+        inbound: VecDeque::new(),
+        outbound: VecDeque::new(),
+        outbound_bytes: 0,
+        closed: None,
+        dropped: false,
+        encrypted: false,
+        traffic: Traffic::default(),
+    }))
+}
+
+fn close(queues: &Mutex<Queues>, reason: String) {
+    let mut queues = queues.lock().unwrap();
+    if queues.closed.is_none() {
+        queues.closed = Some(reason);
+    }
+}
+
+/// Hands a client's first message to `on_connect`, and queues the rest for
+/// its match. Fails if a message other than the first couldn't be decoded.
+fn deliver<F>(
+    queues: &Arc<Mutex<Queues>>,
+    accepted: &mut bool,
+    token: Token,
+    peer: SocketAddr,
+    waker: &Waker,
+    message: io::Result<ClientMessage>,
+    on_connect: &mut F,
+) -> io::Result<()>
+where
+    F: FnMut(Connection, io::Result<ClientMessage>),
+{
+    if !*accepted {
+        // the first message decides what happens to the client, even if we
+        // can't make sense of it, so that it can be told why
+        *accepted = true;
+        on_connect(
+            Connection {
+                token,
+                queues: queues.clone(),
+                waker: waker.clone(),
+                peer,
+            },
+            message,
+        );
+        return Ok(());
+    }
+
+    let mut queues = queues.lock().unwrap();
+    // no one is going to read it
+    if queues.dropped {
+        return Ok(());
+    }
+    queues.inbound.push_back(message?);
+    Ok(())
+}
+
+/// Whether `packet` is a client's first, with its `Connect`. A packet that
+/// starts a connection carries the first reliable message, so a fresh endpoint
+/// reads it.
+fn is_connect_packet(packet: &[u8], now: Instant) -> bool {
+    let messages = match UdpEndpoint::new(now).receive(packet, now) {
+        Ok(messages) => messages,
+        Err(_) => return false,
+    };
+    let message = messages
+        .first()
+        .and_then(|payload| str::from_utf8(payload).ok())
+        .and_then(|s| common::decode_message::<ClientMessage>(s).ok());
+    match message {
+        Some(ClientMessage::Connect { .. }) => true,
+        _ => false,
+    }
+}
+
+/// The event loop's side of a UDP connection. Its packets all go through the
+/// event loop's one UDP socket.
+struct UdpClient {
+    peer: SocketAddr,
+    queues: Arc<Mutex<Queues>>,
+    endpoint: UdpEndpoint,
+    /// Whether the first message has been handed to `on_connect`.
+    accepted: bool,
+    created: Instant,
+}
+
+impl UdpClient {
+    fn new(peer: SocketAddr, now: Instant) -> Self {
+        UdpClient {
+            peer,
+            queues: new_queues(),
+            endpoint: UdpEndpoint::new(now),
+            accepted: false,
+            created: now,
+        }
+    }
+
+    /// Nothing we've sent has been acked, so the client may not be at `peer`.
+    fn is_pending(&self) -> bool {
+        self.endpoint.rtt().is_none()
+    }
+
+    fn receive<F>(&mut self, packet: &[u8], token: Token, waker: &Waker, on_connect: &mut F)
+    where
+        F: FnMut(Connection, io::Result<ClientMessage>),
+    {
+        {
+            let mut queues = self.queues.lock().unwrap();
+            queues.traffic.packets_in += 1;
+            queues.traffic.bytes_in += packet.len() as u64;
+
+            // ignoring the packet means it isn't acked, so it's resent once
+            // the match has caught up
+            if queues.inbound.len() >= MAX_QUEUED_MESSAGES {
+                return;
+            }
+        }
+
+        let messages = match self.endpoint.receive(packet, Instant::now()) {
+            Ok(messages) => messages,
+            // not from a client, or mangled on the way
+            Err(_) => return,
+        };
+
+        for payload in messages {
+            let message = String::from_utf8(payload)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+                .and_then(|s| common::decode_message::<ClientMessage>(&s));
+            let result = deliver(
+                &self.queues,
+                &mut self.accepted,
+                token,
+                self.peer,
+                waker,
+                message,
+                on_connect,
+            );
+            if let Err(err) = result {
+                close(&self.queues, err.to_string());
+                return;
+            }
+        }
+    }
+
+    /// Sends what's been queued, along with any resends and acks, by way of
+    /// `outgoing`. Returns true once the connection should be forgotten.
+    fn service(&mut self, outgoing: &mut Conditioner<(SocketAddr, Vec<u8>)>) -> bool {
+        {
+            let mut queues = self.queues.lock().unwrap();
+            while let Some((channel, frame)) = queues.outbound.pop_front() {
+                queues.outbound_bytes -= frame.len();
+                // the length prefix is only needed over TCP
+                self.endpoint.send(channel, frame[4..].to_vec());
+            }
+        }
+
+        let now = Instant::now();
+        let packets = self.endpoint.packets(now);
+        // what goes out is counted when it reaches the socket
+        self.queues.lock().unwrap().traffic.queued_bytes = self.endpoint.unacked_bytes();
+        for packet in packets {
+            let size = packet.len();
+            outgoing.push((self.peer, packet), size, now);
+        }
+
+        if self.endpoint.unacked_bytes() > MAX_QUEUED_BYTES {
+            close(&self.queues, "the client fell too far behind".into());
+        } else if self.endpoint.is_disconnected() {
+            close(&self.queues, "the client disconnected".into());
+        } else if self.endpoint.timed_out(now) {
+            close(&self.queues, "the client stopped responding".into());
+        } else if self.is_pending() &&
+                   now.duration_since(self.created) >=
+                       Duration::from_millis(PENDING_UDP_TIMEOUT_MS)
+        {
+            close(&self.queues, "the client never answered".into());
+        }
+
+        let queues = self.queues.lock().unwrap();
+        // wait for the last messages to be acked, so a kick gets there
+        let done = queues.closed.is_some() ||
+            (queues.dropped && queues.outbound.is_empty() && self.endpoint.is_idle());
+        done
+    }
+}
+
+enum Client {
+    Tcp(TcpClient),
+    Udp(UdpClient),
+}
+
+/// Runs every client connection on one thread, so that a slow or stuck client
+/// can't hold anything else up. Clients can connect over TCP, or over UDP on
+/// the same port.
+pub struct EventLoop {
+    poll: Poll,
+    listener: TcpListener,
+    udp: Option<UdpSocket>,
+    // dropping this would stop the waker from working
+    _registration: Registration,
+    waker: Waker,
+    clients: HashMap<Token, Client>,
+    udp_peers: HashMap<SocketAddr, Token>,
+    next_token: usize,
+    /// Simulated for every client, for testing.
+    conditions: LinkConditions,
+    /// For clients that ask for TLS, if the server has a certificate.
+    tls_config: Option<Arc<rustls::ServerConfig>>,
+    /// UDP packets received and to be sent, held back by `conditions`.
+    udp_incoming: Conditioner<(SocketAddr, Vec<u8>)>,
+    udp_outgoing: Conditioner<(SocketAddr, Vec<u8>)>,
+}
+
+impl EventLoop {
+    pub fn bind(
+        addr: SocketAddr,
+        udp: bool,
+        conditions: LinkConditions,
+        tls_config: Option<Arc<rustls::ServerConfig>>,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+
+        let listener = TcpListener::bind(&addr)?;
+        poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge())?;
+
+        let udp = if udp {
+            let socket = UdpSocket::bind(&addr)?;
+            poll.register(&socket, UDP, Ready::readable(), PollOpt::edge())?;
+            Some(socket)
+        } else {
+            None
+        };
+
+        let (registration, set_readiness) = Registration::new2();
+        poll.register(&registration, WAKER, Ready::readable(), PollOpt::edge())?;
+
+        Ok(EventLoop {
+            poll,
+            listener,
+            udp,
+            _registration: registration,
+            waker: Waker {
+                pending: Arc::new(Mutex::new(HashSet::new())),
+                set_readiness,
+            },
+            clients: HashMap::new(),
+            udp_peers: HashMap::new(),
+            next_token: FIRST_CLIENT,
+            udp_incoming: Conditioner::new(conditions.clone(), false),
+            udp_outgoing: Conditioner::new(conditions.clone(), false),
+            conditions,
+            tls_config,
+        })
+    }
+
+    /// Where clients connect, which is useful after binding to port 0.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Runs until polling fails. `on_connect` is given each new client along
+    /// with its first message, which should be `Connect`.
+    pub fn run<F>(&mut self, mut on_connect: F) -> io::Result<()>
+    where
+        F: FnMut(Connection, io::Result<ClientMessage>),
+    {
+        let mut events = Events::with_capacity(1024);
+        let update_interval = Duration::from_millis(UDP_UPDATE_MS);
+        let mut last_update = Instant::now();
+
+        loop {
+            // UDP clients need resends and keepalives even when nothing
+            // happens, and anything held back by the link conditions has to
+            // go out on time
+            let mut wake = self.next_due();
+            if !self.udp_peers.is_empty() {
+                let update = last_update + update_interval;
+                wake = Some(wake.map_or(update, |wake| wake.min(update)));
+            }
+            let now = Instant::now();
+            let timeout = wake.map(|wake| if wake > now {
+                wake - now
+            } else {
+                Duration::from_millis(0)
+            });
+            self.poll.poll(&mut events, timeout)?;
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept()?,
+                    UDP => self.receive_udp(),
+                    WAKER => {
+                        // reset before draining, so a wake from now on isn't missed
+                        self.waker.set_readiness.set_readiness(Ready::empty())?;
+                        let pending: Vec<Token> =
+                            self.waker.pending.lock().unwrap().drain().collect();
+                        for token in pending {
+                            self.service(token, &mut on_connect);
+                        }
+                    }
+                    token => self.service(token, &mut on_connect),
+                }
+            }
+
+            let now = Instant::now();
+            self.handle_udp_packets(now, &mut on_connect);
+
+            let due: Vec<Token> = self.clients
+                .iter()
+                .filter(|&(_, client)| match *client {
+                    Client::Tcp(ref client) => client.next_due().map_or(false, |due| due <= now),
+                    Client::Udp(_) => false,
+                })
+                .map(|(&token, _)| token)
+                .collect();
+            for token in due {
+                self.service(token, &mut on_connect);
+            }
+
+            if last_update.elapsed() >= update_interval {
+                last_update = Instant::now();
+                let tokens: Vec<Token> = self.udp_peers.values().cloned().collect();
+                for token in tokens {
+                    self.service(token, &mut on_connect);
+                }
+            }
+
+            self.send_udp_packets(Instant::now());
+        }
+    }
+
+    /// When something held back by the link conditions is due.
+    fn next_due(&self) -> Option<Instant> {
+        self.clients
+            .values()
+            .filter_map(|client| match *client {
+                Client::Tcp(ref client) => client.next_due(),
+                Client::Udp(_) => None,
+            })
+            .chain(self.udp_incoming.next_due())
+            .chain(self.udp_outgoing.next_due())
+            .min()
+    }
+
+    fn accept(&mut self) -> io::Result<()> {
+        loop {
+            let (socket, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => {
+                    println!("Couldn't accept a connection: {}", err);
+                    return Ok(());
+                }
+            };
+
+            let _ = socket.set_nodelay(true);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            self.poll.register(
+                &socket,
+                token,
+                Ready::readable() | Ready::writable(),
+                PollOpt::edge(),
+            )?;
+            println!("Connection from {}", peer);
+            let client = TcpClient::new(socket, peer, &self.conditions, self.tls_config.clone());
+            self.clients.insert(token, Client::Tcp(client));
+        }
+    }
+
+    /// Reads every waiting packet, to be handled once the link conditions
+    /// say they've arrived.
+    fn receive_udp(&mut self) {
+        let mut buf = vec![0; 65536];
+
+        loop {
+            let (n, peer) = match self.udp.as_ref().unwrap().recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                // a previous packet to a client that has gone away bounced
+                Err(ref err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    println!("Couldn't receive a UDP packet: {}", err);
+                    return;
+                }
+            };
+            self.udp_incoming.push((peer, buf[..n].to_vec()), n, Instant::now());
+        }
+    }
+
+    fn handle_udp_packets<F>(&mut self, now: Instant, on_connect: &mut F)
+    where
+        F: FnMut(Connection, io::Result<ClientMessage>),
+    {
+        for (peer, packet) in self.udp_incoming.pop_due(now) {
+            let known = self.udp_peers.get(&peer).cloned();
+            let token = match known {
+                Some(token) => token,
+                // anything else from a stranger is a stray or a late packet
+                // from a closed connection, and isn't worth keeping state for
+                None if is_connect_packet(&packet, now) => {
+                    if self.pending_udp_clients() >= MAX_PENDING_UDP_CLIENTS {
+                        continue;
+                    }
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    println!("UDP connection from {}", peer);
+                    self.udp_peers.insert(peer, token);
+                    self.clients.insert(token, Client::Udp(UdpClient::new(peer, now)));
+                    token
+                }
+                None => continue,
+            };
+
+            if let Some(&mut Client::Udp(ref mut client)) = self.clients.get_mut(&token) {
+                client.receive(&packet, token, &self.waker, on_connect);
+            }
+        }
+    }
+
+    fn pending_udp_clients(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|client| match **client {
+                Client::Udp(ref client) => client.is_pending(),
+                Client::Tcp(_) => false,
+            })
+            .count()
+    }
+
+    fn send_udp_packets(&mut self, now: Instant) {
+        for (peer, packet) in self.udp_outgoing.pop_due(now) {
+            // a packet the OS won't take is lost like any other, and resent
+            // if it has to be
+            let sent = match self.udp.as_ref().unwrap().send_to(&packet, &peer) {
+                Ok(sent) => sent,
+                Err(_) => continue,
+            };
+
+            let token = match self.udp_peers.get(&peer) {
+                Some(token) => token,
+                // closed while the packet was held back
+                None => continue,
+            };
+            if let Some(&Client::Udp(ref client)) = self.clients.get(token) {
+                let mut queues = client.queues.lock().unwrap();
+                queues.traffic.packets_out += 1;
+                queues.traffic.bytes_out += sent as u64;
+            }
+        }
+    }
+
+    fn service<F>(&mut self, token: Token, on_connect: &mut F)
+    where
+        F: FnMut(Connection, io::Result<ClientMessage>),
+    {
+        let done = match self.clients.get_mut(&token) {
+            Some(&mut Client::Tcp(ref mut client)) => client.service(token, &self.waker, on_connect),
+            Some(&mut Client::Udp(ref mut client)) => client.service(&mut self.udp_outgoing),
+            // already closed
+            None => return,
+        };
+
+        if !done {
+            return;
+        }
+        match self.clients.remove(&token).unwrap() {
+            Client::Tcp(client) => {
+                let _ = self.poll.deregister(&client.socket);
+                println!("Closed connection from {}", client.peer);
+            }
+            Client::Udp(client) => {
+                let packet = client.endpoint.disconnect_packet();
+                let _ = self.udp.as_ref().unwrap().send_to(&packet, &client.peer);
+                self.udp_peers.remove(&client.peer);
+                println!("Closed UDP connection from {}", client.peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{self, SocketAddr};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use common::{self, Channel, ClientMessage, ClientStream, LinkConditions, ServerMessage,
+                 UdpEndpoint};
+    use super::*;
+
+    const CERT: &str = "testdata/cert.pem";
+    const KEY: &str = "testdata/key.pem";
+    /// Self-signed with the same name, but not the certificate the server has.
+    const OTHER_CERT: &str = "testdata/other-cert.pem";
+
+    /// An event loop with TLS on loopback, handing each new client and its
+    /// first message to the receiver.
+    fn tls_server() -> (SocketAddr, mpsc::Receiver<(Connection, io::Result<ClientMessage>)>) {
+        let (addr_sender, addr_receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            let tls = common::server_config(CERT, KEY).unwrap();
+            let addr = "127.0.0.1:0".parse().unwrap();
+            let mut event_loop =
+                EventLoop::bind(addr, false, LinkConditions::default(), Some(tls)).unwrap();
+            addr_sender.send(event_loop.local_addr().unwrap()).unwrap();
+            event_loop
+                .run(move |connection, message| {
+                    let _ = sender.send((connection, message));
+                })
+                .unwrap();
+        });
+
+        (addr_receiver.recv().unwrap(), receiver)
+    }
+
+    fn tls_client(addr: SocketAddr, cert: &str) -> ClientStream {
+        let mut socket = net::TcpStream::connect(addr).unwrap();
+        common::negotiate_tls(&mut socket).unwrap();
+        let config = common::client_config(cert).unwrap();
+        ClientStream::with_tls(socket, &config, LinkConditions::default())
+    }
+
+    /// Length prefixed, as a client writes it over TCP.
+    fn encode_frame_for(message: &ClientMessage) -> Vec<u8> {
+        let s = common::encode_message(message);
+        let mut frame = vec![0; 4];
+        BigEndian::write_u32(&mut frame, s.len() as u32);
+        frame.extend_from_slice(s.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn clients_can_connect_over_tls() {
+        let (addr, connections) = tls_server();
+        let mut client = tls_client(addr, CERT);
+        client.write_message(connect()).unwrap();
+
+        let (mut connection, message) = connections.recv_timeout(Duration::from_secs(5)).unwrap();
+        match message {
+            Ok(ClientMessage::Connect { name, .. }) => assert_eq!(name, "alice"),
+            other => panic!("expected a Connect, got {:?}", other),
+        }
+        assert!(connection.is_encrypted());
+
+        connection
+            .write_message(ServerMessage::Kick { reason: "over TLS".into() })
+            .unwrap();
+        match client.get_message() {
+            Ok(ServerMessage::Kick { reason }) => assert_eq!(reason, "over TLS"),
+            other => panic!("expected a Kick, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plaintext_sent_along_with_start_tls_is_rejected() {
+        let (addr, connections) = tls_server();
+        let mut socket = net::TcpStream::connect(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut frames = encode_frame_for(&ClientMessage::StartTls);
+        frames.extend(encode_frame_for(&connect()));
+        socket.write_all(&frames).unwrap();
+
+        // closed without agreeing to TLS
+        let mut buf = [0; 64];
+        match socket.read(&mut buf) {
+            Ok(0) => {}
+            Err(ref err) if err.kind() != io::ErrorKind::WouldBlock &&
+                                err.kind() != io::ErrorKind::TimedOut => {}
+            other => panic!("expected the connection to close, got {:?}", other),
+        }
+        assert!(connections.try_recv().is_err());
+    }
+
+    #[test]
+    fn servers_with_another_certificate_are_refused() {
+        let (addr, connections) = tls_server();
+        let mut client = tls_client(addr, OTHER_CERT);
+        // the handshake goes out with the first write, and fails on the reply
+        let _ = client.write_message(connect());
+
+        assert!(client.get_message().is_err());
+        assert!(connections.recv_timeout(Duration::from_millis(500)).is_err());
+    }
+
+    fn first_packet(messages: &[ClientMessage]) -> Vec<u8> {
+        let now = Instant::now();
+        let mut endpoint = UdpEndpoint::new(now);
+        for message in messages {
+            endpoint.send(Channel::Reliable, common::encode_message(message).into_bytes());
+        }
+        endpoint.packets(now).remove(0)
+    }
+
+    fn connect() -> ClientMessage {
+        ClientMessage::Connect {
+            protocol_version: common::PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            name: "alice".into(),
+            team: None,
+            match_id: None,
+        }
+    }
+
+    #[test]
+    fn a_connect_opens_a_udp_connection() {
+        let now = Instant::now();
+        assert!(is_connect_packet(&first_packet(&[connect()]), now));
+        assert!(is_connect_packet(&first_packet(&[connect(), ClientMessage::Quit]), now));
+    }
+
+    #[test]
+    fn other_packets_dont_open_a_udp_connection() {
+        let now = Instant::now();
+        assert!(!is_connect_packet(&first_packet(&[ClientMessage::Quit]), now));
+        assert!(!is_connect_packet(b"not a moba packet at all", now));
+
+        // a later packet, sent before the first is due to be resent
+        let mut endpoint = UdpEndpoint::new(now);
+        endpoint.send(Channel::Reliable, common::encode_message(&connect()).into_bytes());
+        endpoint.packets(now);
+        endpoint.send(Channel::Reliable, common::encode_message(&connect()).into_bytes());
+        let later = endpoint.packets(now).remove(0);
+        assert!(!is_connect_packet(&later, now));
+    }
+}
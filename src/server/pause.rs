@@ -0,0 +1,323 @@
+use std::time;
+use std::collections::HashMap;
+
+use common::{ServerMessage, EntityID, Team, ChatChannel};
+
+const UNPAUSE_COUNTDOWN_SECS: u32 = 3;
+
+/// Who paused, and whose pause time it uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Pauser {
+    /// Admins have no budget, and only they can unpause.
+    Admin,
+    Team(Team),
+    /// Without teams, every player has their own budget.
+    Player(EntityID),
+}
+
+impl Pauser {
+    /// A player on `team`, or on their own without one.
+    pub fn player(player: EntityID, team: Option<Team>) -> Self {
+        match team {
+            Some(team) => Pauser::Team(team),
+            None => Pauser::Player(player),
+        }
+    }
+}
+
+enum PauseState {
+    Running,
+    Paused {
+        by: Pauser,
+        since: time::Instant,
+    },
+    Resuming {
+        until: time::Instant,
+        last_announced: u32,
+    },
+}
+
+/// Tracks whether the match is paused, how much pause time each team has left,
+/// and the countdown before play resumes.
+pub struct Pause {
+    state: PauseState,
+    /// Seconds each team, or player without teams, may spend paused over the
+    /// whole match.
+    budget: f64,
+    used: HashMap<Pauser, f64>,
+}
+
+fn secs(d: time::Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1000000000.0
+}
+
+impl Pause {
+    pub fn new(budget_secs: u32) -> Self {
+        Pause {
+            state: PauseState::Running,
+            budget: budget_secs as f64,
+            used: HashMap::new(),
+        }
+    }
+
+    /// True while paused or counting down to resume.
+    pub fn is_paused(&self) -> bool {
+        match self.state {
+            PauseState::Running => false,
+            _ => true,
+        }
+    }
+
+    /// `None` for admins, who have no budget.
+    pub fn remaining_budget(&self, by: Pauser) -> Option<f64> {
+        match by {
+            Pauser::Admin => None,
+            _ => Some((self.budget - self.used.get(&by).cloned().unwrap_or(0.0)).max(0.0)),
+        }
+    }
+
+    pub fn pause(
+        &mut self,
+        name: &str,
+        by: Pauser,
+        now: time::Instant,
+    ) -> Result<ServerMessage, String> {
+        if let PauseState::Paused { .. } = self.state {
+            return Err("The game is already paused.".into());
+        }
+
+        match self.remaining_budget(by) {
+            Some(left) if left <= 0.0 => {
+                return Err(match by {
+                    Pauser::Team(_) => "Your team has no pause time left.".into(),
+                    _ => "You have no pause time left.".into(),
+                })
+            }
+            _ => {}
+        }
+
+        self.state = PauseState::Paused { by, since: now };
+
+        Ok(ServerMessage::Paused {
+            by: name.into(),
+            remaining_budget: self.remaining_budget(by).map(|left| left as u32),
+        })
+    }
+
+    /// Only admins and whoever paused can unpause early. Anyone else waits for
+    /// the pause time to run out.
+    pub fn unpause(&mut self, by: Pauser, now: time::Instant) -> Result<ServerMessage, String> {
+        let paused_by = match self.state {
+            PauseState::Paused { by: paused_by, .. } => paused_by,
+            _ => return Err("The game isn't paused.".into()),
+        };
+
+        if by != Pauser::Admin && paused_by != by {
+            return Err(match paused_by {
+                Pauser::Admin => {
+                    "An admin paused the game, so only an admin can unpause it.".into()
+                }
+                Pauser::Team(_) => {
+                    "Only the team that paused can unpause. Play resumes when their pause time \
+                     runs out."
+                        .into()
+                }
+                Pauser::Player(_) => {
+                    "Only the player who paused can unpause. Play resumes when their pause \
+                     time runs out."
+                        .into()
+                }
+            });
+        }
+
+        self.charge_budget(now);
+        Ok(self.start_countdown(now))
+    }
+
+    fn start_countdown(&mut self, now: time::Instant) -> ServerMessage {
+        self.state = PauseState::Resuming {
+            until: now + time::Duration::from_secs(UNPAUSE_COUNTDOWN_SECS as u64),
+            last_announced: UNPAUSE_COUNTDOWN_SECS,
+        };
+
+        ServerMessage::UnpauseCountdown { seconds: UNPAUSE_COUNTDOWN_SECS }
+    }
+
+    /// Adds the time spent in the current pause to the pauser's total, and
+    /// restarts the pause timer.
+    fn charge_budget(&mut self, now: time::Instant) {
+        if let PauseState::Paused { by, ref mut since } = self.state {
+            if by != Pauser::Admin {
+                *self.used.entry(by).or_insert(0.0) += secs(now - *since);
+            }
+            *since = now;
+        }
+    }
+
+    /// Call once per tick. Returns the messages to broadcast.
+    pub fn update(&mut self, now: time::Instant) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+
+        let payer = match self.state {
+            PauseState::Paused { by, .. } if by != Pauser::Admin => Some(by),
+            _ => None,
+        };
+        if let Some(payer) = payer {
+            self.charge_budget(now);
+            if self.remaining_budget(payer).map_or(false, |left| left <= 0.0) {
+                messages.push(ServerMessage::ReceiveChat {
+                    user: "".into(),
+                    message: "Pause time has run out.".into(),
+                    channel: ChatChannel::System,
+                });
+                messages.push(self.start_countdown(now));
+            }
+        }
+
+        let mut resumed = false;
+        if let PauseState::Resuming {
+            until,
+            ref mut last_announced,
+        } = self.state
+        {
+            if now >= until {
+                resumed = true;
+            } else {
+                let left = secs(until - now).ceil() as u32;
+                if left < *last_announced {
+                    *last_announced = left;
+                    messages.push(ServerMessage::UnpauseCountdown { seconds: left });
+                }
+            }
+        }
+        if resumed {
+            self.state = PauseState::Running;
+            messages.push(ServerMessage::Resumed);
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use common::{EntityID, ServerMessage, Team};
+    use super::*;
+
+    const ALICE: EntityID = EntityID(0);
+    const BOB: EntityID = EntityID(1);
+
+    fn after(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    fn resumed(messages: &[ServerMessage]) -> bool {
+        messages.iter().any(|m| match *m {
+            ServerMessage::Resumed => true,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn pausing_twice_fails() {
+        let now = Instant::now();
+        let mut pause = Pause::new(60);
+        assert!(pause.pause("alice", Pauser::Team(Team(0)), now).is_ok());
+        assert!(pause.is_paused());
+        assert!(pause.pause("bob", Pauser::Team(Team(1)), now).is_err());
+    }
+
+    #[test]
+    fn unpausing_counts_down_then_resumes() {
+        let start = Instant::now();
+        let mut pause = Pause::new(60);
+        let team = Pauser::Team(Team(0));
+        pause.pause("alice", team, start).unwrap();
+
+        match pause.unpause(team, after(start, 5)) {
+            Ok(ServerMessage::UnpauseCountdown { seconds }) => {
+                assert_eq!(seconds, UNPAUSE_COUNTDOWN_SECS)
+            }
+            other => panic!("expected a countdown, got {:?}", other),
+        }
+        assert!(pause.is_paused());
+        assert_eq!(pause.remaining_budget(team), Some(55.0));
+
+        let during = after(start, 5 + UNPAUSE_COUNTDOWN_SECS as u64 - 1);
+        assert!(!resumed(&pause.update(during)));
+        let done = after(start, 5 + UNPAUSE_COUNTDOWN_SECS as u64);
+        assert!(resumed(&pause.update(done)));
+        assert!(!pause.is_paused());
+    }
+
+    #[test]
+    fn only_the_pausing_team_or_an_admin_can_unpause() {
+        let now = Instant::now();
+        let mut pause = Pause::new(60);
+        pause.pause("alice", Pauser::Team(Team(0)), now).unwrap();
+
+        assert!(pause.unpause(Pauser::Team(Team(1)), now).is_err());
+        assert!(pause.unpause(Pauser::Player(BOB), now).is_err());
+        assert!(pause.unpause(Pauser::Admin, now).is_ok());
+    }
+
+    #[test]
+    fn only_admins_can_unpause_an_admin_pause() {
+        let now = Instant::now();
+        let mut pause = Pause::new(60);
+        pause.pause("admin", Pauser::Admin, now).unwrap();
+
+        assert!(pause.unpause(Pauser::Team(Team(0)), now).is_err());
+        assert!(pause.unpause(Pauser::Admin, now).is_ok());
+    }
+
+    #[test]
+    fn admin_pauses_dont_run_out() {
+        let start = Instant::now();
+        let mut pause = Pause::new(10);
+        pause.pause("admin", Pauser::Admin, start).unwrap();
+
+        assert!(pause.update(after(start, 60)).is_empty());
+        assert!(pause.is_paused());
+        assert_eq!(pause.remaining_budget(Pauser::Admin), None);
+    }
+
+    #[test]
+    fn pauses_end_when_the_budget_runs_out() {
+        let start = Instant::now();
+        let mut pause = Pause::new(10);
+        let team = Pauser::Team(Team(0));
+        pause.pause("alice", team, start).unwrap();
+
+        assert!(pause.update(after(start, 5)).is_empty());
+        let messages = pause.update(after(start, 10));
+        assert!(messages.iter().any(|m| match *m {
+            ServerMessage::UnpauseCountdown { .. } => true,
+            _ => false,
+        }));
+        assert_eq!(pause.remaining_budget(team), Some(0.0));
+
+        pause.update(after(start, 20));
+        assert!(!pause.is_paused());
+        assert!(pause.pause("alice", team, after(start, 21)).is_err());
+        // the other team still has theirs
+        assert!(pause.pause("bob", Pauser::Team(Team(1)), after(start, 21)).is_ok());
+    }
+
+    #[test]
+    fn players_without_teams_have_their_own_budgets() {
+        let start = Instant::now();
+        let mut pause = Pause::new(10);
+        let alice = Pauser::player(ALICE, None);
+        let bob = Pauser::player(BOB, None);
+        assert_eq!(alice, Pauser::Player(ALICE));
+
+        pause.pause("alice", alice, start).unwrap();
+        assert!(pause.unpause(bob, after(start, 4)).is_err());
+        pause.unpause(alice, after(start, 4)).unwrap();
+
+        assert_eq!(pause.remaining_budget(alice), Some(6.0));
+        assert_eq!(pause.remaining_budget(bob), Some(10.0));
+    }
+}